@@ -18,10 +18,12 @@ async fn main() -> Result<()> {
     println!("Starting basic communication example...");
     
     // Start server in background
-    let server_handle = tokio::spawn(run_server());
+    let server = NamedPipeServerStruct::new(PIPE_NAME);
+    let mut ready = server.ready_signal();
+    let server_handle = tokio::spawn(run_server(server));
     
-    // Give server time to start
-    sleep(Duration::from_millis(500)).await;
+    // Wait until the server is listening
+    ready.wait().await?;
     
     // Run client
     let client_result = run_client().await;
@@ -32,11 +34,9 @@ async fn main() -> Result<()> {
     client_result
 }
 
-async fn run_server() -> Result<()> {
+async fn run_server(mut server: NamedPipeServerStruct) -> Result<()> {
     println!("[SERVER] Starting echo server on pipe '{}'", PIPE_NAME);
     
-    let mut server = NamedPipeServerStruct::new(PIPE_NAME);
-    
     server.start(|mut connection| async move {
         println!("[SERVER] Client connected (ID: {})", connection.id());
         
@@ -42,7 +42,7 @@ async fn test_encryption_mode(
             "   [SERVER] Starting encrypted server with {}...",
             key_description
         );
-        let mut server = NamedPipeServerStruct::new_encrypted(&server_pipe_name, server_key);
+        let mut server = NamedPipeServerStruct::new_encrypted(&server_pipe_name, server_key)?;
 
         server
             .start(|mut connection| async move {
@@ -70,7 +70,7 @@ async fn test_encryption_mode(
 
     // Create and test client
     println!("   [CLIENT] Connecting with {}...", key_description);
-    let mut client = NamedPipeClientStruct::new_encrypted(pipe_name, client_key);
+    let mut client = NamedPipeClientStruct::new_encrypted(pipe_name, client_key)?;
     client.connect().await?;
     println!("   [CLIENT] Connected successfully!");
 
@@ -9,7 +9,7 @@
 //!
 //! Run with: cargo run --example event_driven_server
 
-use pipeguard::{NamedPipeConnection, NamedPipeServerStruct, Result};
+use pipeguard::{ConnectionId, NamedPipeConnection, NamedPipeServerStruct, Result};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::sync::{
@@ -26,15 +26,15 @@ const PIPE_NAME: &str = "event_driven_server";
 #[derive(Debug, Clone)]
 pub enum ServerEvent {
     ClientConnected {
-        client_id: usize,
+        client_id: ConnectionId,
         timestamp: u64,
     },
     ClientDisconnected {
-        client_id: usize,
+        client_id: ConnectionId,
         timestamp: u64,
     },
     MessageReceived {
-        client_id: usize,
+        client_id: ConnectionId,
         message: String,
         timestamp: u64,
     },
@@ -46,7 +46,7 @@ pub enum ServerEvent {
         timestamp: u64,
     },
     Error {
-        client_id: Option<usize>,
+        client_id: Option<ConnectionId>,
         error: String,
         timestamp: u64,
     },
@@ -74,13 +74,13 @@ pub trait EventHandler: Send + Sync {
 #[derive(Clone)]
 pub struct ServerState {
     pub is_running: Arc<AtomicBool>,
-    pub clients: Arc<Mutex<HashMap<usize, ClientInfo>>>,
+    pub clients: Arc<Mutex<HashMap<ConnectionId, ClientInfo>>>,
     pub event_handler: Arc<dyn EventHandler>,
 }
 
 #[derive(Debug, Clone)]
 pub struct ClientInfo {
-    pub id: usize,
+    pub id: ConnectionId,
     pub connected_at: u64,
     pub message_count: u32,
 }
@@ -230,7 +230,7 @@ impl EventDrivenServer {
         self.state.clients.lock().await.len()
     }
 
-    pub async fn get_client_info(&self, client_id: usize) -> Option<ClientInfo> {
+    pub async fn get_client_info(&self, client_id: ConnectionId) -> Option<ClientInfo> {
         self.state.clients.lock().await.get(&client_id).cloned()
     }
 }
@@ -347,7 +347,7 @@ async fn handle_client_connection(
 
 async fn process_message(
     raw_message: &str,
-    client_id: usize,
+    client_id: ConnectionId,
     state: &ServerState,
 ) -> Result<ServerResponse> {
     // Try to parse as JSON first, fall back to plain string
@@ -8,7 +8,7 @@
 //! Start the server: cargo run --example multi_client_server
 //! Then connect multiple clients using the basic_client example
 
-use pipeguard::{NamedPipeServerStruct, Result};
+use pipeguard::{ConnectionId, NamedPipeServerStruct, Result};
 use std::sync::Arc;
 use std::collections::HashMap;
 use tokio::sync::{broadcast, Mutex};
@@ -16,7 +16,7 @@ use tokio::signal;
 
 const PIPE_NAME: &str = "multi_client_server";
 
-type ClientId = usize;
+type ClientId = ConnectionId;
 
 #[tokio::main]
 async fn main() -> Result<()> {
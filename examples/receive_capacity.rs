@@ -0,0 +1,76 @@
+use pipeguard::InMemoryConnection;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+
+// Counts every allocation and reallocation made by the process
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+const MESSAGE_SIZE: usize = 1024 * 1024;
+const ROUNDS: usize = 32;
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    println!("=== Receive Capacity Micro-Benchmark ===");
+    println!(
+        "Receiving one {} KiB message on each of {} fresh in-memory connections",
+        MESSAGE_SIZE / 1024,
+        ROUNDS
+    );
+    println!();
+
+    run("receive_bytes", None).await?;
+    run("receive_bytes_with_capacity", Some(MESSAGE_SIZE)).await?;
+    Ok(())
+}
+
+async fn run(label: &str, hint: Option<usize>) -> Result<(), Box<dyn std::error::Error>> {
+    let message = vec![0x5Au8; MESSAGE_SIZE];
+    let mut allocations = 0;
+    let start = Instant::now();
+
+    for _ in 0..ROUNDS {
+        // Send the whole message up front so only the receive is counted
+        let (mut sender, mut receiver) =
+            InMemoryConnection::pair_with_buffer_size(2 * MESSAGE_SIZE);
+        sender.send_bytes(&message).await?;
+
+        let before = ALLOCATIONS.load(Ordering::Relaxed);
+        let received = match hint {
+            Some(hint) => receiver.receive_bytes_with_capacity(hint).await?,
+            None => receiver.receive_bytes().await?,
+        };
+        allocations += ALLOCATIONS.load(Ordering::Relaxed) - before;
+        assert_eq!(received.len(), MESSAGE_SIZE);
+    }
+
+    println!(
+        "{:<30} {:>5} allocations ({:.1} per message), {:?}",
+        label,
+        allocations,
+        allocations as f64 / ROUNDS as f64,
+        start.elapsed()
+    );
+    Ok(())
+}
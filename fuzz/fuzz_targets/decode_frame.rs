@@ -0,0 +1,31 @@
+//! Fuzz the frame decoder with arbitrary bytes, formats and size limits.
+//!
+//! Run with `cargo fuzz run decode_frame` from the repository root.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use pipeguard::{decode_frame, Endian, FrameFormat, LengthWidth};
+
+fuzz_target!(|data: &[u8]| {
+    let _ = decode_frame(data);
+
+    // Use the first two bytes to pick a format and a size limit
+    let Some((&[selector, limit], buf)) = data.split_first_chunk::<2>() else {
+        return;
+    };
+    let format = FrameFormat {
+        endian: if selector & 1 == 0 { Endian::Little } else { Endian::Big },
+        width: match (selector >> 1) % 3 {
+            0 => LengthWidth::U16,
+            1 => LengthWidth::U32,
+            _ => LengthWidth::U64,
+        },
+    };
+
+    if let Ok(frame) = format.decode_frame(buf, u64::from(limit)) {
+        assert!(frame.len <= buf.len());
+        assert!(frame.payload.len() <= usize::from(limit));
+        let _ = std::str::from_utf8(frame.payload);
+    }
+});
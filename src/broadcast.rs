@@ -0,0 +1,85 @@
+//! Sending the same message to many server-side connections.
+//!
+//! A `Broadcaster` takes ownership of the connections it serves, typically handed over by
+//! a handler that has nothing left to do but keep the client subscribed. Connections that
+//! fail to take a message are removed, so the set only holds live clients.
+
+use crate::error::NamedPipeError;
+use crate::server::NamedPipeConnection;
+use crate::utils::ConnectionId;
+use std::collections::BTreeMap;
+
+/// Callback told about every failed delivery
+type FailureCallback = Box<dyn FnMut(ConnectionId, &[u8], NamedPipeError) + Send>;
+
+/// A set of connections that receive every broadcast message
+#[derive(Default)]
+pub struct Broadcaster {
+    connections: BTreeMap<ConnectionId, NamedPipeConnection>,
+    on_failure: Option<FailureCallback>,
+}
+
+impl Broadcaster {
+    /// Create an empty broadcaster
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Call `callback` with the connection id, the message and the error for each failed
+    /// delivery, e.g. to log it or queue a retry. The connection has already been removed
+    /// when the callback runs. Without a callback, failed connections are removed silently.
+    pub fn on_failure<F>(&mut self, callback: F)
+    where
+        F: FnMut(ConnectionId, &[u8], NamedPipeError) + Send + 'static,
+    {
+        self.on_failure = Some(Box::new(callback));
+    }
+
+    /// Add a connection to receive future broadcasts, returning its id
+    pub fn add(&mut self, connection: NamedPipeConnection) -> ConnectionId {
+        let id = connection.id();
+        self.connections.insert(id, connection);
+        id
+    }
+
+    /// Remove a connection, handing it back to the caller
+    pub fn remove(&mut self, id: ConnectionId) -> Option<NamedPipeConnection> {
+        self.connections.remove(&id)
+    }
+
+    /// Number of connections receiving broadcasts
+    pub fn len(&self) -> usize {
+        self.connections.len()
+    }
+
+    /// Whether no connection receives broadcasts
+    pub fn is_empty(&self) -> bool {
+        self.connections.is_empty()
+    }
+
+    /// Send `data` to every connection, returning how many received it.
+    ///
+    /// Connections are served one after another, so a client that stops reading holds up
+    /// the ones after it once its pipe buffer is full.
+    pub async fn broadcast(&mut self, data: &[u8]) -> usize {
+        let mut failed = Vec::new();
+        for (&id, connection) in self.connections.iter_mut() {
+            let result = match connection.send_bytes(data).await {
+                Ok(()) => connection.flush().await,
+                Err(e) => Err(e),
+            };
+            if let Err(e) = result {
+                failed.push((id, e));
+            }
+        }
+
+        let delivered = self.connections.len() - failed.len();
+        for (id, e) in failed {
+            self.connections.remove(&id);
+            if let Some(callback) = self.on_failure.as_mut() {
+                callback(id, data, e);
+            }
+        }
+        delivered
+    }
+}
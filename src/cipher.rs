@@ -0,0 +1,259 @@
+//! AEAD cipher suites used to encrypt frames.
+//!
+//! An encrypted payload is a fresh random nonce followed by the ciphertext. The nonce
+//! length is a property of the suite, so both sealing and opening derive it from there.
+//! The ciphertext ends with a 16-byte authentication tag, so even an empty message
+//! encrypts to a nonce and a tag.
+
+use crate::error::{NamedPipeError, Result};
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, OsRng},
+    ChaCha20Poly1305, Key, Nonce, XChaCha20Poly1305, XNonce,
+};
+use std::sync::Arc;
+use zeroize::Zeroizing;
+
+/// An AEAD algorithm for encrypting frames; both peers must use the same suite
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum CipherSuite {
+    /// ChaCha20-Poly1305 with a 12-byte random nonce (the default)
+    #[default]
+    ChaCha20Poly1305,
+    /// XChaCha20-Poly1305 with a 24-byte random nonce. The larger nonce makes random
+    /// nonce collisions negligible even on connections that send billions of frames.
+    XChaCha20Poly1305,
+}
+
+impl CipherSuite {
+    /// Length of the nonce prepended to every ciphertext
+    pub const fn nonce_len(self) -> usize {
+        match self {
+            CipherSuite::ChaCha20Poly1305 => 12,
+            CipherSuite::XChaCha20Poly1305 => 24,
+        }
+    }
+}
+
+/// Length of the Poly1305 authentication tag closing every ciphertext
+const AUTH_TAG_LEN: usize = 16;
+
+/// Plaintext encrypted by `Cipher::self_test`
+const SELF_TEST_PLAINTEXT: &[u8] = b"pipeguard cipher self-test vector";
+
+/// Reject keys that are obviously weak: all zero, or the same byte repeated.
+///
+/// This catches placeholder and uninitialized keys, not low-entropy keys in general; keys
+/// should come from a CSPRNG or a proper key derivation function.
+pub fn check_key(key: &[u8; 32]) -> Result<()> {
+    if key.iter().all(|&byte| byte == key[0]) {
+        return Err(NamedPipeError::WeakKey);
+    }
+    Ok(())
+}
+
+/// A keyed instance of a `CipherSuite`
+#[derive(Clone)]
+pub enum Cipher {
+    /// ChaCha20-Poly1305, keyed for `CipherSuite::ChaCha20Poly1305`
+    ChaCha20Poly1305(ChaCha20Poly1305),
+    /// XChaCha20-Poly1305, keyed for `CipherSuite::XChaCha20Poly1305`
+    XChaCha20Poly1305(XChaCha20Poly1305),
+}
+
+impl Cipher {
+    /// Create a cipher for `suite` with a 32-byte key
+    pub fn new(suite: CipherSuite, key: &[u8; 32]) -> Self {
+        let key = Key::from_slice(key);
+        match suite {
+            CipherSuite::ChaCha20Poly1305 => Cipher::ChaCha20Poly1305(ChaCha20Poly1305::new(key)),
+            CipherSuite::XChaCha20Poly1305 => {
+                Cipher::XChaCha20Poly1305(XChaCha20Poly1305::new(key))
+            }
+        }
+    }
+
+    /// The suite this cipher implements
+    pub fn suite(&self) -> CipherSuite {
+        match self {
+            Cipher::ChaCha20Poly1305(_) => CipherSuite::ChaCha20Poly1305,
+            Cipher::XChaCha20Poly1305(_) => CipherSuite::XChaCha20Poly1305,
+        }
+    }
+
+    /// Encrypt `data` under a fresh random nonce, returning the nonce followed by the ciphertext
+    pub fn encrypt(&self, data: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            Cipher::ChaCha20Poly1305(cipher) => seal(cipher, data),
+            Cipher::XChaCha20Poly1305(cipher) => seal(cipher, data),
+        }
+    }
+
+    /// Decrypt a payload produced by `encrypt`
+    pub fn decrypt(&self, data: &[u8]) -> Result<Vec<u8>> {
+        let (nonce, ciphertext) = split_nonce(data, self.suite().nonce_len())?;
+        let plaintext = match self {
+            Cipher::ChaCha20Poly1305(cipher) => {
+                cipher.decrypt(Nonce::from_slice(nonce), ciphertext)
+            }
+            Cipher::XChaCha20Poly1305(cipher) => {
+                cipher.decrypt(XNonce::from_slice(nonce), ciphertext)
+            }
+        };
+        plaintext.map_err(|e| {
+            NamedPipeError::Io(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Decryption failed: {}", e),
+            ))
+        })
+    }
+
+    /// Check that this cipher round-trips a known plaintext and rejects a tampered
+    /// ciphertext, failing with an `InvalidData` IO error otherwise
+    pub fn self_test(&self) -> Result<()> {
+        let mut ciphertext = self.encrypt(SELF_TEST_PLAINTEXT)?;
+        if self.decrypt(&ciphertext)? != SELF_TEST_PLAINTEXT {
+            return Err(self_test_failed(
+                "decrypted text differs from the plaintext",
+            ));
+        }
+
+        if let Some(last) = ciphertext.last_mut() {
+            *last ^= 0x01;
+        }
+        if self.decrypt(&ciphertext).is_ok() {
+            return Err(self_test_failed("a tampered ciphertext was accepted"));
+        }
+        Ok(())
+    }
+}
+
+fn self_test_failed(reason: &str) -> NamedPipeError {
+    NamedPipeError::Io(std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        format!("Cipher self-test failed: {}", reason),
+    ))
+}
+
+impl From<ChaCha20Poly1305> for Cipher {
+    fn from(cipher: ChaCha20Poly1305) -> Self {
+        Cipher::ChaCha20Poly1305(cipher)
+    }
+}
+
+impl From<XChaCha20Poly1305> for Cipher {
+    fn from(cipher: XChaCha20Poly1305) -> Self {
+        Cipher::XChaCha20Poly1305(cipher)
+    }
+}
+
+/// A keyed cipher built once and shared by any number of clients and servers, e.g. by an
+/// application that opens many short-lived clients.
+///
+/// The key is checked once, when the cipher is built. Clones share it, and each connection
+/// takes its own copy of the keyed cipher, which for the ChaCha suites costs no more than
+/// copying the key. Every copy zeroizes its key when dropped.
+#[derive(Clone)]
+pub struct PipeCipher {
+    cipher: Arc<Cipher>,
+    /// The key the cipher was built from, if known, for rekeying
+    key: Option<Arc<Zeroizing<[u8; 32]>>>,
+}
+
+impl PipeCipher {
+    /// Build a cipher for `suite`, failing with `NamedPipeError::WeakKey` if the key is
+    /// obviously weak (see `check_key`)
+    pub fn new(suite: CipherSuite, key: &[u8; 32]) -> Result<Self> {
+        check_key(key)?;
+        Ok(Self::with_key(suite, key))
+    }
+
+    /// Build a cipher for `suite` from an already checked key
+    pub(crate) fn with_key(suite: CipherSuite, key: &[u8; 32]) -> Self {
+        Self {
+            cipher: Arc::new(Cipher::new(suite, key)),
+            key: Some(Arc::new(Zeroizing::new(*key))),
+        }
+    }
+
+    /// The suite this cipher implements
+    pub fn suite(&self) -> CipherSuite {
+        self.cipher.suite()
+    }
+
+    /// The shared keyed cipher
+    pub fn cipher(&self) -> &Cipher {
+        &self.cipher
+    }
+
+    /// The key the cipher was built from, unless it was built from a `Cipher`
+    pub(crate) fn key(&self) -> Option<&[u8; 32]> {
+        self.key.as_deref().map(|key| &**key)
+    }
+}
+
+impl From<Cipher> for PipeCipher {
+    fn from(cipher: Cipher) -> Self {
+        Self {
+            cipher: Arc::new(cipher),
+            key: None,
+        }
+    }
+}
+
+/// Fills in each nonce in place of `OsRng`; see `set_nonce_source`
+#[cfg(any(test, feature = "test-util"))]
+pub type NonceSource = Box<dyn FnMut(&mut [u8])>;
+
+#[cfg(any(test, feature = "test-util"))]
+thread_local! {
+    static NONCE_SOURCE: std::cell::RefCell<Option<NonceSource>> = const { std::cell::RefCell::new(None) };
+}
+
+/// Draw the nonces of frames encrypted on this thread from `source` instead of `OsRng`, or
+/// go back to `OsRng` with `None`.
+///
+/// Only for tests, e.g. golden vectors of encrypted frames: reused nonces break the
+/// encryption. Builds without the `test-util` feature always use `OsRng`. The source is
+/// per thread, so use a current-thread runtime to cover frames sealed inside tasks.
+#[cfg(any(test, feature = "test-util"))]
+pub fn set_nonce_source(source: Option<NonceSource>) {
+    NONCE_SOURCE.with_borrow_mut(|current| *current = source);
+}
+
+/// Encrypt `data` under a fresh random nonce and prepend the nonce
+pub(crate) fn seal<A: Aead + AeadCore>(cipher: &A, data: &[u8]) -> Result<Vec<u8>> {
+    let nonce = A::generate_nonce(&mut OsRng);
+    #[cfg(any(test, feature = "test-util"))]
+    let nonce = {
+        let mut nonce = nonce;
+        NONCE_SOURCE.with_borrow_mut(|source| {
+            if let Some(source) = source {
+                source(&mut nonce);
+            }
+        });
+        nonce
+    };
+    let ciphertext = cipher.encrypt(&nonce, data).map_err(|e| {
+        NamedPipeError::Io(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("Encryption failed: {}", e),
+        ))
+    })?;
+
+    let mut message = Vec::with_capacity(nonce.len() + ciphertext.len());
+    message.extend_from_slice(&nonce);
+    message.extend_from_slice(&ciphertext);
+    Ok(message)
+}
+
+/// Split an encrypted payload into its nonce and ciphertext. The shortest valid payload,
+/// a nonce and a bare authentication tag, holds an empty message.
+pub(crate) fn split_nonce(data: &[u8], nonce_len: usize) -> Result<(&[u8], &[u8])> {
+    if data.len() < nonce_len + AUTH_TAG_LEN {
+        return Err(NamedPipeError::Io(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "Encrypted message too short",
+        )));
+    }
+    Ok(data.split_at(nonce_len))
+}
@@ -1,15 +1,162 @@
+use crate::cipher::{check_key, Cipher, CipherSuite, PipeCipher};
 use crate::error::{NamedPipeError, Result};
-use crate::utils::{encrypt_message, format_pipe_name, get_server_pid, verify_same_path};
-use chacha20poly1305::{aead::KeyInit, ChaCha20Poly1305, Key};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::windows::named_pipe::{ClientOptions, NamedPipeClient};
+use crate::framing::{
+    ConnectionParams, Endian, Features, FrameFormat, Framed, Framing, Priority, DEFAULT_ACK_TIMEOUT,
+};
+use crate::handshake::{authenticate_server, derive_client_key, send_hello};
+use crate::message::{
+    CloseCode, ContentType, Headers, Message, Progress, TextEncoding, DEFAULT_COMPRESSION_THRESHOLD,
+};
+use crate::resilient::{ReconnectPolicy, ResilientClient};
+use crate::split::{FramedReadHalf, FramedWriteHalf};
+use crate::stream::STREAM_CHUNK_SIZE;
+use crate::utils::{
+    format_pipe_name, get_pipe_mode, get_server_pid, is_remote_pipe_name, next_connection_id,
+    verify_same_path, verify_signed_process, ConnectionId,
+};
+use futures::Sink;
+use std::collections::BTreeMap;
+use std::os::windows::io::RawHandle;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::windows::named_pipe::{ClientOptions, NamedPipeClient, PipeMode};
+use tokio::time::{sleep, Duration, Instant};
+use windows::core::PCWSTR;
+use windows::Win32::Foundation::{ERROR_PIPE_BUSY, ERROR_SEM_TIMEOUT};
+use windows::Win32::System::Pipes::{WaitNamedPipeW, NMPWAIT_WAIT_FOREVER};
+
+/// Delay between attempts in `connect_with_deadline`
+const CONNECT_RETRY_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Default time `request` waits for its response
+const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Whether opening the pipe may succeed if retried later
+fn is_retryable_open_error(e: &std::io::Error) -> bool {
+    e.kind() == std::io::ErrorKind::NotFound || is_pipe_busy(e)
+}
+
+/// Whether opening the pipe failed because all of its instances are in use
+fn is_pipe_busy(e: &std::io::Error) -> bool {
+    e.raw_os_error() == Some(ERROR_PIPE_BUSY.0 as i32)
+}
+
+/// Wait until an instance of `pipe_name` is free to connect to, up to `timeout` (forever if
+/// `None`). `WaitNamedPipeW` blocks, so it runs on the blocking thread pool. Returns early
+/// if the pipe does not exist; the caller learns the outcome by opening the pipe again.
+async fn wait_for_instance(pipe_name: &str, timeout: Option<Duration>) {
+    let name: Vec<u16> = pipe_name.encode_utf16().chain(std::iter::once(0)).collect();
+    let timeout_ms = timeout.map_or(NMPWAIT_WAIT_FOREVER, |timeout| {
+        // Zero would mean the server's default timeout, and u32::MAX waits forever
+        u32::try_from(timeout.as_millis())
+            .unwrap_or(u32::MAX)
+            .clamp(1, NMPWAIT_WAIT_FOREVER - 1)
+    });
+
+    let _ = tokio::task::spawn_blocking(move || unsafe {
+        WaitNamedPipeW(PCWSTR(name.as_ptr()), timeout_ms)
+    })
+    .await;
+}
+
+/// A snapshot of a client's settings, returned by `NamedPipeClientStruct::effective_config`.
+/// Keys and secrets are reported only as present or absent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientConfig {
+    /// Full pipe name, including the `\\.\pipe\` prefix
+    pub pipe_name: String,
+    /// Whether a key or shared cipher is configured
+    pub encrypted: bool,
+    /// Suite encrypted connections use
+    pub cipher_suite: CipherSuite,
+    /// Whether each connection derives its own key from the master key
+    pub derive_keys: bool,
+    /// Whether the connection handshake runs
+    pub handshake: bool,
+    /// Whether the server must prove it knows a shared secret
+    pub shared_secret: bool,
+    /// Whether the server must run the same executable as the client
+    pub enforce_same_path_server: bool,
+    /// Whether the server's executable must be validly signed
+    pub signed_peer: bool,
+    /// Whether every send is flushed straight away
+    pub auto_flush: bool,
+    /// Whether buffered sends are flushed before each receive
+    pub flush_before_receive: bool,
+    /// Whether buffered sends are flushed when the client is dropped
+    pub flush_on_drop: bool,
+    /// Whether unencrypted frames carry a CRC32C
+    pub integrity_check: bool,
+    /// Whether frames are compressed
+    pub compression: bool,
+    /// Smallest body, in bytes, that is compressed
+    pub compression_threshold: usize,
+    /// Whether `send_bytes_acked` is allowed
+    pub acks: bool,
+    /// How long an acked send waits for its ack
+    pub ack_timeout: Duration,
+    /// Whether encrypted frames carry sequence numbers checked against replays
+    pub replay_protection: bool,
+    /// Whether the pipe reads in byte or message mode
+    pub pipe_mode: PipeMode,
+    /// Layout of the length prefix in front of every frame
+    pub frame_format: FrameFormat,
+    /// How messages are delimited on the stream
+    pub framing: Framing,
+    /// Largest message accepted, in bytes
+    pub max_message_size: u64,
+    /// Largest amount of data in one `send_stream` chunk, in bytes
+    pub stream_chunk_size: usize,
+    /// Headers attached to every data frame
+    pub default_headers: Headers,
+    /// How long `request` waits for the response
+    pub request_timeout: Duration,
+    /// Deadline for `connect`, if any
+    pub connect_timeout: Option<Duration>,
+    /// Whether `connect` waits for a free instance when all are busy
+    pub busy_wait: bool,
+    /// Whether the pipe is opened for reading
+    pub read_access: bool,
+    /// Whether the pipe is opened for writing
+    pub write_access: bool,
+}
 
 /// A named pipe client for Windows
 pub struct NamedPipeClientStruct {
-    client: Option<NamedPipeClient>,
+    client: Option<Framed<NamedPipeClient>>,
+    id: Option<ConnectionId>,
+    authenticated: bool,
+    path_verified: bool,
     pipe_name: String,
-    cipher: Option<ChaCha20Poly1305>,
+    cipher_key: Option<[u8; 32]>,
+    cipher_suite: CipherSuite,
+    shared_cipher: Option<PipeCipher>,
+    derive_keys: bool,
+    keys: BTreeMap<u8, [u8; 32]>,
+    active_key: u8,
     enforce_same_path_server: bool,
+    signed_peer: Option<Option<String>>,
+    auto_flush: bool,
+    flush_before_receive: bool,
+    shared_secret: Option<Vec<u8>>,
+    integrity_check: bool,
+    compression: bool,
+    compression_threshold: usize,
+    acks: bool,
+    ack_timeout: Duration,
+    replay_protection: bool,
+    pipe_mode: PipeMode,
+    frame_format: FrameFormat,
+    framing: Framing,
+    max_message_size: u64,
+    stream_chunk_size: usize,
+    default_headers: Headers,
+    flush_on_drop: bool,
+    handshake: bool,
+    request_timeout: Duration,
+    connect_timeout: Option<Duration>,
+    busy_wait: bool,
+    read_access: bool,
+    write_access: bool,
 }
 
 impl NamedPipeClientStruct {
@@ -17,94 +164,889 @@ impl NamedPipeClientStruct {
     pub fn new(pipe_name: &str) -> Self {
         Self {
             client: None,
+            id: None,
+            authenticated: false,
+            path_verified: false,
             pipe_name: format_pipe_name(pipe_name),
-            cipher: None,
+            cipher_key: None,
+            cipher_suite: CipherSuite::default(),
+            shared_cipher: None,
+            derive_keys: false,
+            keys: BTreeMap::new(),
+            active_key: 0,
             enforce_same_path_server: false,
+            signed_peer: None,
+            auto_flush: true,
+            flush_before_receive: true,
+            shared_secret: None,
+            integrity_check: false,
+            compression: false,
+            compression_threshold: DEFAULT_COMPRESSION_THRESHOLD,
+            acks: false,
+            ack_timeout: DEFAULT_ACK_TIMEOUT,
+            replay_protection: false,
+            pipe_mode: PipeMode::Byte,
+            frame_format: FrameFormat::default(),
+            framing: Framing::default(),
+            max_message_size: u64::MAX,
+            stream_chunk_size: STREAM_CHUNK_SIZE,
+            default_headers: Headers::new(),
+            flush_on_drop: false,
+            handshake: true,
+            request_timeout: DEFAULT_REQUEST_TIMEOUT,
+            connect_timeout: None,
+            busy_wait: false,
+            read_access: true,
+            write_access: true,
         }
     }
 
+    /// Start configuring a client with a builder that checks the options for conflicts
+    pub fn builder(pipe_name: &str) -> NamedPipeClientBuilder {
+        NamedPipeClientBuilder::new(pipe_name)
+    }
+
     /// Create a new named pipe client with encryption.
     /// If key is None, uses a secure compile-time generated default key.
-    /// If key is Some(key), uses the provided custom key.
-    pub fn new_encrypted(pipe_name: &str, key: Option<&[u8; 32]>) -> Self {
+    /// If key is Some(key), uses the provided custom key, which is rejected with
+    /// `NamedPipeError::WeakKey` if it is obviously weak (see `cipher::check_key`).
+    pub fn new_encrypted(pipe_name: &str, key: Option<&[u8; 32]>) -> Result<Self> {
         let key_to_use = key.unwrap_or(&crate::DEFAULT_ENCRYPTION_KEY);
-        let key = Key::from_slice(key_to_use);
-        let cipher = ChaCha20Poly1305::new(key);
+        check_key(key_to_use)?;
 
-        Self {
+        Ok(Self {
             client: None,
+            id: None,
+            authenticated: false,
+            path_verified: false,
             pipe_name: format_pipe_name(pipe_name),
-            cipher: Some(cipher),
+            cipher_key: Some(*key_to_use),
+            cipher_suite: CipherSuite::default(),
+            shared_cipher: None,
+            derive_keys: false,
+            keys: BTreeMap::new(),
+            active_key: 0,
             enforce_same_path_server: false,
+            signed_peer: None,
+            auto_flush: true,
+            flush_before_receive: true,
+            shared_secret: None,
+            integrity_check: false,
+            compression: false,
+            compression_threshold: DEFAULT_COMPRESSION_THRESHOLD,
+            acks: false,
+            ack_timeout: DEFAULT_ACK_TIMEOUT,
+            replay_protection: false,
+            pipe_mode: PipeMode::Byte,
+            frame_format: FrameFormat::default(),
+            framing: Framing::default(),
+            max_message_size: u64::MAX,
+            stream_chunk_size: STREAM_CHUNK_SIZE,
+            default_headers: Headers::new(),
+            flush_on_drop: false,
+            handshake: true,
+            request_timeout: DEFAULT_REQUEST_TIMEOUT,
+            connect_timeout: None,
+            busy_wait: false,
+            read_access: true,
+            write_access: true,
+        })
+    }
+
+    /// Create a new named pipe client encrypted with a cipher built beforehand, which may
+    /// be shared with other clients and servers. The cipher fixes the suite.
+    pub fn new_encrypted_with_cipher(pipe_name: &str, cipher: PipeCipher) -> Self {
+        let mut client = Self::new(pipe_name);
+        client.shared_cipher = Some(cipher);
+        client
+    }
+
+    /// Create a new named pipe client that encrypts each connection under its own key,
+    /// derived with HKDF-SHA256 from `master_key` and random salts exchanged during the
+    /// handshake. The server must be created with `new_encrypted_derived` and the same
+    /// master key; otherwise `connect` fails with `NamedPipeError::EncryptionMismatch`.
+    pub fn new_encrypted_derived(pipe_name: &str, master_key: &[u8; 32]) -> Result<Self> {
+        let mut client = Self::new_encrypted(pipe_name, Some(master_key))?;
+        client.derive_keys = true;
+        Ok(client)
+    }
+
+    /// Choose the cipher suite for encrypted connections (ChaCha20Poly1305 by default).
+    /// The server must use the same suite; the handshake rejects a mismatch with
+    /// `NamedPipeError::EncryptionMismatch`. Has no effect on unencrypted clients and on
+    /// clients created with `new_encrypted_with_cipher`.
+    pub fn set_cipher_suite(&mut self, suite: CipherSuite) {
+        self.cipher_suite = suite;
+    }
+
+    /// Register another key under `id` for key rotation; see `Framed::add_key`. Applies to
+    /// the current connection and every later one; the server must register the same keys
+    /// before the client connects.
+    pub fn add_key(&mut self, id: u8, key: &[u8; 32]) -> Result<()> {
+        if self.cipher_key.is_none() && self.shared_cipher.is_none() {
+            return Err(NamedPipeError::InvalidConfig(
+                "key ids need an encrypted client".to_string(),
+            ));
+        }
+        if self.derive_keys {
+            return Err(NamedPipeError::InvalidConfig(
+                "key ids cannot be combined with derived keys".to_string(),
+            ));
         }
+        check_key(key)?;
+        if let Some(client) = self.client.as_mut() {
+            client.add_key(id, key)?;
+        }
+        self.keys.insert(id, *key);
+        Ok(())
+    }
+
+    /// Seal frames with the key registered under `id` (0 is the key the client was
+    /// created with)
+    pub fn set_active_key(&mut self, id: u8) -> Result<()> {
+        if self.keys.is_empty() || (id != 0 && !self.keys.contains_key(&id)) {
+            return Err(NamedPipeError::UnknownKeyId(id));
+        }
+        if let Some(client) = self.client.as_mut() {
+            client.set_active_key(id)?;
+        }
+        self.active_key = id;
+        Ok(())
+    }
+
+    /// Require the server's executable to carry a valid Authenticode signature from a
+    /// trusted publisher, optionally signed by `expected_subject`, the simple display name
+    /// of the signing certificate (e.g. `"Contoso Ltd"`); see `utils::verify_signed_process`.
+    ///
+    /// Checked on every connect, before any data is exchanged. Servers that fail it are
+    /// dropped with `NamedPipeError::SignatureVerificationFailed`. This proves who published
+    /// the server binary, unlike path checks, which a copied or replaced binary can pass.
+    /// Only local servers can be checked.
+    pub fn enforce_signed_peer(&mut self, expected_subject: Option<String>) {
+        self.signed_peer = Some(expected_subject);
     }
 
     /// Enable enforcement that the server must have the same executable path as this process.
     pub fn enforce_same_path_server(&mut self, enforce: bool) {
         self.enforce_same_path_server = enforce;
     }
-    /// Connect to the named pipe server
+
+    /// Enable or disable the connection handshake (enabled by default); the server must
+    /// match.
+    ///
+    /// The handshake tells the server whether this client encrypts, so a server with a
+    /// different encryption policy rejects the connection with
+    /// `NamedPipeError::EncryptionRequired` or `EncryptionMismatch` instead of exchanging
+    /// unreadable frames. Disable it only to talk to servers that speak the crate's framing
+    /// but not its handshake.
+    pub fn set_handshake(&mut self, enabled: bool) {
+        self.handshake = enabled;
+    }
+
+    /// Require mutual shared-secret authentication when connecting.
+    ///
+    /// The server must be configured with the same secret. `connect` answers the server's
+    /// HMAC-SHA256 challenge and verifies the server's answer to its own, failing with
+    /// `NamedPipeError::AuthFailed` if either side does not know the secret.
+    pub fn set_shared_secret(&mut self, secret: &[u8]) {
+        self.shared_secret = Some(secret.to_vec());
+    }
+
+    /// Enable or disable flushing after every send (enabled by default).
+    ///
+    /// When disabled, `send_bytes` appends each frame to an internal buffer and nothing
+    /// reaches the pipe until `flush` is called. `close` flushes implicitly; `disconnect`
+    /// and dropping the client discard anything still buffered.
+    pub fn set_auto_flush(&mut self, enabled: bool) {
+        self.auto_flush = enabled;
+        if let Some(client) = self.client.as_mut() {
+            client.set_auto_flush(enabled);
+        }
+    }
+
+    /// Flush buffered frames before every receive (enabled by default), so with auto-flush
+    /// disabled a receive never waits for a reply to a request that was never written.
+    /// See `Framed::set_flush_before_receive`.
+    pub fn set_flush_before_receive(&mut self, enabled: bool) {
+        self.flush_before_receive = enabled;
+        if let Some(client) = self.client.as_mut() {
+            client.set_flush_before_receive(enabled);
+        }
+    }
+
+    /// Append a CRC32C to every unencrypted frame and verify it on receive (disabled by
+    /// default), so corrupted frames fail with `NamedPipeError::ChecksumMismatch` instead of
+    /// being parsed. Has no effect on encrypted clients, whose frames are already
+    /// authenticated. The server must use the same setting.
+    pub fn set_integrity_check(&mut self, enabled: bool) {
+        self.integrity_check = enabled;
+        if let Some(client) = self.client.as_mut() {
+            client.set_integrity_check(enabled);
+        }
+    }
+
+    /// Compress frames sent to the server where that makes them smaller (disabled by
+    /// default). With the handshake, frames are only compressed if the server enables
+    /// compression too; otherwise the server decompresses whatever its own setting. See
+    /// `Framed::set_compression` for the ordering with encryption and the risk of
+    /// compressing secrets.
+    pub fn set_compression(&mut self, enabled: bool) {
+        self.compression = enabled;
+        if let Some(client) = self.client.as_mut() {
+            client.set_compression(enabled);
+        }
+    }
+
+    /// Only compress frames whose body is larger than `bytes`
+    /// (`DEFAULT_COMPRESSION_THRESHOLD` by default)
+    pub fn set_compression_threshold(&mut self, bytes: usize) {
+        self.compression_threshold = bytes;
+        if let Some(client) = self.client.as_mut() {
+            client.set_compression_threshold(bytes);
+        }
+    }
+
+    /// Allow `send_bytes_acked` (disabled by default); the handshake keeps it enabled only
+    /// if the server enables acks too. Takes effect on the next connect.
+    pub fn set_acks(&mut self, enabled: bool) {
+        self.acks = enabled;
+    }
+
+    /// Set how long `send_bytes_acked` waits for the server's ack (30 seconds by default)
+    pub fn set_ack_timeout(&mut self, timeout: Duration) {
+        self.ack_timeout = timeout;
+        if let Some(client) = self.client.as_mut() {
+            client.set_ack_timeout(timeout);
+        }
+    }
+
+    /// Reject replayed encrypted frames with `NamedPipeError::ReplayDetected` (disabled by
+    /// default); see `Framed::enable_replay_protection`. Has no effect on unencrypted
+    /// clients. The server must use the same setting.
+    pub fn enable_replay_protection(&mut self, enabled: bool) {
+        self.replay_protection = enabled;
+        if let Some(client) = self.client.as_mut() {
+            client.enable_replay_protection(enabled);
+        }
+    }
+
+    /// Select how messages are delimited; must match the server (`PipeMode::Byte` by default).
+    ///
+    /// `PipeMode::Byte` uses the crate's own length-prefix framing. `PipeMode::Message`
+    /// opens the pipe in message read mode and relies on the pipe's message boundaries
+    /// instead, for interop with peers that expect message-mode pipes. Message mode limits
+    /// payloads to `MAX_MESSAGE_MODE_PAYLOAD` bytes. Takes effect on the next `connect`.
+    pub fn set_pipe_mode(&mut self, mode: PipeMode) {
+        self.pipe_mode = mode;
+    }
+
+    /// Choose how messages are delimited; must match the server. `Framing::None` turns the
+    /// pipe into a plain byte stream (see `Framed::set_framing`) and needs the handshake
+    /// disabled, since the handshake is made of frames; `connect` fails with
+    /// `NamedPipeError::InvalidConfig` otherwise. Takes effect on the next `connect`.
+    pub fn set_framing(&mut self, framing: Framing) {
+        self.framing = framing;
+    }
+
+    /// Set the byte order of the frame length prefix; must match the server
+    /// (little-endian by default)
+    pub fn set_frame_endianness(&mut self, endian: Endian) {
+        self.set_frame_format(FrameFormat {
+            endian,
+            ..self.frame_format
+        });
+    }
+
+    /// Set the layout (byte order and width) of the frame length prefix; must match the
+    /// server. Use this to interoperate with non-Rust peers such as .NET's
+    /// `NamedPipeClientStream` that expect a specific prefix.
+    pub fn set_frame_format(&mut self, format: FrameFormat) {
+        self.frame_format = format;
+        if let Some(client) = self.client.as_mut() {
+            client.set_frame_format(format);
+        }
+    }
+
+    /// Reject frames whose payload is larger than `max_size` bytes (unlimited by default).
+    ///
+    /// The limit covers the payload as sent on the wire, including encryption and checksum
+    /// overhead. Oversized frames fail with `NamedPipeError::MessageTooLarge`, incoming ones
+    /// before any buffer is allocated.
+    pub fn set_max_message_size(&mut self, max_size: u64) {
+        self.max_message_size = max_size;
+        if let Some(client) = self.client.as_mut() {
+            client.set_max_message_size(max_size);
+        }
+    }
+
+    /// Set the chunk size of `send_stream` and the amount `receive_stream` collects before
+    /// writing (`STREAM_CHUNK_SIZE` by default); see `Framed::set_stream_chunk_size`
+    pub fn set_stream_chunk_size(&mut self, bytes: usize) {
+        self.stream_chunk_size = bytes.max(1);
+        if let Some(client) = self.client.as_mut() {
+            client.set_stream_chunk_size(bytes);
+        }
+    }
+
+    /// Attach `headers` to every data frame sent to the server, e.g. a trace id (none by
+    /// default); see `Framed::set_default_headers`
+    pub fn set_default_headers(&mut self, headers: Headers) {
+        if let Some(client) = self.client.as_mut() {
+            client.set_default_headers(headers.clone());
+        }
+        self.default_headers = headers;
+    }
+
+    /// Flush buffered frames on a background task when the client is dropped (disabled by
+    /// default).
+    ///
+    /// `close().await` is the canonical way to shut a client down without losing buffered
+    /// data. With this enabled, dropping a connected client that still holds buffered frames
+    /// hands the connection to a task on the current Tokio runtime, which flushes and then
+    /// closes it. Without it, or outside a runtime, the buffered frames are discarded and a
+    /// warning is logged.
+    pub fn set_flush_on_drop(&mut self, enabled: bool) {
+        self.flush_on_drop = enabled;
+    }
+
+    /// Wait for a free pipe instance when all of the server's instances are busy (disabled
+    /// by default).
+    ///
+    /// When enabled and opening the pipe fails with `ERROR_PIPE_BUSY`, `connect` waits with
+    /// `WaitNamedPipe` until the server frees an instance and then connects straight away,
+    /// instead of failing. `connect_with_deadline` does the same for up to the remaining
+    /// time, instead of polling every 50 ms. The wait occupies a thread of Tokio's blocking
+    /// pool, and a cancelled `connect` leaves that thread waiting until an instance frees
+    /// up, so prefer `connect_with_deadline` when the server may never answer.
+    pub fn set_busy_wait(&mut self, enabled: bool) {
+        self.busy_wait = enabled;
+    }
+
+    /// Open the pipe for reading, writing or both (both by default), e.g. read-only to
+    /// enforce least privilege on a client that only listens.
+    ///
+    /// Sends and receives in a direction the pipe was not opened for fail with
+    /// `NamedPipeError::AccessMode` before touching the pipe. The handshake and message
+    /// mode both need full access, so a restricted client must disable the handshake and
+    /// use byte mode; `connect` fails with `InvalidConfig` otherwise, or if both directions
+    /// are disabled.
+    pub fn set_access(&mut self, read: bool, write: bool) {
+        self.read_access = read;
+        self.write_access = write;
+    }
+
+    /// Set how long `request` waits for its response (30 seconds by default)
+    pub fn set_request_timeout(&mut self, timeout: Duration) {
+        self.request_timeout = timeout;
+    }
+
+    /// Give up on `connect` and `connect_with_metadata` with `NamedPipeError::Timeout` if
+    /// opening the pipe and the handshake take longer than `timeout` (no limit by default)
+    pub fn set_connect_timeout(&mut self, timeout: Option<Duration>) {
+        self.connect_timeout = timeout;
+    }
+
+    /// Check the configured key and cipher suite before connecting: the key must not be
+    /// weak and must round-trip a test message (see `Cipher::self_test`). Always succeeds
+    /// on unencrypted clients.
+    pub fn verify_crypto_setup(&self) -> Result<()> {
+        if let Some(key) = self.cipher_key {
+            check_key(&key)?;
+        }
+        match self.cipher() {
+            Some(cipher) => cipher.self_test(),
+            None => Ok(()),
+        }
+    }
+
+    /// Get the settings currently in effect, e.g. to log them at startup
+    pub fn effective_config(&self) -> ClientConfig {
+        ClientConfig {
+            pipe_name: self.pipe_name.clone(),
+            encrypted: self.cipher_key.is_some() || self.shared_cipher.is_some(),
+            cipher_suite: self
+                .shared_cipher
+                .as_ref()
+                .map_or(self.cipher_suite, PipeCipher::suite),
+            derive_keys: self.derive_keys,
+            handshake: self.handshake,
+            shared_secret: self.shared_secret.is_some(),
+            enforce_same_path_server: self.enforce_same_path_server,
+            signed_peer: self.signed_peer.is_some(),
+            auto_flush: self.auto_flush,
+            flush_before_receive: self.flush_before_receive,
+            flush_on_drop: self.flush_on_drop,
+            integrity_check: self.integrity_check,
+            compression: self.compression,
+            compression_threshold: self.compression_threshold,
+            acks: self.acks,
+            ack_timeout: self.ack_timeout,
+            replay_protection: self.replay_protection,
+            pipe_mode: self.pipe_mode,
+            frame_format: self.frame_format,
+            framing: self.framing,
+            max_message_size: self.max_message_size,
+            stream_chunk_size: self.stream_chunk_size,
+            default_headers: self.default_headers.clone(),
+            request_timeout: self.request_timeout,
+            connect_timeout: self.connect_timeout,
+            busy_wait: self.busy_wait,
+            read_access: self.read_access,
+            write_access: self.write_access,
+        }
+    }
+
+    /// Write any buffered frames to the pipe and flush it
+    pub async fn flush(&mut self) -> Result<()> {
+        self.framed()?.flush().await
+    }
+
+    /// Whether a server is listening on `pipe_name`, without connecting to it. The name is
+    /// normalized like the one given to `new`.
+    ///
+    /// Uses `WaitNamedPipeW` with the shortest timeout, which reports a pipe with a free
+    /// instance right away and one whose instances are all busy after about a millisecond.
+    /// The answer may be stale by the time it is used: the server can stop, or start, before
+    /// a following `connect`, so handle that failing anyway.
+    pub fn exists(pipe_name: &str) -> bool {
+        let name: Vec<u16> = format_pipe_name(pipe_name)
+            .encode_utf16()
+            .chain(std::iter::once(0))
+            .collect();
+        // Zero would mean the server's default timeout
+        if unsafe { WaitNamedPipeW(PCWSTR(name.as_ptr()), 1) }.as_bool() {
+            return true;
+        }
+        // Timing out means the pipe exists but has no free instance
+        std::io::Error::last_os_error().raw_os_error() == Some(ERROR_SEM_TIMEOUT.0 as i32)
+    }
+
+    /// Connect to the named pipe server.
+    ///
+    /// # Cancel safety
+    ///
+    /// This method is cancel-safe. The client only becomes connected once the pipe has been
+    /// opened and verified, so dropping the future (e.g. when another `tokio::select!` branch
+    /// wins) leaves the client in a clean not-connected state.
     pub async fn connect(&mut self) -> Result<()> {
+        self.connect_within_timeout(None).await
+    }
+
+    /// Connect to the named pipe server and send `meta` as the first message.
+    ///
+    /// The server must be configured with `set_expect_metadata`, and exposes the bytes as
+    /// `NamedPipeConnection::metadata` before its handler runs. Use this for the identifying
+    /// header (client name, version, capabilities) a client would otherwise send by hand.
+    /// The metadata counts against the max message size. Like `connect`, this method is
+    /// cancel-safe.
+    pub async fn connect_with_metadata(&mut self, meta: &[u8]) -> Result<()> {
+        self.connect_within_timeout(Some(meta)).await
+    }
+
+    /// Connect to the named pipe server, retrying until `deadline` while the pipe does not
+    /// exist yet or all of its instances are busy.
+    ///
+    /// Returns `NamedPipeError::Timeout` if no connection could be made before the deadline.
+    /// Like `connect`, this method is cancel-safe.
+    pub async fn connect_with_deadline(&mut self, deadline: Instant) -> Result<()> {
+        loop {
+            match self.open() {
+                Ok(framed) => return self.establish(framed, None).await,
+                Err(NamedPipeError::Io(e)) if self.busy_wait && is_pipe_busy(&e) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Err(NamedPipeError::Timeout);
+                    }
+                    wait_for_instance(&self.pipe_name, Some(deadline - now)).await;
+                }
+                Err(NamedPipeError::Io(e)) if is_retryable_open_error(&e) => {
+                    if Instant::now() + CONNECT_RETRY_INTERVAL >= deadline {
+                        return Err(NamedPipeError::Timeout);
+                    }
+                    sleep(CONNECT_RETRY_INTERVAL).await;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Open the pipe and establish the connection, bounded by the connect timeout if set
+    async fn connect_within_timeout(&mut self, metadata: Option<&[u8]>) -> Result<()> {
+        let timeout = self.connect_timeout;
+        let connect = async {
+            let framed = self.open_waiting().await?;
+            self.establish(framed, metadata).await
+        };
+        match timeout {
+            Some(timeout) => tokio::time::timeout(timeout, connect)
+                .await
+                .map_err(|_| NamedPipeError::Timeout)?,
+            None => connect.await,
+        }
+    }
+
+    /// Open the pipe like `open`, first waiting for a free instance if busy-wait is enabled
+    async fn open_waiting(&self) -> Result<Framed<NamedPipeClient>> {
+        loop {
+            match self.open() {
+                Err(NamedPipeError::Io(e)) if self.busy_wait && is_pipe_busy(&e) => {
+                    wait_for_instance(&self.pipe_name, None).await;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Open the pipe, without touching the client's state
+    fn open(&self) -> Result<Framed<NamedPipeClient>> {
+        if self.framing == Framing::None && self.handshake {
+            return Err(NamedPipeError::InvalidConfig(
+                "raw framing needs the handshake disabled".to_string(),
+            ));
+        }
+        let access_conflict = match (self.read_access, self.write_access) {
+            (true, true) => None,
+            (false, false) => Some("no access at all"),
+            _ if self.handshake => Some("the handshake"),
+            _ if self.pipe_mode == PipeMode::Message => Some("message mode"),
+            _ => None,
+        };
+        if let Some(conflict) = access_conflict {
+            return Err(NamedPipeError::InvalidConfig(format!(
+                "restricted access cannot be combined with {}",
+                conflict
+            )));
+        }
         let client = ClientOptions::new()
+            .read(self.read_access)
+            .write(self.write_access)
+            .pipe_mode(self.pipe_mode)
             .open(&self.pipe_name)
             .map_err(NamedPipeError::Io)?;
 
-        self.client = Some(client);
+        let mut framed = Framed::with_cipher(client, self.cipher());
+        framed.set_cipher_key(self.cipher_key());
+        framed.set_auto_flush(self.auto_flush);
+        framed.set_flush_before_receive(self.flush_before_receive);
+        framed.install_keys(&self.keys, self.active_key)?;
+        framed.set_integrity_check(self.integrity_check);
+        framed.set_compression(self.compression);
+        framed.set_compression_threshold(self.compression_threshold);
+        framed.set_acks(self.acks);
+        framed.set_ack_timeout(self.ack_timeout);
+        framed.enable_replay_protection(self.replay_protection);
+        framed.set_pipe_mode(self.pipe_mode);
+        framed.set_frame_format(self.frame_format);
+        framed.set_framing(self.framing);
+        framed.set_max_message_size(self.max_message_size);
+        framed.set_stream_chunk_size(self.stream_chunk_size);
+        framed.set_default_headers(self.default_headers.clone());
+        framed.set_access(self.read_access, self.write_access);
+        Ok(framed)
+    }
 
+    /// Finish setting up a freshly opened pipe and make it the current connection
+    async fn establish(
+        &mut self,
+        mut framed: Framed<NamedPipeClient>,
+        metadata: Option<&[u8]>,
+    ) -> Result<()> {
         // Verify server path if enforcement is enabled
-        self.verify_server_path()?;
+        let path_verified = self.enforce_same_path_server;
+        if path_verified {
+            verify_same_path(get_server_pid(framed.get_ref())?)?;
+        }
+        if let Some(expected_subject) = self.signed_peer.clone() {
+            // WinVerifyTrust may fetch revocation lists, so keep it off the runtime's threads
+            let pid = get_server_pid(framed.get_ref())?;
+            tokio::task::spawn_blocking(move || {
+                verify_signed_process(pid, expected_subject.as_deref())
+            })
+            .await
+            .map_err(|err| NamedPipeError::SignatureVerificationFailed(err.to_string()))??;
+        }
+        let authenticated = self.handshake(&mut framed).await?;
+        if let Some(metadata) = metadata {
+            framed.send_bytes(metadata).await?;
+            framed.flush().await?;
+        }
 
+        self.client = Some(framed);
+        self.id = Some(next_connection_id());
+        self.authenticated = authenticated;
+        self.path_verified = path_verified;
         Ok(())
     }
 
-    /// Send raw bytes to the server
-    pub async fn send_bytes(&mut self, data: &[u8]) -> Result<()> {
-        let client = self.client.as_mut().ok_or(NamedPipeError::NotConnected)?;
+    /// Run the connection handshake on a freshly opened pipe, returning whether the server
+    /// was authenticated
+    async fn handshake(&self, framed: &mut Framed<NamedPipeClient>) -> Result<bool> {
+        if self.handshake {
+            send_hello(framed, self.derive_keys).await?;
+        } else if self.derive_keys {
+            return Err(NamedPipeError::InvalidConfig(
+                "key derivation needs the handshake".to_string(),
+            ));
+        }
+        if let Some(master_key) = self.cipher_key.filter(|_| self.derive_keys) {
+            derive_client_key(framed, &master_key).await?;
+        }
+        if let Some(secret) = self.shared_secret.as_deref() {
+            authenticate_server(framed, secret).await?;
+            return Ok(true);
+        }
+        Ok(false)
+    }
 
-        if let Some(ref cipher) = self.cipher {
-            let encrypted_message = encrypt_message(cipher, data)?;
+    /// The cipher for new connections: the shared one, or one built from the key
+    fn cipher(&self) -> Option<Cipher> {
+        match (&self.shared_cipher, self.cipher_key) {
+            (Some(cipher), _) => Some(cipher.cipher().clone()),
+            (None, Some(key)) => Some(Cipher::new(self.cipher_suite, &key)),
+            (None, None) => None,
+        }
+    }
 
-            // Send length-prefixed encrypted message
-            let len = encrypted_message.len() as u32;
-            client.write_all(&len.to_le_bytes()).await?;
-            client.write_all(&encrypted_message).await?;
-        } else {
-            // Send unencrypted data with length prefix
-            let len = data.len() as u32;
-            client.write_all(&len.to_le_bytes()).await?;
-            client.write_all(data).await?;
+    /// The key `cipher` is built from, if known
+    fn cipher_key(&self) -> Option<&[u8; 32]> {
+        match &self.shared_cipher {
+            Some(cipher) => cipher.key(),
+            None => self.cipher_key.as_ref(),
         }
+    }
 
-        client.flush().await?;
-        Ok(())
+    fn framed(&mut self) -> Result<&mut Framed<NamedPipeClient>> {
+        self.client.as_mut().ok_or(NamedPipeError::NotConnected)
+    }
+
+    /// Send raw bytes to the server.
+    ///
+    /// # Cancel safety
+    ///
+    /// This method is not cancel-safe. If the future is dropped after part of a frame has
+    /// been written, the peer sees a truncated frame and the connection should be discarded.
+    pub async fn send_bytes(&mut self, data: &[u8]) -> Result<()> {
+        self.framed()?.send_bytes(data).await
+    }
+
+    /// Send raw bytes to the server and wait for it to confirm receipt; see
+    /// `Framed::send_bytes_acked`. Needs `set_acks(true)` on both ends.
+    pub async fn send_bytes_acked(&mut self, data: &[u8]) -> Result<()> {
+        self.framed()?.send_bytes_acked(data).await
+    }
+
+    /// Send raw bytes ahead of buffered frames of lower priority; see `Framed::send_bytes_priority`
+    pub async fn send_bytes_priority(&mut self, data: &[u8], priority: Priority) -> Result<()> {
+        self.framed()?.send_bytes_priority(data, priority).await
+    }
+
+    /// Send bytes already encoded as `content_type`, e.g. a bincode-serialized value
+    pub async fn send_with_content_type(
+        &mut self,
+        data: &[u8],
+        content_type: ContentType,
+    ) -> Result<()> {
+        self.framed()?
+            .send_with_content_type(data, content_type)
+            .await
+    }
+
+    /// Send an application-level error that the server receives as `NamedPipeError::RemoteError`
+    pub async fn send_error(&mut self, code: u32, msg: &str) -> Result<()> {
+        self.framed()?.send_error(code, msg).await
+    }
+
+    /// Send everything `reader` yields to the server as a resumable stream; see `Framed::send_stream`
+    pub async fn send_stream<R: AsyncRead + Unpin>(&mut self, reader: R) -> Result<u64> {
+        self.framed()?.send_stream(reader).await
+    }
+
+    /// Resume an interrupted stream from the offset the server acknowledged
+    pub async fn resume_stream<R: AsyncRead + Unpin>(
+        &mut self,
+        from_offset: u64,
+        reader: R,
+    ) -> Result<u64> {
+        self.framed()?.resume_stream(from_offset, reader).await
+    }
+
+    /// Receive a stream from the server into `writer`, returning its total length
+    pub async fn receive_stream<W: AsyncWrite + Unpin>(&mut self, writer: W) -> Result<u64> {
+        self.framed()?.receive_stream(writer).await
+    }
+
+    /// Receive the rest of an interrupted stream of which `from_offset` bytes were written
+    pub async fn resume_receive_stream<W: AsyncWrite + Unpin>(
+        &mut self,
+        from_offset: u64,
+        writer: W,
+    ) -> Result<u64> {
+        self.framed()?
+            .resume_receive_stream(from_offset, writer)
+            .await
+    }
+
+    /// Forward every frame from the server into `sink` until it disconnects; see
+    /// `Framed::drain_to`
+    pub async fn drain_to<Si>(&mut self, sink: Si) -> Result<u64>
+    where
+        Si: Sink<Vec<u8>> + Unpin,
+        Si::Error: std::error::Error + Send + Sync + 'static,
+    {
+        self.framed()?.drain_to(sink).await
+    }
+
+    /// Receive raw bytes from the server, reserving room for `hint` bytes up front; see
+    /// `Framed::receive_bytes_with_capacity`
+    pub async fn receive_bytes_with_capacity(&mut self, hint: usize) -> Result<Vec<u8>> {
+        self.framed()?.receive_bytes_with_capacity(hint).await
     }
 
-    /// Receive raw bytes from the server
+    /// Send the next `len` bytes of `reader` as one frame without buffering it; see
+    /// `Framed::send_frame_from`
+    pub async fn send_frame_from<R: AsyncRead + Unpin>(
+        &mut self,
+        reader: R,
+        len: u64,
+    ) -> Result<()> {
+        self.framed()?.send_frame_from(reader, len).await
+    }
+
+    /// Receive one frame into `writer` without buffering it, returning its length; see
+    /// `Framed::receive_frame_into`
+    pub async fn receive_frame_into<W: AsyncWrite + Unpin>(&mut self, writer: W) -> Result<u64> {
+        self.framed()?.receive_frame_into(writer).await
+    }
+
+    /// Receive one message from the server, decoded according to its content type
+    pub async fn receive_any(&mut self) -> Result<Message> {
+        self.framed()?.receive_any().await
+    }
+
+    /// Receive raw bytes from the server.
+    ///
+    /// # Cancel safety
+    ///
+    /// This method is cancel-safe. Bytes of a partially received frame are kept in the
+    /// read-ahead buffer, so if the future is dropped (e.g. when another `tokio::select!`
+    /// branch wins), the next receive continues the same frame instead of losing sync.
     pub async fn receive_bytes(&mut self) -> Result<Vec<u8>> {
-        let client = self.client.as_mut().ok_or(NamedPipeError::NotConnected)?;
+        self.framed()?.receive_bytes().await
+    }
 
-        // Read length first
-        let mut len_bytes = [0u8; 4];
-        client.read_exact(&mut len_bytes).await?;
-        let len = u32::from_le_bytes(len_bytes) as usize;
+    /// Receive the result of a long operation from the server, calling `on_progress` with
+    /// each progress update sent before it; see `Framed::receive_with_progress`
+    pub async fn receive_with_progress<F: FnMut(Progress) + Send>(
+        &mut self,
+        on_progress: F,
+    ) -> Result<Vec<u8>> {
+        self.framed()?.receive_with_progress(on_progress).await
+    }
+
+    /// Send a progress update to the server; see `Framed::send_progress`
+    pub async fn send_progress(&mut self, progress: &Progress) -> Result<()> {
+        self.framed()?.send_progress(progress).await
+    }
+
+    /// Send raw bytes to the server with `headers` on top of the default headers
+    pub async fn send_bytes_with_headers(&mut self, data: &[u8], headers: &Headers) -> Result<()> {
+        self.framed()?.send_bytes_with_headers(data, headers).await
+    }
+
+    /// Get the headers of the last frame received from the server. Fails with
+    /// `NamedPipeError::NotConnected` when not connected.
+    pub fn received_headers(&self) -> Result<&Headers> {
+        let client = self.client.as_ref().ok_or(NamedPipeError::NotConnected)?;
+        Ok(client.received_headers())
+    }
 
-        // Read data
-        let mut buffer = vec![0u8; len];
-        client.read_exact(&mut buffer).await?;
+    /// Send one frame and wait for exactly one response frame.
+    ///
+    /// The request, and anything buffered before it, is flushed before waiting for the
+    /// response, even when auto-flush and flush-before-receive are disabled. If no response
+    /// arrives within the request timeout, this returns `NamedPipeError::Timeout` and
+    /// disconnects the client, since a late response would otherwise be taken as the
+    /// answer to the next request.
+    ///
+    /// There is no correlation between requests and responses: the server must answer
+    /// every request with exactly one frame, in order, and the client must not send or
+    /// receive anything else while a request is outstanding.
+    pub async fn request(&mut self, data: &[u8]) -> Result<Vec<u8>> {
+        let timeout = self.request_timeout;
+        let framed = self.framed()?;
+        framed.send_bytes(data).await?;
+        framed.flush().await?;
 
-        if let Some(ref cipher) = self.cipher {
-            let plaintext = crate::utils::decrypt_message(cipher, &buffer)?;
-            Ok(plaintext)
-        } else {
-            Ok(buffer)
+        match tokio::time::timeout(timeout, framed.receive_bytes()).await {
+            Ok(response) => response,
+            Err(_) => {
+                self.disconnect();
+                Err(NamedPipeError::Timeout)
+            }
         }
     }
 
+    /// Get the id of the current connection, or `None` if not connected.
+    ///
+    /// A fresh id is assigned on every successful connect, drawn from the same
+    /// process-wide counter as server-side connection ids.
+    pub fn id(&self) -> Option<ConnectionId> {
+        self.id
+    }
+
     /// Check if the client is connected
     pub fn is_connected(&self) -> bool {
         self.client.is_some()
     }
 
+    /// Split the connection into a read half and a write half for separate tasks; see
+    /// `Framed::into_split`. Fails with `NamedPipeError::NotConnected` when not connected,
+    /// or `InvalidConfig` while acks are enabled.
+    pub fn into_split(
+        mut self,
+    ) -> Result<(
+        FramedReadHalf<NamedPipeClient>,
+        FramedWriteHalf<NamedPipeClient>,
+    )> {
+        let client = self.client.take().ok_or(NamedPipeError::NotConnected)?;
+        client.into_split()
+    }
+
+    /// Number of bytes of frames buffered for the server but not yet written, e.g. to decide
+    /// at shutdown whether to `flush` first; see `Framed::buffered_len`. 0 when not connected.
+    pub fn pending_write_bytes(&self) -> usize {
+        self.client.as_ref().map_or(0, Framed::buffered_len)
+    }
+
+    /// The optional features agreed with the server in the handshake, e.g. compression is
+    /// only used if both sides enabled it. Without the handshake, the features this client
+    /// was configured with.
+    pub fn negotiated_capabilities(&self) -> Result<Features> {
+        let client = self.client.as_ref().ok_or(NamedPipeError::NotConnected)?;
+        Ok(client.capabilities())
+    }
+
+    /// What the connection to the server actually uses: the protocol version, cipher suite,
+    /// features and framing agreed in the handshake, and which peer checks it passed.
+    /// Fails with `NamedPipeError::NotConnected` when not connected.
+    pub fn connection_params(&self) -> Result<ConnectionParams> {
+        let client = self.client.as_ref().ok_or(NamedPipeError::NotConnected)?;
+        Ok(ConnectionParams {
+            authenticated: self.authenticated,
+            path_verified: self.path_verified,
+            ..client.connection_params(self.handshake)
+        })
+    }
+
+    /// Ask Windows whether the connected pipe reads in byte or message mode.
+    ///
+    /// Unlike the configured `set_pipe_mode`, this reports the actual state of the handle,
+    /// which helps diagnose framing mismatches with non-Rust servers.
+    pub fn pipe_mode(&self) -> Result<PipeMode> {
+        let client = self.client.as_ref().ok_or(NamedPipeError::NotConnected)?;
+        get_pipe_mode(client.get_ref())
+    }
+
     /// Verify that the server has the same executable path as this process, if enforcement is enabled.
     pub fn verify_server_path(&self) -> Result<()> {
         if !self.enforce_same_path_server {
@@ -112,52 +1054,102 @@ impl NamedPipeClientStruct {
         }
 
         let client = self.client.as_ref().ok_or(NamedPipeError::NotConnected)?;
-        let server_pid = get_server_pid(client)?;
+        let server_pid = get_server_pid(client.get_ref())?;
         verify_same_path(server_pid)
     }
 
+    /// Receive a handle the server duplicated into this process with `send_handle`.
+    /// The returned handle is owned by the caller, who is responsible for closing it. A data
+    /// frame arriving first fails with `InvalidData` and is kept for the next receive.
+    pub async fn receive_handle(&mut self) -> Result<RawHandle> {
+        let value = self.framed()?.receive_handle().await?;
+        Ok(value as usize as RawHandle)
+    }
+
     /// Send a string message to the server
     pub async fn send_string(&mut self, message: &str) -> Result<()> {
-        let data = message.as_bytes();
-        self.send_bytes(data).await
+        self.framed()?.send_string(message).await
     }
 
     /// Receive a string message from the server
     pub async fn receive_string(&mut self) -> Result<String> {
-        let data = self.receive_bytes().await?;
-        String::from_utf8(data).map_err(|e| {
-            NamedPipeError::Io(std::io::Error::new(
-                std::io::ErrorKind::InvalidData,
-                format!("Invalid UTF-8 string: {}", e),
-            ))
-        })
+        self.framed()?.receive_string().await
+    }
+
+    /// Send a string message to the server in the given encoding
+    pub async fn send_string_as(&mut self, message: &str, encoding: TextEncoding) -> Result<()> {
+        self.framed()?.send_string_as(message, encoding).await
+    }
+
+    /// Receive a string message in the given encoding from the server
+    pub async fn receive_string_as(&mut self, encoding: TextEncoding) -> Result<String> {
+        self.framed()?.receive_string_as(encoding).await
     }
 
     /// Send a JSON message to the server
     pub async fn send_json<T: serde::Serialize>(&mut self, message: &T) -> Result<()> {
-        let json = serde_json::to_string(message).map_err(|e| {
-            NamedPipeError::Io(std::io::Error::new(
-                std::io::ErrorKind::InvalidData,
-                format!("JSON serialization failed: {}", e),
-            ))
-        })?;
-        self.send_string(&json).await
+        self.framed()?.send_json(message).await
     }
 
     /// Receive a JSON message from the server
     pub async fn receive_json<T: serde::de::DeserializeOwned>(&mut self) -> Result<T> {
-        let json = self.receive_string().await?;
-        serde_json::from_str(&json).map_err(|e| {
-            NamedPipeError::Io(std::io::Error::new(
-                std::io::ErrorKind::InvalidData,
-                format!("JSON deserialization failed: {}", e),
-            ))
-        })
+        self.framed()?.receive_json().await
+    }
+
+    /// Flush any buffered frames and disconnect from the server
+    pub async fn close(&mut self) -> Result<()> {
+        if let Some(client) = self.client.as_mut() {
+            client.flush().await?;
+        }
+        self.disconnect();
+        Ok(())
+    }
+
+    /// Restart the sequence numbers of both ends, optionally switching to a derived key; see
+    /// `Framed::reset_crypto_state`. Applies to the current connection only: a reconnect
+    /// starts from the configured keys.
+    pub async fn reset_crypto_state(&mut self, rekey: bool) -> Result<()> {
+        self.framed()?.reset_crypto_state(rekey).await
+    }
+
+    /// Switch a plaintext connection to encryption with `key` under `suite` together with
+    /// the server; see `Framed::upgrade_to_encrypted`. A reconnected client starts in
+    /// plaintext again.
+    pub async fn upgrade_to_encrypted(&mut self, key: &[u8; 32], suite: CipherSuite) -> Result<()> {
+        self.framed()?.upgrade_to_encrypted(key, suite).await
+    }
+
+    /// Finish sending but keep receiving, e.g. after the last part of a request; see
+    /// `Framed::shutdown_write`. The server's receives fail with
+    /// `NamedPipeError::PeerWriteShutdown` once it has read everything sent before.
+    pub async fn shutdown_write(&mut self) -> Result<()> {
+        self.framed()?.shutdown_write().await
     }
 
-    /// Disconnect from the server
+    /// Flush any buffered frames, tell the server why with a close frame and disconnect.
+    /// The server's next receive fails with `NamedPipeError::ClosedWithCode(code)`.
+    pub async fn close_with_code(&mut self, code: CloseCode) -> Result<()> {
+        if let Some(client) = self.client.as_mut() {
+            client.send_close(code).await?;
+        }
+        self.disconnect();
+        Ok(())
+    }
+
+    /// Disconnect from the server, discarding any buffered frames
     pub fn disconnect(&mut self) {
         self.client = None;
+        self.id = None;
+    }
+
+    /// Get the underlying pipe client, if connected.
+    ///
+    /// This is an escape hatch for calling Win32 pipe APIs the crate does not wrap, such as
+    /// `SetNamedPipeHandleState`. Reading from or writing to the pipe directly bypasses the
+    /// framing layer; mixing that with `send_bytes`/`receive_bytes` is unsupported and will
+    /// desynchronize the connection.
+    pub fn as_raw_client(&self) -> Option<&NamedPipeClient> {
+        self.client.as_ref().map(Framed::get_ref)
     }
 
     /// Get the pipe name
@@ -166,8 +1158,130 @@ impl NamedPipeClientStruct {
     }
 }
 
+/// A builder for `NamedPipeClientStruct` that rejects conflicting options in `build`.
+///
+/// ```ignore
+/// let mut client = NamedPipeClientStruct::builder("my_pipe")
+///     .encryption(Some(&key))
+///     .connect_timeout(Duration::from_secs(5))
+///     .build()?;
+/// ```
+#[must_use = "a builder does nothing until `build` is called"]
+pub struct NamedPipeClientBuilder {
+    pipe_name: String,
+    encryption: Option<Option<[u8; 32]>>,
+    enforce_same_path_server: bool,
+    connect_timeout: Option<Duration>,
+    reconnect_policy: Option<ReconnectPolicy>,
+    frame_format: FrameFormat,
+}
+
+impl NamedPipeClientBuilder {
+    /// Start configuring an unencrypted client for `pipe_name`
+    pub fn new(pipe_name: &str) -> Self {
+        Self {
+            pipe_name: pipe_name.to_string(),
+            encryption: None,
+            enforce_same_path_server: false,
+            connect_timeout: None,
+            reconnect_policy: None,
+            frame_format: FrameFormat::default(),
+        }
+    }
+
+    /// Encrypt the connection, with the default key if `key` is `None`; see
+    /// `NamedPipeClientStruct::new_encrypted`
+    pub fn encryption(mut self, key: Option<&[u8; 32]>) -> Self {
+        self.encryption = Some(key.copied());
+        self
+    }
+
+    /// Require the server to run the same executable as this process
+    pub fn enforce_same_path_server(mut self, enforce: bool) -> Self {
+        self.enforce_same_path_server = enforce;
+        self
+    }
+
+    /// Bound how long connecting may take; see `NamedPipeClientStruct::set_connect_timeout`
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = Some(timeout);
+        self
+    }
+
+    /// Retry failed operations according to `policy`; only valid with `build_resilient`
+    pub fn reconnect_policy(mut self, policy: ReconnectPolicy) -> Self {
+        self.reconnect_policy = Some(policy);
+        self
+    }
+
+    /// Set the frame length prefix format; the server must match
+    pub fn frame_config(mut self, format: FrameFormat) -> Self {
+        self.frame_format = format;
+        self
+    }
+
+    /// Build the client, failing with `NamedPipeError::InvalidConfig` if options conflict
+    /// or with `NamedPipeError::WeakKey` if the encryption key is weak
+    pub fn build(self) -> Result<NamedPipeClientStruct> {
+        if self.reconnect_policy.is_some() {
+            return Err(NamedPipeError::InvalidConfig(
+                "a reconnect policy requires build_resilient".to_string(),
+            ));
+        }
+        self.build_client()
+    }
+
+    /// Build a `ResilientClient` that retries according to the reconnect policy, or the
+    /// default policy if none was set
+    pub fn build_resilient(mut self) -> Result<ResilientClient> {
+        let policy = self.reconnect_policy.take().unwrap_or_default();
+        Ok(ResilientClient::with_policy(self.build_client()?, policy))
+    }
+
+    fn build_client(self) -> Result<NamedPipeClientStruct> {
+        let pipe_name = format_pipe_name(&self.pipe_name);
+        if self.enforce_same_path_server && is_remote_pipe_name(&pipe_name) {
+            return Err(NamedPipeError::InvalidConfig(
+                "server path enforcement is not possible for a remote pipe".to_string(),
+            ));
+        }
+        if self.connect_timeout == Some(Duration::ZERO) {
+            return Err(NamedPipeError::InvalidConfig(
+                "the connect timeout must not be zero".to_string(),
+            ));
+        }
+
+        let mut client = match self.encryption {
+            Some(key) => NamedPipeClientStruct::new_encrypted(&pipe_name, key.as_ref())?,
+            None => NamedPipeClientStruct::new(&pipe_name),
+        };
+        client.enforce_same_path_server(self.enforce_same_path_server);
+        client.set_connect_timeout(self.connect_timeout);
+        client.set_frame_format(self.frame_format);
+        Ok(client)
+    }
+}
+
 impl Drop for NamedPipeClientStruct {
     fn drop(&mut self) {
+        if let Some(mut client) = self.client.take() {
+            let buffered = client.buffered_len();
+            if buffered > 0 {
+                match tokio::runtime::Handle::try_current() {
+                    Ok(runtime) if self.flush_on_drop => {
+                        runtime.spawn(async move {
+                            if let Err(e) = client.flush().await {
+                                eprintln!("Failed to flush dropped client: {}", e);
+                            }
+                        });
+                    }
+                    _ => eprintln!(
+                        "Warning: client for {} dropped with {} buffered bytes; call close() to flush them",
+                        self.pipe_name, buffered
+                    ),
+                }
+            }
+        }
         self.disconnect();
     }
 }
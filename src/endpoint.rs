@@ -0,0 +1,75 @@
+//! One channel between two symmetric peers, whichever of them starts first.
+//!
+//! `PipeEndpoint::connect_or_host` tries to connect to the pipe as a client, and if no one
+//! is hosting it yet, creates it as the first instance of its name and waits for the other
+//! peer. If both peers try to host at once, only one pipe creation can succeed; the other
+//! sees the name taken and connects as a client instead.
+
+use crate::client::NamedPipeClientStruct;
+use crate::error::{NamedPipeError, Result};
+use crate::server::{NamedPipeConnection, NamedPipeServerStruct};
+use futures::StreamExt;
+use tokio::time::{sleep, Duration};
+
+/// Delay before retrying after losing a race for the pipe name
+const RETRY_INTERVAL: Duration = Duration::from_millis(20);
+
+/// How often `connect_or_host` switches roles before giving up
+const MAX_ATTEMPTS: u32 = 50;
+
+/// The role a peer took in `connect_or_host`, holding its end of the channel
+pub enum PipeEndpoint {
+    /// The pipe already existed and this peer connected to it
+    Client(NamedPipeClientStruct),
+    /// This peer created the pipe and the other peer connected to it
+    Host(NamedPipeConnection),
+}
+
+impl PipeEndpoint {
+    /// Connect to `pipe_name` as a client, or host it and wait for one client if it does
+    /// not exist yet.
+    ///
+    /// The host accepts exactly one connection and then stops listening, so a third peer
+    /// cannot join. Fails with `NamedPipeError::PipeNameInUse` if the name stays taken by a
+    /// pipe that cannot be connected to, e.g. because its instances are all busy.
+    pub async fn connect_or_host(pipe_name: &str) -> Result<Self> {
+        let mut last_error = NamedPipeError::PipeNameInUse(pipe_name.to_string());
+        for _ in 0..MAX_ATTEMPTS {
+            let mut client = NamedPipeClientStruct::new(pipe_name);
+            match client.connect().await {
+                Ok(()) => return Ok(PipeEndpoint::Client(client)),
+                Err(NamedPipeError::Io(e)) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => {
+                    last_error = e;
+                    sleep(RETRY_INTERVAL).await;
+                    continue;
+                }
+            }
+
+            match host(pipe_name).await {
+                Ok(connection) => return Ok(PipeEndpoint::Host(connection)),
+                // The other peer created the pipe first; connect to it instead
+                Err(e @ NamedPipeError::PipeNameInUse(_)) => last_error = e,
+                Err(e) => return Err(e),
+            }
+        }
+        Err(last_error)
+    }
+
+    /// Whether this peer hosts the pipe
+    pub fn is_host(&self) -> bool {
+        matches!(self, PipeEndpoint::Host(_))
+    }
+}
+
+/// Create `pipe_name` as its first instance and accept a single connection
+async fn host(pipe_name: &str) -> Result<NamedPipeConnection> {
+    let mut server = NamedPipeServerStruct::new(pipe_name);
+    server.set_first_pipe_instance(true);
+    server.set_max_total_connections(1);
+
+    let mut incoming = Box::pin(server.incoming());
+    let connection = incoming.next().await;
+    server.stop().await?;
+    connection.unwrap_or(Err(NamedPipeError::ConnectionClosed))
+}
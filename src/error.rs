@@ -11,14 +11,131 @@ pub enum NamedPipeError {
     #[error("Connection closed")]
     ConnectionClosed,
 
+    #[error("Peer disconnected")]
+    Disconnected,
+
     #[error("Invalid pipe name: {0}")]
     InvalidPipeName(String),
 
     #[error("Timeout occurred")]
     Timeout,
 
+    #[error("Client did not complete the handshake in time")]
+    HandshakeTimeout,
+
     #[error("Server already running on pipe: {0}")]
     ServerAlreadyRunning(String),
+
+    #[error("Pipe name already in use by another server: {0}")]
+    PipeNameInUse(String),
+
+    #[error("Connection was not opened for {0}")]
+    AccessMode(&'static str),
+
+    #[error("Sending was shut down with shutdown_write")]
+    WriteShutdown,
+
+    #[error("Peer shut down sending")]
+    PeerWriteShutdown,
+
+    #[error("Invalid configuration: {0}")]
+    InvalidConfig(String),
+
+    #[error("Connection rejected by the accept filter")]
+    Rejected,
+
+    #[error("Connection rejected by the server: {reason}")]
+    RejectedByServer { reason: String },
+
+    #[error("Authentication failed")]
+    AuthFailed,
+
+    #[error("Peer requires an encrypted connection")]
+    EncryptionRequired,
+
+    #[error("Peer does not accept encrypted connections")]
+    EncryptionMismatch,
+
+    #[error("Frame checksum mismatch")]
+    ChecksumMismatch,
+
+    #[error("Replayed frame detected")]
+    ReplayDetected,
+
+    #[error("Frame sealed with unknown key id {0}")]
+    UnknownKeyId(u8),
+
+    #[error("Peer signature verification failed: {0}")]
+    SignatureVerificationFailed(String),
+
+    #[error("Encryption key is too weak")]
+    WeakKey,
+
+    #[error("Connection handler panicked: {0}")]
+    HandlerPanicked(String),
+
+    #[error("Expected a {expected:?} frame, got {actual:?}")]
+    ContentTypeMismatch {
+        expected: crate::message::ContentType,
+        actual: crate::message::ContentType,
+    },
+
+    #[error("Peer closed the connection with code {}", .0.code())]
+    ClosedWithCode(crate::message::CloseCode),
+
+    #[error("Peer reported error {code}: {msg}")]
+    RemoteError { code: u32, msg: String },
+
+    #[error("Stream interrupted at offset {offset}: {source}")]
+    StreamInterrupted {
+        offset: u64,
+        source: Box<NamedPipeError>,
+    },
+
+    #[error("Sink error: {0}")]
+    Sink(Box<dyn std::error::Error + Send + Sync>),
+
+    #[error("JSON deserialization failed: {0}")]
+    Json(serde_json::Error),
+
+    #[error("JSON-RPC error: {0}")]
+    JsonRpc(crate::jsonrpc::ErrorObject),
+
+    #[error("Message of {size} bytes exceeds the limit of {limit} bytes")]
+    MessageTooLarge { size: u64, limit: u64 },
+
+    #[error("Frame tag has unknown flags {0:#04x}")]
+    UnknownFrameFlags(u8),
+
+    #[error("Frame headers of {size} bytes exceed the limit of {limit} bytes")]
+    HeadersTooLarge { size: usize, limit: usize },
+
+    #[error("All {} servers failed", .0.len())]
+    AllFailed(Vec<NamedPipeError>),
+
+    #[error("No active connection with id {0}")]
+    ConnectionNotFound(u64),
+
+    #[error("The server disconnected this connection")]
+    DisconnectRequested,
+
+    #[error("The peer switched to encryption; call upgrade_to_encrypted before receiving again")]
+    PeerUpgraded,
+
+    #[error("Peer lacks required security features {0:?}")]
+    FeatureMismatch(crate::framing::Features),
+}
+
+impl NamedPipeError {
+    /// Whether the error means the peer went away, as opposed to a real failure
+    pub fn is_disconnect(&self) -> bool {
+        matches!(
+            self,
+            NamedPipeError::Disconnected
+                | NamedPipeError::ConnectionClosed
+                | NamedPipeError::ClosedWithCode(_)
+        )
+    }
 }
 
 pub type Result<T> = std::result::Result<T, NamedPipeError>;
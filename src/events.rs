@@ -0,0 +1,131 @@
+//! Lifecycle events of a server's connections, for dashboards and monitoring.
+//!
+//! `NamedPipeServerStruct::event_stream` returns an `EventStream` that reports accepted and
+//! rejected clients, closed connections, byte milestones and errors, each tagged with the
+//! connection id. The stream holds at most `EVENT_STREAM_CAPACITY` events: when a slow
+//! consumer lets it fill up, the oldest event is dropped to make room, so the server never
+//! waits for the consumer.
+
+use crate::utils::ConnectionId;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::Notify;
+
+/// Number of events an `EventStream` holds before dropping the oldest
+pub const EVENT_STREAM_CAPACITY: usize = 1024;
+
+/// Default interval of `PipeEvent::BytesMilestone`, in bytes sent and received
+pub const DEFAULT_BYTE_MILESTONE: u64 = 1024 * 1024;
+
+/// Something that happened to one of a server's connections
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PipeEvent {
+    /// A client passed the connection checks and was handed to the handler
+    Accepted { id: ConnectionId },
+    /// A client failed the connection checks and was dropped
+    Rejected { id: ConnectionId, reason: String },
+    /// The bytes a connection sent and received together reached another multiple of the
+    /// milestone interval
+    BytesMilestone { id: ConnectionId, total: u64 },
+    /// A connection ended, with the bytes it sent and received in total
+    Closed {
+        id: ConnectionId,
+        bytes_sent: u64,
+        bytes_received: u64,
+    },
+    /// Accepting a client failed, or a connection's handler failed
+    Error {
+        id: Option<ConnectionId>,
+        message: String,
+    },
+}
+
+struct Shared {
+    queue: Mutex<VecDeque<PipeEvent>>,
+    notify: Notify,
+    senders: AtomicUsize,
+    dropped: AtomicU64,
+}
+
+/// Receives the events of a server; see `NamedPipeServerStruct::event_stream`
+pub struct EventStream {
+    shared: Arc<Shared>,
+}
+
+impl EventStream {
+    /// Create a stream and the sender feeding it
+    pub(crate) fn channel() -> (EventSender, Self) {
+        let shared = Arc::new(Shared {
+            queue: Mutex::new(VecDeque::new()),
+            notify: Notify::new(),
+            senders: AtomicUsize::new(1),
+            dropped: AtomicU64::new(0),
+        });
+        let sender = EventSender {
+            shared: Arc::clone(&shared),
+        };
+        (sender, Self { shared })
+    }
+
+    /// Wait for the next event. Returns `None` once the server and all its connections are
+    /// gone and every event has been received.
+    pub async fn recv(&mut self) -> Option<PipeEvent> {
+        loop {
+            if let Some(event) = self.try_recv() {
+                return Some(event);
+            }
+            if self.shared.senders.load(Ordering::Acquire) == 0 {
+                return self.try_recv();
+            }
+            self.shared.notify.notified().await;
+        }
+    }
+
+    /// Take the next event if one is waiting
+    pub fn try_recv(&mut self) -> Option<PipeEvent> {
+        self.shared.queue.lock().unwrap().pop_front()
+    }
+
+    /// Number of events dropped so far because the stream was full
+    pub fn dropped(&self) -> u64 {
+        self.shared.dropped.load(Ordering::Relaxed)
+    }
+}
+
+/// Feeds an `EventStream`; held by the server and each of its connections
+pub(crate) struct EventSender {
+    shared: Arc<Shared>,
+}
+
+impl EventSender {
+    /// Queue `event`, dropping the oldest one if the stream is full
+    pub(crate) fn send(&self, event: PipeEvent) {
+        let mut queue = self.shared.queue.lock().unwrap();
+        if queue.len() >= EVENT_STREAM_CAPACITY {
+            queue.pop_front();
+            self.shared.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+        queue.push_back(event);
+        drop(queue);
+        self.shared.notify.notify_one();
+    }
+}
+
+impl Clone for EventSender {
+    fn clone(&self) -> Self {
+        self.shared.senders.fetch_add(1, Ordering::Relaxed);
+        Self {
+            shared: Arc::clone(&self.shared),
+        }
+    }
+}
+
+impl Drop for EventSender {
+    fn drop(&mut self) {
+        if self.shared.senders.fetch_sub(1, Ordering::AcqRel) == 1 {
+            // Wake the stream so it sees that no more events will come
+            self.shared.notify.notify_one();
+        }
+    }
+}
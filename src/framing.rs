@@ -0,0 +1,2301 @@
+//! Length-prefixed message framing over any async byte stream.
+//!
+//! Every message is sent as a length prefix followed by the payload. By default the prefix
+//! is a little-endian `u32`; both its byte order and width are configurable through
+//! `FrameFormat` so the framing can match a non-Rust peer exactly. The prefix counts
+//! payload bytes only, never itself. When a cipher is set, the payload is the nonce (12 bytes
+//! for ChaCha20Poly1305, 24 for XChaCha20Poly1305) followed by the ciphertext, and the length
+//! covers both. Without a cipher, the payload is the message itself, followed by its
+//! little-endian CRC32C if the integrity check is enabled. Once keys are added with
+//! `add_key`, an encrypted payload starts with a one-byte key id naming the key that
+//! sealed it.
+//!
+//! Frames too large to hold in memory, such as beyond 4 GiB with an 8-byte prefix, can be
+//! sent from a reader with `send_frame_from` and received into a writer with
+//! `receive_frame_into`. Both move the payload in small chunks and do all length math in
+//! `u64`, so the announced length is never truncated or allocated up front. Such frames
+//! are plaintext and unchecksummed, since encryption and checksums cover whole payloads.
+//!
+//! In message mode the length prefix is dropped entirely: each payload is written as a
+//! single message of a `PIPE_TYPE_MESSAGE` pipe, and the pipe itself delimits messages.
+//!
+//! With `Framing::None` nothing delimits messages at all: sends are written as they are and
+//! receives return whatever bytes have arrived, so the pipe is a plain byte stream. This
+//! is meant for measuring raw pipe throughput and for applications with framing of their
+//! own. Mixing a raw peer with a framed one is undefined.
+
+use crate::cipher::{check_key, Cipher, CipherSuite};
+use crate::error::{NamedPipeError, Result};
+use crate::handshake::{hkdf_sha256, PROTOCOL_VERSION};
+use crate::message::{
+    bounded_reason, compress, decode_ack_sequence, decode_close, decode_error, decode_handle,
+    decode_headers, decode_json, decode_progress, decode_rejection, decode_resync, decode_utf8,
+    decompress, encode_error, encode_headers, expect_content_type, is_control_tag, split_tag,
+    CloseCode, ContentType, Headers, Message, Progress, Resync, ResyncKey, TextEncoding,
+    ACK_SEQUENCE_LEN, ACK_TAG, CLOSE_TAG, DEFAULT_COMPRESSION_THRESHOLD, ERROR_TAG,
+    FLAG_ACK_REQUESTED, FLAG_COMPRESSED, FLAG_HEADERS, HANDLE_TAG, PROGRESS_TAG, REJECT_TAG,
+    RESYNC_SALT_LEN, RESYNC_TAG, SHUTDOWN_TAG, UPGRADE_TAG,
+};
+use crate::rate_limit::RateLimiter;
+use crate::stream::STREAM_CHUNK_SIZE;
+use chacha20poly1305::aead::{rand_core::RngCore, OsRng};
+use futures::{Sink, SinkExt};
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::windows::named_pipe::PipeMode;
+use tokio::time::Duration;
+use windows::Win32::Foundation::{ERROR_BROKEN_PIPE, ERROR_NO_DATA, ERROR_PIPE_NOT_CONNECTED};
+use zeroize::Zeroizing;
+
+/// Byte order of the frame length prefix
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Endian {
+    /// Least significant byte first (the default)
+    #[default]
+    Little,
+    /// Most significant byte first, also known as network byte order
+    Big,
+}
+
+/// Width of the frame length prefix, which bounds the largest frame that can be sent
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LengthWidth {
+    /// 2-byte prefix, frames up to 64 KiB
+    U16,
+    /// 4-byte prefix, frames up to 4 GiB (the default)
+    #[default]
+    U32,
+    /// 8-byte prefix
+    U64,
+}
+
+impl LengthWidth {
+    /// Number of bytes the prefix occupies on the wire
+    pub fn size(self) -> usize {
+        match self {
+            LengthWidth::U16 => 2,
+            LengthWidth::U32 => 4,
+            LengthWidth::U64 => 8,
+        }
+    }
+
+    /// Largest payload length the prefix can express
+    pub fn max_payload(self) -> u64 {
+        match self {
+            LengthWidth::U16 => u16::MAX as u64,
+            LengthWidth::U32 => u32::MAX as u64,
+            LengthWidth::U64 => u64::MAX,
+        }
+    }
+}
+
+/// How messages are delimited in a byte-mode pipe; both ends must agree on it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Framing {
+    /// Every message is preceded by its length, laid out as the `FrameFormat` says (the
+    /// default)
+    #[default]
+    LengthPrefixed,
+    /// No delimiting: a pure byte stream. Excludes message mode, encryption, integrity
+    /// checks and the handshake, which all rely on frames.
+    None,
+}
+
+/// A set of optional features, as exchanged in the handshake. Combine them with `|` and
+/// intersect them with `&`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct Features(u32);
+
+impl Features {
+    /// Frames are encrypted
+    pub const ENCRYPTION: Self = Self(0x01);
+    /// Frame bodies are compressed where that makes them smaller
+    pub const COMPRESSION: Self = Self(0x02);
+    /// Unencrypted frames carry a CRC32C
+    pub const INTEGRITY_CHECK: Self = Self(0x04);
+    /// Encrypted frames carry a sequence number checked against replays
+    pub const REPLAY_PROTECTION: Self = Self(0x08);
+    /// Frames sent with `send_bytes_acked` are acknowledged
+    pub const ACKS: Self = Self(0x10);
+
+    /// No features
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    /// The features as a bitmask, as sent in the handshake
+    pub const fn bits(self) -> u32 {
+        self.0
+    }
+
+    /// Features from a bitmask; bits this version does not know are kept
+    pub const fn from_bits(bits: u32) -> Self {
+        Self(bits)
+    }
+
+    /// Whether every feature in `other` is in `self`
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl std::ops::BitOr for Features {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+impl std::ops::BitAnd for Features {
+    type Output = Self;
+
+    fn bitand(self, rhs: Self) -> Self {
+        Self(self.0 & rhs.0)
+    }
+}
+
+/// What a connection actually uses after the handshake and the connection checks, for
+/// debugging interop and asserting negotiation results in tests
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConnectionParams {
+    /// Version of the handshake protocol both ends spoke; `None` without the handshake
+    pub protocol_version: Option<u8>,
+    /// The suite frames are encrypted with, if any
+    pub cipher_suite: Option<CipherSuite>,
+    /// Id of the key sealing frames, once key ids are in use
+    pub active_key: Option<u8>,
+    /// The optional features in use, e.g. compression only if both ends enabled it
+    pub capabilities: Features,
+    /// How messages are delimited on the stream
+    pub framing: Framing,
+    /// Layout of the length prefix in front of every frame
+    pub frame_format: FrameFormat,
+    /// Whether frames carry a content-type tag
+    pub frame_tags: bool,
+    /// Whether the peer proved it knows the shared secret
+    pub authenticated: bool,
+    /// Whether the peer was checked to run the same executable as this process
+    pub path_verified: bool,
+}
+
+/// Layout of the length prefix in front of every frame; both ends must agree on it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct FrameFormat {
+    /// Byte order of the prefix
+    pub endian: Endian,
+    /// Width of the prefix
+    pub width: LengthWidth,
+}
+
+impl FrameFormat {
+    /// Append the prefix for a payload of `len` bytes to `buffer`
+    fn encode_length(&self, len: u64, buffer: &mut Vec<u8>) -> Result<()> {
+        if len > self.width.max_payload() {
+            return Err(NamedPipeError::Io(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "Frame of {} bytes does not fit a {}-byte length prefix",
+                    len,
+                    self.width.size()
+                ),
+            )));
+        }
+
+        let bytes = match self.endian {
+            Endian::Little => len.to_le_bytes(),
+            Endian::Big => len.to_be_bytes(),
+        };
+        match self.endian {
+            Endian::Little => buffer.extend_from_slice(&bytes[..self.width.size()]),
+            Endian::Big => buffer.extend_from_slice(&bytes[8 - self.width.size()..]),
+        }
+        Ok(())
+    }
+
+    /// Decode a prefix of exactly `self.width.size()` bytes
+    fn decode_length(&self, prefix: &[u8]) -> u64 {
+        let mut bytes = [0u8; 8];
+        match self.endian {
+            Endian::Little => {
+                bytes[..prefix.len()].copy_from_slice(prefix);
+                u64::from_le_bytes(bytes)
+            }
+            Endian::Big => {
+                bytes[8 - prefix.len()..].copy_from_slice(prefix);
+                u64::from_be_bytes(bytes)
+            }
+        }
+    }
+
+    /// Decode the frame at the start of `buf`, rejecting payloads larger than `max_len`.
+    ///
+    /// This is the pure counterpart of the stream receive path: it never panics and never
+    /// allocates, whatever the input. A buffer too short for the prefix or for the payload
+    /// it announces fails with an `UnexpectedEof` IO error.
+    pub fn decode_frame<'a>(&self, buf: &'a [u8], max_len: u64) -> Result<Frame<'a>> {
+        match self.frame_len(buf, max_len)? {
+            FrameLen::Complete(len) => Ok(Frame {
+                payload: &buf[self.width.size()..len],
+                len,
+            }),
+            FrameLen::Needs(needed) => Err(truncated(needed, buf.len())),
+        }
+    }
+
+    /// Measure the frame at the start of `buf`, or how many bytes it needs to be complete
+    fn frame_len(&self, buf: &[u8], max_len: u64) -> Result<FrameLen> {
+        let prefix_len = self.width.size();
+        let Some(prefix) = buf.get(..prefix_len) else {
+            return Ok(FrameLen::Needs(prefix_len));
+        };
+        let payload_len = self.payload_len(prefix, max_len)?;
+
+        match prefix_len.checked_add(payload_len) {
+            Some(frame_len) if frame_len <= buf.len() => Ok(FrameLen::Complete(frame_len)),
+            Some(frame_len) => Ok(FrameLen::Needs(frame_len)),
+            None => Ok(FrameLen::Needs(usize::MAX)),
+        }
+    }
+
+    /// Decode `prefix` and check the payload length it announces against `max_len`
+    fn payload_len(&self, prefix: &[u8], max_len: u64) -> Result<usize> {
+        let len = self.decode_length(prefix);
+        check_frame_size(len, max_len)?;
+        usize::try_from(len).map_err(|_| {
+            NamedPipeError::Io(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "Frame length exceeds addressable memory",
+            ))
+        })
+    }
+}
+
+/// Keys a connection can open frames with, by key id
+#[derive(Clone)]
+struct KeyRing {
+    ciphers: BTreeMap<u8, Cipher>,
+    /// The keys the ciphers were built from, where known, for deriving new keys from
+    keys: BTreeMap<u8, Zeroizing<[u8; 32]>>,
+    /// Id of the key sealing outgoing frames
+    active: u8,
+}
+
+/// Called with the bytes moved so far each time they reach another milestone
+pub(crate) type MilestoneCallback = Arc<dyn Fn(u64) + Send + Sync>;
+
+/// Reports the bytes a stream sent and received together at every multiple of `every`
+struct ByteMilestones {
+    every: u64,
+    next: u64,
+    callback: MilestoneCallback,
+}
+
+/// A stream's byte counts, published for readers on other tasks
+#[derive(Debug, Default)]
+pub(crate) struct SharedByteCounts {
+    pub(crate) sent: AtomicU64,
+    pub(crate) received: AtomicU64,
+}
+
+/// Priority of a buffered frame; higher-priority frames are flushed first
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum Priority {
+    /// Urgent frames such as control messages
+    High = 0,
+    /// The priority of `send_bytes` (the default)
+    #[default]
+    Normal = 1,
+    /// Bulk data that may wait behind everything else
+    Low = 2,
+}
+
+impl Priority {
+    /// Number of priority levels
+    const LEVELS: usize = 3;
+}
+
+/// Length of the frame at the start of a buffer
+enum FrameLen {
+    /// The buffer holds a whole frame of this many bytes
+    Complete(usize),
+    /// The frame is incomplete; this many bytes are needed in total
+    Needs(usize),
+}
+
+/// A frame decoded from a byte buffer by `decode_frame`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Frame<'a> {
+    /// The frame's payload, borrowed from the input buffer
+    pub payload: &'a [u8],
+    /// Number of input bytes the frame occupies, length prefix included
+    pub len: usize,
+}
+
+/// Decode the frame at the start of `buf` using the default frame format and no size limit.
+///
+/// See `FrameFormat::decode_frame` for other formats and limits. Intended for inspecting
+/// captured traffic and as a fuzzing entry point; it never panics or allocates.
+pub fn decode_frame(buf: &[u8]) -> Result<Frame<'_>> {
+    FrameFormat::default().decode_frame(buf, u64::MAX)
+}
+
+/// Largest payload that can be sent in message mode. Tokio reads pipes in 4 KiB chunks,
+/// so a longer message would be split across reads and lose its boundaries.
+pub const MAX_MESSAGE_MODE_PAYLOAD: usize = 4 * 1024;
+
+/// Smallest read issued when the read-ahead buffer runs dry. Reading at least this much
+/// lets one read pick up many small frames that arrive back to back.
+const READ_AHEAD_SIZE: usize = 8 * 1024;
+
+/// Most memory reserved ahead of arriving data, so a bogus length prefix cannot force a
+/// huge allocation before the payload actually shows up
+const MAX_READ_RESERVE: usize = 64 * 1024;
+
+/// Upper bound on what a frame adds to its message besides the length prefix: tag byte,
+/// key id, nonce, authentication tag, sequence number and checksum
+const MAX_FRAME_OVERHEAD: usize = 64;
+
+/// Size of the CRC32C appended to unencrypted frames when the integrity check is enabled
+const CHECKSUM_LEN: usize = 4;
+
+/// Size of the sequence number sealed into encrypted frames when replay protection is enabled
+const SEQUENCE_LEN: usize = 8;
+
+/// Number of most recent sequence numbers the replay window remembers
+const REPLAY_WINDOW: u64 = 64;
+
+/// HKDF info string binding rekeyed keys to their purpose
+const REKEY_INFO: &[u8] = b"pipeguard rekey";
+
+/// Default time `send_bytes_acked` waits for its ack
+pub const DEFAULT_ACK_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// One opened frame, before it is handed to the application
+enum Incoming {
+    /// Data, with its content type and headers if tags are enabled
+    Frame(Option<ContentType>, Vec<u8>, Headers),
+    /// An error or rejection frame, reported as the error it carries
+    Remote(NamedPipeError),
+    /// An ack for the frame with this sequence number
+    Ack(u64),
+    /// A resync request from the peer, already answered
+    ResyncRequested,
+    /// The peer's answer to a resync request
+    ResyncAnswered,
+    /// A progress update on the operation whose result comes next
+    Progress(Progress),
+    /// The peer's upgrade frame, after which its frames are encrypted
+    PeerUpgraded,
+    /// The value of a handle the peer duplicated into this process
+    Handle(u64),
+}
+
+/// Sliding window over the sequence numbers received so far.
+///
+/// Numbers above the highest seen are always accepted; numbers within `REPLAY_WINDOW` below
+/// it are accepted once; anything older is rejected.
+#[derive(Default)]
+struct ReplayWindow {
+    highest: Option<u64>,
+    /// Bit `i` is set if `highest - i` has been received
+    seen: u64,
+}
+
+impl ReplayWindow {
+    fn check(&mut self, sequence: u64) -> Result<()> {
+        let Some(highest) = self.highest else {
+            self.highest = Some(sequence);
+            self.seen = 1;
+            return Ok(());
+        };
+
+        if sequence > highest {
+            let shift = sequence - highest;
+            self.seen = if shift >= REPLAY_WINDOW {
+                0
+            } else {
+                self.seen << shift
+            };
+            self.seen |= 1;
+            self.highest = Some(sequence);
+            return Ok(());
+        }
+
+        let offset = highest - sequence;
+        if offset >= REPLAY_WINDOW || self.seen & (1 << offset) != 0 {
+            return Err(NamedPipeError::ReplayDetected);
+        }
+        self.seen |= 1 << offset;
+        Ok(())
+    }
+}
+
+/// A framed message stream with optional encryption.
+///
+/// Both `NamedPipeClientStruct` and `NamedPipeConnection` wrap a `Framed` around their pipe
+/// handle; it can equally be used over any other `AsyncRead + AsyncWrite` transport.
+pub struct Framed<S> {
+    stream: S,
+    cipher: Option<Cipher>,
+    key_ring: Option<KeyRing>,
+    cipher_key: Option<Zeroizing<[u8; 32]>>,
+    auto_flush: bool,
+    flush_before_receive: bool,
+    write_queues: [Vec<u8>; Priority::LEVELS],
+    read_buffer: Vec<u8>,
+    pending_message: Option<Vec<u8>>,
+    rate_limiter: Option<Arc<tokio::sync::Mutex<RateLimiter>>>,
+    integrity_check: bool,
+    replay_protection: bool,
+    send_sequence: u64,
+    replay_window: ReplayWindow,
+    pipe_mode: PipeMode,
+    frame_format: FrameFormat,
+    framing: Framing,
+    max_message_size: u64,
+    stream_chunk_size: usize,
+    tagged: bool,
+    compression: bool,
+    compression_threshold: usize,
+    acks: bool,
+    ack_timeout: Duration,
+    ack_sequence: u64,
+    /// Sequence numbers of received frames whose ack is not written yet
+    pending_acks: VecDeque<u64>,
+    unread_frames: VecDeque<Incoming>,
+    readable: bool,
+    writable: bool,
+    write_shutdown: bool,
+    peer_write_shutdown: bool,
+    peer_upgraded: bool,
+    default_headers: Headers,
+    received_headers: Headers,
+    milestones: Option<ByteMilestones>,
+    shared_counts: Option<Arc<SharedByteCounts>>,
+    bytes_sent: u64,
+    bytes_received: u64,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Framed<S> {
+    /// Wrap a stream without encryption
+    pub fn new(stream: S) -> Self {
+        Self::with_cipher(stream, None)
+    }
+
+    /// Wrap a stream, encrypting every frame if a cipher is given
+    pub fn with_cipher(stream: S, cipher: Option<Cipher>) -> Self {
+        Self {
+            stream,
+            cipher,
+            key_ring: None,
+            cipher_key: None,
+            auto_flush: true,
+            flush_before_receive: true,
+            write_queues: Default::default(),
+            read_buffer: Vec::new(),
+            pending_message: None,
+            rate_limiter: None,
+            integrity_check: false,
+            replay_protection: false,
+            send_sequence: 0,
+            replay_window: ReplayWindow::default(),
+            pipe_mode: PipeMode::Byte,
+            frame_format: FrameFormat::default(),
+            framing: Framing::default(),
+            max_message_size: u64::MAX,
+            stream_chunk_size: STREAM_CHUNK_SIZE,
+            tagged: false,
+            compression: false,
+            compression_threshold: DEFAULT_COMPRESSION_THRESHOLD,
+            acks: false,
+            ack_timeout: DEFAULT_ACK_TIMEOUT,
+            ack_sequence: 0,
+            pending_acks: VecDeque::new(),
+            unread_frames: VecDeque::new(),
+            readable: true,
+            writable: true,
+            write_shutdown: false,
+            peer_write_shutdown: false,
+            peer_upgraded: false,
+            default_headers: Headers::new(),
+            received_headers: Headers::new(),
+            milestones: None,
+            shared_counts: None,
+            bytes_sent: 0,
+            bytes_received: 0,
+        }
+    }
+
+    /// Split into a framed reader and a framed writer over the halves `split` makes of the
+    /// stream, both keeping this stream's settings except byte milestones and shared byte
+    /// counts, and sharing one rate limit. Read-ahead data, unread frames and the replay
+    /// window go to the reader; buffered frames and the send sequences to the writer.
+    pub(crate) fn into_halves<R, W>(
+        self,
+        split: impl FnOnce(S) -> (R, W),
+    ) -> (Framed<R>, Framed<W>) {
+        let (read_stream, write_stream) = split(self.stream);
+        let writer = Framed {
+            stream: write_stream,
+            cipher: self.cipher.clone(),
+            key_ring: self.key_ring.clone(),
+            cipher_key: self.cipher_key.clone(),
+            auto_flush: self.auto_flush,
+            flush_before_receive: self.flush_before_receive,
+            write_queues: self.write_queues,
+            read_buffer: Vec::new(),
+            pending_message: None,
+            rate_limiter: self.rate_limiter.clone(),
+            integrity_check: self.integrity_check,
+            replay_protection: self.replay_protection,
+            send_sequence: self.send_sequence,
+            replay_window: ReplayWindow::default(),
+            pipe_mode: self.pipe_mode,
+            frame_format: self.frame_format,
+            framing: self.framing,
+            max_message_size: self.max_message_size,
+            stream_chunk_size: self.stream_chunk_size,
+            tagged: self.tagged,
+            compression: self.compression,
+            compression_threshold: self.compression_threshold,
+            acks: self.acks,
+            ack_timeout: self.ack_timeout,
+            ack_sequence: self.ack_sequence,
+            pending_acks: VecDeque::new(),
+            unread_frames: VecDeque::new(),
+            readable: self.readable,
+            writable: self.writable,
+            write_shutdown: self.write_shutdown,
+            peer_write_shutdown: false,
+            peer_upgraded: false,
+            default_headers: self.default_headers.clone(),
+            received_headers: Headers::new(),
+            milestones: None,
+            shared_counts: None,
+            bytes_sent: self.bytes_sent,
+            bytes_received: 0,
+        };
+        let reader = Framed {
+            stream: read_stream,
+            cipher: self.cipher,
+            key_ring: self.key_ring,
+            cipher_key: self.cipher_key,
+            auto_flush: self.auto_flush,
+            flush_before_receive: false,
+            write_queues: Default::default(),
+            read_buffer: self.read_buffer,
+            pending_message: self.pending_message,
+            rate_limiter: self.rate_limiter,
+            integrity_check: self.integrity_check,
+            replay_protection: self.replay_protection,
+            send_sequence: 0,
+            replay_window: self.replay_window,
+            pipe_mode: self.pipe_mode,
+            frame_format: self.frame_format,
+            framing: self.framing,
+            max_message_size: self.max_message_size,
+            stream_chunk_size: self.stream_chunk_size,
+            tagged: self.tagged,
+            compression: self.compression,
+            compression_threshold: self.compression_threshold,
+            acks: self.acks,
+            ack_timeout: self.ack_timeout,
+            ack_sequence: 0,
+            pending_acks: VecDeque::new(),
+            unread_frames: self.unread_frames,
+            readable: self.readable,
+            writable: self.writable,
+            write_shutdown: false,
+            peer_write_shutdown: self.peer_write_shutdown,
+            peer_upgraded: self.peer_upgraded,
+            default_headers: self.default_headers,
+            received_headers: self.received_headers,
+            milestones: None,
+            shared_counts: None,
+            bytes_sent: 0,
+            bytes_received: self.bytes_received,
+        };
+        (reader, writer)
+    }
+
+    /// Get a reference to the underlying stream
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+
+    /// Get a mutable reference to the underlying stream
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.stream
+    }
+
+    /// Consume the framed stream, returning the underlying stream.
+    /// Frames still buffered while auto-flush is disabled are discarded, as is any data
+    /// already read ahead from the stream but not yet received.
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+
+    /// Replace the cipher used for subsequent frames; `None` disables encryption.
+    /// Keys added with `add_key` are dropped, and frames no longer carry key ids.
+    pub fn set_cipher(&mut self, cipher: Option<Cipher>) {
+        self.cipher = cipher;
+        self.key_ring = None;
+        self.cipher_key = None;
+    }
+
+    /// Record the key the current cipher was built from, which rekeying derives new keys
+    /// from; ciphers given without their key cannot be rekeyed
+    pub(crate) fn set_cipher_key(&mut self, key: Option<&[u8; 32]>) {
+        self.cipher_key = key.map(|key| Zeroizing::new(*key));
+    }
+
+    /// Register another key for the connection's cipher suite under `id`, so frames
+    /// sealed with it can be opened, e.g. during a key rotation.
+    ///
+    /// The first call switches the connection to key ids: from then on every encrypted
+    /// frame starts with the id of the key that sealed it, and the receiver opens it with
+    /// the key registered under that id, whichever is active. The key the connection was
+    /// created with becomes id 0 and stays active until `set_active_key`. Both ends must
+    /// switch before the first frame they exchange, and fail with
+    /// `NamedPipeError::UnknownKeyId` on frames sealed with a key they do not have.
+    /// Unencrypted connections fail with `NamedPipeError::InvalidConfig`.
+    pub fn add_key(&mut self, id: u8, key: &[u8; 32]) -> Result<()> {
+        check_key(key)?;
+        let Some(cipher) = self.cipher.as_ref() else {
+            return Err(NamedPipeError::InvalidConfig(
+                "key ids need an encrypted connection".to_string(),
+            ));
+        };
+        let suite = cipher.suite();
+        let ring = self.key_ring.get_or_insert_with(|| KeyRing {
+            ciphers: BTreeMap::from([(0, cipher.clone())]),
+            keys: self.cipher_key.iter().map(|key| (0, key.clone())).collect(),
+            active: 0,
+        });
+        ring.ciphers.insert(id, Cipher::new(suite, key));
+        ring.keys.insert(id, Zeroizing::new(*key));
+        if id == ring.active {
+            self.cipher = Some(Cipher::new(suite, key));
+            self.cipher_key = Some(Zeroizing::new(*key));
+        }
+        Ok(())
+    }
+
+    /// Seal subsequent frames with the key registered under `id`
+    pub fn set_active_key(&mut self, id: u8) -> Result<()> {
+        let ring = self
+            .key_ring
+            .as_mut()
+            .ok_or(NamedPipeError::UnknownKeyId(id))?;
+        let cipher = ring
+            .ciphers
+            .get(&id)
+            .ok_or(NamedPipeError::UnknownKeyId(id))?;
+        self.cipher = Some(cipher.clone());
+        self.cipher_key = ring.keys.get(&id).cloned();
+        ring.active = id;
+        Ok(())
+    }
+
+    /// Register every key of `keys` and activate `active`, switching to key ids unless
+    /// `keys` is empty
+    pub(crate) fn install_keys(&mut self, keys: &BTreeMap<u8, [u8; 32]>, active: u8) -> Result<()> {
+        if keys.is_empty() {
+            return Ok(());
+        }
+        for (&id, key) in keys {
+            self.add_key(id, key)?;
+        }
+        self.set_active_key(active)
+    }
+
+    /// Forget the key registered under `id`, e.g. once a rotation is complete. The active
+    /// key cannot be removed.
+    pub fn remove_key(&mut self, id: u8) -> Result<()> {
+        match self.key_ring.as_mut() {
+            Some(ring) if ring.active == id => Err(NamedPipeError::InvalidConfig(
+                "the active key cannot be removed".to_string(),
+            )),
+            Some(ring) => {
+                ring.keys.remove(&id);
+                ring.ciphers
+                    .remove(&id)
+                    .map(|_| ())
+                    .ok_or(NamedPipeError::UnknownKeyId(id))
+            }
+            None => Err(NamedPipeError::UnknownKeyId(id)),
+        }
+    }
+
+    /// Id of the key sealing frames, once key ids are in use
+    pub fn active_key(&self) -> Option<u8> {
+        self.key_ring.as_ref().map(|ring| ring.active)
+    }
+
+    /// Whether frames are currently encrypted
+    pub fn is_encrypted(&self) -> bool {
+        self.cipher.is_some()
+    }
+
+    /// The suite frames are encrypted with, if any
+    pub fn cipher_suite(&self) -> Option<CipherSuite> {
+        self.cipher.as_ref().map(Cipher::suite)
+    }
+
+    /// Enable or disable flushing after every send (enabled by default)
+    pub fn set_auto_flush(&mut self, enabled: bool) {
+        self.auto_flush = enabled;
+    }
+
+    /// Start every frame with a one-byte content-type tag (disabled by default).
+    ///
+    /// The handshake enables tags on both ends once it succeeds; enable them manually only
+    /// when both ends of a stream without handshake do. With tags, `receive_any` reports
+    /// what each frame holds and `receive_json`/`receive_string` reject frames of another
+    /// type with `NamedPipeError::ContentTypeMismatch`.
+    pub fn set_frame_tags(&mut self, enabled: bool) {
+        self.tagged = enabled;
+    }
+
+    /// Deflate-compress frame bodies where that makes them smaller (disabled by default).
+    ///
+    /// Compression is flagged in the frame tag, so it needs frame tags and has no effect
+    /// without them; the peer decompresses flagged frames whatever its own setting. Bodies
+    /// are compressed before encryption, never after. Do not compress secrets together
+    /// with data an attacker controls: the compressed length reveals how well the two
+    /// match, which is how CRIME-style attacks recover secrets from encrypted traffic.
+    pub fn set_compression(&mut self, enabled: bool) {
+        self.compression = enabled;
+    }
+
+    /// Only compress bodies larger than `bytes` (`DEFAULT_COMPRESSION_THRESHOLD` by
+    /// default), so small frames skip the effort and the deflate overhead. Each frame's tag
+    /// says whether it was compressed, so the peer needs no matching setting.
+    pub fn set_compression_threshold(&mut self, bytes: usize) {
+        self.compression_threshold = bytes;
+    }
+
+    /// Get the size up to which bodies are sent uncompressed
+    pub fn compression_threshold(&self) -> usize {
+        self.compression_threshold
+    }
+
+    /// Allow `send_bytes_acked` on this stream (disabled by default). The handshake keeps
+    /// it enabled only if the peer enables it too. Frames that request an ack are answered
+    /// whatever this setting.
+    pub fn set_acks(&mut self, enabled: bool) {
+        self.acks = enabled;
+    }
+
+    /// Whether `send_bytes_acked` is allowed, after the handshake's negotiation
+    pub(crate) fn acks_enabled(&self) -> bool {
+        self.acks
+    }
+
+    /// Set how long `send_bytes_acked` waits for its ack (30 seconds by default)
+    pub fn set_ack_timeout(&mut self, timeout: Duration) {
+        self.ack_timeout = timeout;
+    }
+
+    /// Record which directions the stream was opened for; sends and receives in the other
+    /// direction fail with `NamedPipeError::AccessMode` instead of an OS error
+    pub(crate) fn set_access(&mut self, read: bool, write: bool) {
+        self.readable = read;
+        self.writable = write;
+    }
+
+    /// Fail with `AccessMode` unless the stream was opened for reading, or writing with
+    /// `write`
+    fn check_access(&self, write: bool) -> Result<()> {
+        match (write, self.readable, self.writable) {
+            (false, false, _) => Err(NamedPipeError::AccessMode("reading")),
+            (true, _, false) => Err(NamedPipeError::AccessMode("writing")),
+            _ => Ok(()),
+        }
+    }
+
+    /// Whether frames carry a content-type tag
+    pub fn has_frame_tags(&self) -> bool {
+        self.tagged
+    }
+
+    /// Flush buffered frames before every receive (enabled by default).
+    ///
+    /// With auto-flush disabled, a request still sitting in the buffer when its caller
+    /// starts waiting for the reply would leave both ends waiting to read. This setting
+    /// guarantees that every frame sent before a receive has reached the stream by the time
+    /// the receive starts waiting. It costs nothing when the buffer is empty. Disable it
+    /// only to keep batching sends across receives.
+    pub fn set_flush_before_receive(&mut self, enabled: bool) {
+        self.flush_before_receive = enabled;
+    }
+
+    /// Limit combined send and receive throughput to `bytes_per_sec`.
+    /// Transfers over the limit are delayed, never dropped. Passing 0 disables the limit.
+    pub fn set_rate_limit(&mut self, bytes_per_sec: u64) {
+        self.rate_limiter = (bytes_per_sec > 0)
+            .then(|| Arc::new(tokio::sync::Mutex::new(RateLimiter::new(bytes_per_sec))));
+    }
+
+    /// Append a CRC32C to every unencrypted frame and verify it on receive (disabled by
+    /// default). Has no effect while a cipher is set, since AEAD already authenticates frames.
+    /// Both ends must use the same setting.
+    pub fn set_integrity_check(&mut self, enabled: bool) {
+        self.integrity_check = enabled;
+    }
+
+    /// Seal a sequence number into every encrypted frame and reject frames whose number was
+    /// already received or falls behind the replay window, with
+    /// `NamedPipeError::ReplayDetected` (disabled by default).
+    ///
+    /// This stops an attacker with access to the pipe from replaying captured frames within
+    /// a connection. It has no effect without a cipher, since unauthenticated frames can be
+    /// forged outright. Both ends must use the same setting; toggling it restarts the
+    /// sequence.
+    pub fn enable_replay_protection(&mut self, enabled: bool) {
+        self.replay_protection = enabled;
+        self.send_sequence = 0;
+        self.replay_window = ReplayWindow::default();
+    }
+
+    /// Select how frames are delimited (`PipeMode::Byte` by default).
+    ///
+    /// In byte mode every frame carries a length prefix. In message mode the prefix is
+    /// omitted and each payload is written as one message, which requires the stream to be
+    /// a pipe opened in message mode on both ends. Message mode limits payloads to
+    /// `MAX_MESSAGE_MODE_PAYLOAD` bytes and always writes immediately, ignoring auto-flush.
+    pub fn set_pipe_mode(&mut self, mode: PipeMode) {
+        self.pipe_mode = mode;
+    }
+
+    /// Set the byte order of the length prefix (little-endian by default)
+    pub fn set_frame_endianness(&mut self, endian: Endian) {
+        self.frame_format.endian = endian;
+    }
+
+    /// Set the width of the length prefix (`u32` by default)
+    pub fn set_frame_length_width(&mut self, width: LengthWidth) {
+        self.frame_format.width = width;
+    }
+
+    /// Set the whole length prefix layout at once
+    pub fn set_frame_format(&mut self, format: FrameFormat) {
+        self.frame_format = format;
+    }
+
+    /// Get the current length prefix layout
+    pub fn frame_format(&self) -> FrameFormat {
+        self.frame_format
+    }
+
+    /// Choose how messages are delimited (length-prefixed by default). With
+    /// `Framing::None`, `send_bytes` writes the bytes as they are and `receive_bytes`
+    /// returns whatever has arrived, which may be part of a send or several sends at once.
+    /// Sends and receives fail with `NamedPipeError::InvalidConfig` while encryption,
+    /// integrity checks, frame tags or message mode are in use.
+    pub fn set_framing(&mut self, framing: Framing) {
+        self.framing = framing;
+    }
+
+    /// Get how messages are delimited
+    pub fn framing(&self) -> Framing {
+        self.framing
+    }
+
+    /// Reject frames whose payload is larger than `max_size` bytes (unlimited by default).
+    ///
+    /// The limit covers the payload as sent on the wire, including the nonce and tag of
+    /// encrypted frames or the checksum of integrity-checked ones. Oversized incoming frames
+    /// fail with `NamedPipeError::MessageTooLarge` before any buffer is allocated; the rest
+    /// of such a frame is left unread, so the connection should be discarded.
+    pub fn set_max_message_size(&mut self, max_size: u64) {
+        self.max_message_size = max_size;
+    }
+
+    /// Get the largest frame payload this stream sends or accepts
+    pub fn max_message_size(&self) -> u64 {
+        self.max_message_size
+    }
+
+    /// Attach `headers` to every data frame sent from now on, e.g. to propagate a trace id
+    /// without touching the payload (none by default).
+    ///
+    /// Headers travel inside the sealed frame, so they are encrypted and checked like the
+    /// payload; the receiver reads them with `received_headers`. Names are at most 255
+    /// bytes, and a frame's encoded headers at most `MAX_HEADERS_LEN` bytes; larger ones
+    /// fail the send with `NamedPipeError::HeadersTooLarge`. Sending headers requires frame
+    /// tags; without them every data send fails with `InvalidInput`.
+    pub fn set_default_headers(&mut self, headers: Headers) {
+        self.default_headers = headers;
+    }
+
+    /// Get the headers attached to every data frame sent
+    pub fn default_headers(&self) -> &Headers {
+        &self.default_headers
+    }
+
+    /// Get the headers of the last frame returned by a `receive_*` call; empty if it had none
+    pub fn received_headers(&self) -> &Headers {
+        &self.received_headers
+    }
+
+    /// Set the most data `send_stream` puts in one chunk, and how much `receive_stream`
+    /// collects before writing it out (`STREAM_CHUNK_SIZE` by default, at least 1).
+    ///
+    /// Larger chunks mean fewer frames and writes for big transfers, at the cost of that
+    /// much memory per transfer. The peers need not agree: every chunk is a frame of its own.
+    pub fn set_stream_chunk_size(&mut self, bytes: usize) {
+        self.stream_chunk_size = bytes.max(1);
+    }
+
+    /// Get the chunk size used by `send_stream` and `receive_stream`
+    pub fn stream_chunk_size(&self) -> usize {
+        self.stream_chunk_size
+    }
+
+    /// The optional features this stream currently uses. After a handshake these are the
+    /// ones both peers support.
+    pub fn capabilities(&self) -> Features {
+        let features = [
+            (self.is_encrypted(), Features::ENCRYPTION),
+            (self.compression, Features::COMPRESSION),
+            (self.integrity_check, Features::INTEGRITY_CHECK),
+            (self.replay_protection, Features::REPLAY_PROTECTION),
+            (self.acks, Features::ACKS),
+        ];
+        features
+            .into_iter()
+            .filter(|&(enabled, _)| enabled)
+            .fold(Features::empty(), |all, (_, feature)| all | feature)
+    }
+
+    /// The parameters this stream uses, with the checks made by its owner left unset
+    pub(crate) fn connection_params(&self, handshake: bool) -> ConnectionParams {
+        ConnectionParams {
+            protocol_version: handshake.then_some(PROTOCOL_VERSION),
+            cipher_suite: self.cipher_suite(),
+            active_key: self.active_key(),
+            capabilities: self.capabilities(),
+            framing: self.framing,
+            frame_format: self.frame_format,
+            frame_tags: self.tagged,
+            authenticated: false,
+            path_verified: false,
+        }
+    }
+
+    /// The security features in effect here that `negotiated` lacks: the integrity check of
+    /// a plaintext stream and the replay protection of an encrypted one
+    pub(crate) fn missing_security_features(&self, negotiated: Features) -> Features {
+        let encrypted = self.is_encrypted();
+        let required = [
+            (
+                self.integrity_check && !encrypted,
+                Features::INTEGRITY_CHECK,
+            ),
+            (
+                self.replay_protection && encrypted,
+                Features::REPLAY_PROTECTION,
+            ),
+        ];
+        required
+            .into_iter()
+            .filter(|&(enabled, feature)| enabled && !negotiated.contains(feature))
+            .fold(Features::empty(), |all, (_, feature)| all | feature)
+    }
+
+    /// Turn off the convenience features missing from `negotiated`. A security feature in
+    /// effect here is never turned off, since the hello negotiating it is not authenticated:
+    /// if it is missing this fails with `NamedPipeError::FeatureMismatch` instead. Encryption
+    /// is left alone; the handshake settles it separately.
+    pub(crate) fn apply_capabilities(&mut self, negotiated: Features) -> Result<()> {
+        let missing = self.missing_security_features(negotiated);
+        if missing != Features::empty() {
+            return Err(NamedPipeError::FeatureMismatch(missing));
+        }
+        self.compression &= negotiated.contains(Features::COMPRESSION);
+        self.integrity_check &= negotiated.contains(Features::INTEGRITY_CHECK);
+        self.acks &= negotiated.contains(Features::ACKS);
+        if self.replay_protection && !negotiated.contains(Features::REPLAY_PROTECTION) {
+            self.enable_replay_protection(false);
+        }
+        Ok(())
+    }
+
+    /// Number of bytes of buffered frames not yet written to the stream. Frames are only
+    /// buffered with auto-flush disabled, or left behind by a send or flush that failed or
+    /// was cancelled.
+    pub fn buffered_len(&self) -> usize {
+        self.write_queues.iter().map(Vec::len).sum()
+    }
+
+    /// Number of bytes read ahead from the stream but not yet returned as frames, including
+    /// frames that arrived while `send_bytes_acked` waited for its ack
+    pub fn read_ahead_len(&self) -> usize {
+        let unread: usize = self
+            .unread_frames
+            .iter()
+            .map(|incoming| match incoming {
+                Incoming::Frame(_, data, _) => data.len(),
+                Incoming::Remote(_)
+                | Incoming::Ack(_)
+                | Incoming::ResyncRequested
+                | Incoming::ResyncAnswered
+                | Incoming::Progress(_)
+                | Incoming::PeerUpgraded
+                | Incoming::Handle(_) => 0,
+            })
+            .sum();
+        self.read_buffer.len() + self.pending_message.as_ref().map_or(0, Vec::len) + unread
+    }
+
+    /// Total bytes written to the stream so far, including length prefixes, nonces and
+    /// checksums. Frames still buffered are not counted until flushed.
+    pub fn bytes_sent(&self) -> u64 {
+        self.bytes_sent
+    }
+
+    /// Total bytes read from the stream so far, including any read ahead but not yet
+    /// received as frames
+    pub fn bytes_received(&self) -> u64 {
+        self.bytes_received
+    }
+
+    /// Write any buffered frames to the stream, highest priority first, and flush it.
+    ///
+    /// Cancel-safe: bytes leave the buffer only once written, so a cancelled flush is
+    /// completed by the next one without duplicating or losing data. Short writes are
+    /// retried with the rest of the buffer until it is empty.
+    pub async fn flush(&mut self) -> Result<()> {
+        for queue in self.write_queues.iter_mut() {
+            while !queue.is_empty() {
+                let written = self.stream.write(queue).await.map_err(stream_error)?;
+                if written == 0 {
+                    return Err(NamedPipeError::Io(std::io::ErrorKind::WriteZero.into()));
+                }
+                self.bytes_sent += written as u64;
+                queue.drain(..written);
+            }
+        }
+        self.report_byte_counts();
+
+        self.stream.flush().await.map_err(stream_error)?;
+        Ok(())
+    }
+
+    /// Send raw bytes as a single frame. Empty messages are allowed and arrive as an empty
+    /// vector, except for untagged plaintext frames in message mode, which have no payload
+    /// byte to carry them.
+    pub async fn send_bytes(&mut self, data: &[u8]) -> Result<()> {
+        self.send_bytes_priority(data, Priority::Normal).await
+    }
+
+    /// Send raw bytes as a single frame carrying `headers` on top of the default headers,
+    /// replacing default headers of the same name
+    pub async fn send_bytes_with_headers(&mut self, data: &[u8], headers: &Headers) -> Result<()> {
+        self.send_tagged_with_headers(
+            ContentType::Raw as u8,
+            data,
+            Priority::Normal,
+            Some(headers),
+        )
+        .await
+    }
+
+    /// Send raw bytes as a single frame, queued ahead of buffered frames of lower priority.
+    ///
+    /// Priorities only matter while frames are buffered, i.e. with auto-flush disabled:
+    /// `flush` then writes all `High` frames first, then `Normal`, then `Low`, so urgent
+    /// control messages overtake bulk data queued before them. Order is preserved only
+    /// within a priority level. With auto-flush enabled, or in message mode, every frame is
+    /// written as soon as it is sent and priorities have no effect.
+    pub async fn send_bytes_priority(&mut self, data: &[u8], priority: Priority) -> Result<()> {
+        self.send_tagged(ContentType::Raw as u8, data, priority)
+            .await
+    }
+
+    /// Send raw bytes as a single frame and wait until the peer confirms receiving it, for
+    /// up to the ack timeout.
+    ///
+    /// The frame carries a sequence number, and the peer's receive answers it with an ack
+    /// frame before returning the data. An ack confirms that the peer took the frame off the
+    /// pipe, not that it acted on it. Without an ack in time this fails with
+    /// `NamedPipeError::Timeout`; an ack arriving later is discarded. Frames the peer sends
+    /// meanwhile are kept for the following receives.
+    ///
+    /// Requires frame tags and acks enabled with `set_acks`, which the handshake only keeps
+    /// if the peer enables them too; fails with `InvalidInput` otherwise. A stream with
+    /// acks enabled cannot be split.
+    pub async fn send_bytes_acked(&mut self, data: &[u8]) -> Result<()> {
+        if !self.tagged || !self.acks {
+            return Err(NamedPipeError::Io(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "Acknowledged sends require frame tags and acks enabled on both ends",
+            )));
+        }
+        self.ack_sequence += 1;
+        let sequence = self.ack_sequence;
+        let mut body = Vec::with_capacity(ACK_SEQUENCE_LEN + data.len());
+        body.extend_from_slice(&sequence.to_le_bytes());
+        body.extend_from_slice(data);
+        let tag = ContentType::Raw as u8 | FLAG_ACK_REQUESTED;
+        self.send_tagged(tag, &body, Priority::Normal).await?;
+        self.flush().await?;
+
+        let timeout = self.ack_timeout;
+        tokio::time::timeout(timeout, self.wait_for_ack(sequence))
+            .await
+            .map_err(|_| NamedPipeError::Timeout)?
+    }
+
+    /// Receive until the ack for `sequence` arrives, keeping other frames for later
+    async fn wait_for_ack(&mut self, sequence: u64) -> Result<()> {
+        loop {
+            match self.receive_incoming().await? {
+                Incoming::Ack(acked) if acked == sequence => return Ok(()),
+                // The ack of an earlier send that timed out
+                Incoming::Ack(_) => {}
+                incoming => self.unread_frames.push_back(incoming),
+            }
+        }
+    }
+
+    /// Restart the replay-protection sequence numbers on both ends, coordinated with the
+    /// peer through a resync frame, and with `rekey`, switch both ends to a new key derived
+    /// from the active one.
+    ///
+    /// This recovers from a crypto desync, such as a run of `ReplayDetected` errors, without
+    /// reconnecting. The peer takes part in its next receive: it resets its replay window,
+    /// answers, and from then on both ends number their frames from zero again. Frames the
+    /// peer sends before its answer are kept for the following receives. Without an answer
+    /// within the ack timeout this fails with `NamedPipeError::Timeout`.
+    ///
+    /// Rekeying needs key ids (see `add_key`) and fails with `InvalidConfig` without them,
+    /// or if the active cipher was given without its key. The new key is derived from the
+    /// active one with HKDF-SHA256 over a random salt sent in the resync frame. It is
+    /// registered under the id after the active one, replacing any key registered there,
+    /// and activated on both ends. The old key stays registered until `remove_key`.
+    ///
+    /// Requires a cipher and frame tags. Only one end should reset at a time.
+    pub async fn reset_crypto_state(&mut self, rekey: bool) -> Result<()> {
+        if self.cipher.is_none() {
+            return Err(NamedPipeError::InvalidConfig(
+                "resetting the crypto state needs an encrypted connection".to_string(),
+            ));
+        };
+        if !self.tagged {
+            return Err(NamedPipeError::Io(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "Resync frames require frame tags",
+            )));
+        }
+        let new_key = if rekey {
+            let ring = self.key_ring.as_ref().ok_or_else(|| {
+                NamedPipeError::InvalidConfig("rekeying needs key ids".to_string())
+            })?;
+            let mut rekey = ResyncKey {
+                base: ring.active,
+                id: ring.active.wrapping_add(1),
+                salt: [0; RESYNC_SALT_LEN],
+            };
+            OsRng.fill_bytes(&mut rekey.salt);
+            let base = ring.keys.get(&ring.active).ok_or_else(rekey_needs_key)?;
+            Some((rekey, hkdf_sha256(&**base, &rekey.salt, REKEY_INFO)))
+        } else {
+            None
+        };
+
+        let request = Resync::Request(new_key.map(|(rekey, _)| rekey));
+        self.send_tagged(RESYNC_TAG, &request.encode(), Priority::Normal)
+            .await?;
+        self.flush().await?;
+        self.send_sequence = 0;
+        if let Some((rekey, key)) = new_key {
+            self.add_key(rekey.id, &key)?;
+            self.set_active_key(rekey.id)?;
+        }
+
+        let timeout = self.ack_timeout;
+        tokio::time::timeout(timeout, self.wait_for_resync())
+            .await
+            .map_err(|_| NamedPipeError::Timeout)?
+    }
+
+    /// Receive until the peer answers a resync request, keeping other frames for later, then
+    /// reset the replay window for the frames it numbers from zero
+    async fn wait_for_resync(&mut self) -> Result<()> {
+        loop {
+            match self.receive_incoming().await? {
+                Incoming::ResyncAnswered => {
+                    self.replay_window = ReplayWindow::default();
+                    return Ok(());
+                }
+                incoming => self.unread_frames.push_back(incoming),
+            }
+        }
+    }
+
+    /// Switch a plaintext connection to encryption with `key` under `suite`, STARTTLS-style,
+    /// coordinated with the peer through an upgrade frame.
+    ///
+    /// Both ends call this at the same point of their protocol, e.g. after exchanging
+    /// capabilities in plaintext. Each sends an upgrade frame and waits for the peer's:
+    /// frames sent before it stay plaintext and are kept for the following receives, and
+    /// everything after is sealed with `key`. Both ends must pass the same suite. An end that
+    /// receives the peer's upgrade frame in a normal receive fails it with
+    /// `NamedPipeError::PeerUpgraded` and must call this before receiving again. Without the
+    /// peer's upgrade frame within the ack timeout this fails with `NamedPipeError::Timeout`.
+    /// Requires frame tags, and fails with `InvalidConfig` on an encrypted connection.
+    pub async fn upgrade_to_encrypted(&mut self, key: &[u8; 32], suite: CipherSuite) -> Result<()> {
+        if self.cipher.is_some() {
+            return Err(NamedPipeError::InvalidConfig(
+                "the connection is already encrypted".to_string(),
+            ));
+        }
+        if !self.tagged {
+            return Err(NamedPipeError::Io(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "Upgrade frames require frame tags",
+            )));
+        }
+        check_key(key)?;
+
+        self.send_tagged(UPGRADE_TAG, &[], Priority::Normal).await?;
+        self.flush().await?;
+        if !std::mem::take(&mut self.peer_upgraded) {
+            let timeout = self.ack_timeout;
+            tokio::time::timeout(timeout, self.wait_for_upgrade())
+                .await
+                .map_err(|_| NamedPipeError::Timeout)??;
+        }
+        self.cipher = Some(Cipher::new(suite, key));
+        self.cipher_key = Some(Zeroizing::new(*key));
+        self.send_sequence = 0;
+        self.replay_window = ReplayWindow::default();
+        Ok(())
+    }
+
+    /// Receive until the peer's upgrade frame arrives, keeping the plaintext frames before
+    /// it for later
+    async fn wait_for_upgrade(&mut self) -> Result<()> {
+        loop {
+            match self.receive_incoming().await? {
+                Incoming::PeerUpgraded => return Ok(()),
+                incoming => self.unread_frames.push_back(incoming),
+            }
+        }
+    }
+
+    /// Send bytes already encoded as `content_type`, e.g. a bincode-serialized value.
+    /// The type is announced in the frame tag, if tags are enabled.
+    pub async fn send_with_content_type(
+        &mut self,
+        data: &[u8],
+        content_type: ContentType,
+    ) -> Result<()> {
+        self.send_tagged(content_type as u8, data, Priority::Normal)
+            .await
+    }
+
+    /// Send an application-level error; the peer's next `receive_*` call fails with
+    /// `NamedPipeError::RemoteError { code, msg }` instead of returning data.
+    ///
+    /// Error frames are told apart from data by their tag, so this requires frame tags
+    /// (enabled by the handshake) and fails with `InvalidInput` without them.
+    pub async fn send_error(&mut self, code: u32, msg: &str) -> Result<()> {
+        if !self.tagged {
+            return Err(NamedPipeError::Io(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "Error frames require frame tags",
+            )));
+        }
+        self.send_tagged(ERROR_TAG, &encode_error(code, msg), Priority::Normal)
+            .await
+    }
+
+    /// Send the reason a connection is dropped, bounded to 256 bytes; the peer's next
+    /// `receive_*` call fails with `NamedPipeError::RejectedByServer`. Requires frame tags.
+    pub(crate) async fn send_rejection(&mut self, reason: &str) -> Result<()> {
+        if !self.tagged {
+            return Err(NamedPipeError::Io(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "Rejection frames require frame tags",
+            )));
+        }
+        self.send_tagged(
+            REJECT_TAG,
+            bounded_reason(reason).as_bytes(),
+            Priority::High,
+        )
+        .await?;
+        self.flush().await
+    }
+
+    /// Tell the peer why this side is about to disconnect: its next `receive_*` call fails
+    /// with `NamedPipeError::ClosedWithCode(code)` instead of a bare disconnect.
+    ///
+    /// Sends a close frame ahead of lower-priority buffered frames and flushes everything;
+    /// closing the stream is left to the caller. Close frames are told apart from data by
+    /// their tag, so this requires frame tags and fails with `InvalidInput` without them.
+    pub async fn send_close(&mut self, code: CloseCode) -> Result<()> {
+        if !self.tagged {
+            return Err(NamedPipeError::Io(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "Close frames require frame tags",
+            )));
+        }
+        self.send_tagged(CLOSE_TAG, &code.code().to_be_bytes(), Priority::High)
+            .await?;
+        self.flush().await
+    }
+
+    /// Finish sending while still receiving, like shutting down the write side of a socket.
+    ///
+    /// Sends a shutdown frame after all buffered frames and flushes. From then on, sends
+    /// fail with `NamedPipeError::WriteShutdown`; control frames such as acks and
+    /// `send_close` still go out. Once the peer has received everything sent before, its
+    /// receives fail with `NamedPipeError::PeerWriteShutdown`, while it can go on sending
+    /// its reply. Pipes have no half-close of their own, so this requires frame tags and
+    /// fails with `InvalidInput` without them.
+    pub async fn shutdown_write(&mut self) -> Result<()> {
+        if !self.tagged {
+            return Err(NamedPipeError::Io(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "Shutdown frames require frame tags",
+            )));
+        }
+        self.check_access(true)?;
+        if self.write_shutdown {
+            return Ok(());
+        }
+        self.send_tagged(SHUTDOWN_TAG, &[], Priority::Low).await?;
+        self.flush().await?;
+        self.write_shutdown = true;
+        Ok(())
+    }
+
+    /// Receive a single frame as raw bytes, whatever its content type
+    pub async fn receive_bytes(&mut self) -> Result<Vec<u8>> {
+        let (_, data) = self.receive_tagged().await?;
+        Ok(data)
+    }
+
+    /// Send a progress update on a long operation whose result the peer waits for with
+    /// `receive_with_progress`.
+    ///
+    /// Progress frames carry a reserved tag, so every other receive skips them and never
+    /// mistakes one for the result. Requires frame tags and fails with `InvalidInput`
+    /// without them.
+    pub async fn send_progress(&mut self, progress: &Progress) -> Result<()> {
+        if !self.tagged {
+            return Err(NamedPipeError::Io(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "Progress frames require frame tags",
+            )));
+        }
+        if self.write_shutdown {
+            return Err(NamedPipeError::WriteShutdown);
+        }
+        self.send_tagged(PROGRESS_TAG, &progress.encode(), Priority::Normal)
+            .await
+    }
+
+    /// Send the value of a handle already duplicated into the peer process in a handle frame
+    pub(crate) async fn send_handle(&mut self, value: u64) -> Result<()> {
+        if !self.tagged {
+            return Err(NamedPipeError::Io(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "Handle frames require frame tags",
+            )));
+        }
+        if self.write_shutdown {
+            return Err(NamedPipeError::WriteShutdown);
+        }
+        self.send_tagged(HANDLE_TAG, &value.to_le_bytes(), Priority::Normal)
+            .await
+    }
+
+    /// Receive the next handle frame and return its handle value. A data frame arriving
+    /// instead fails with `InvalidData` and is kept for the next receive.
+    pub(crate) async fn receive_handle(&mut self) -> Result<u64> {
+        loop {
+            let incoming = match self.unread_frames.pop_front() {
+                Some(incoming) => incoming,
+                None if self.peer_write_shutdown => return Err(NamedPipeError::PeerWriteShutdown),
+                None => self.receive_incoming().await?,
+            };
+            match incoming {
+                Incoming::Handle(value) => return Ok(value),
+                Incoming::Frame(..) => {
+                    self.unread_frames.push_front(incoming);
+                    return Err(NamedPipeError::Io(std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        "Expected a handle frame",
+                    )));
+                }
+                Incoming::Remote(e) => return Err(e),
+                Incoming::PeerUpgraded => {
+                    self.peer_upgraded = true;
+                    return Err(NamedPipeError::PeerUpgraded);
+                }
+                Incoming::Progress(_)
+                | Incoming::Ack(_)
+                | Incoming::ResyncRequested
+                | Incoming::ResyncAnswered => {}
+            }
+        }
+    }
+
+    /// Receive the result of a long operation as raw bytes, calling `on_progress` with each
+    /// progress update the peer sends before it
+    pub async fn receive_with_progress<F: FnMut(Progress) + Send>(
+        &mut self,
+        mut on_progress: F,
+    ) -> Result<Vec<u8>> {
+        let (_, data) = self
+            .receive_tagged_with_progress(Some(&mut on_progress))
+            .await?;
+        Ok(data)
+    }
+
+    /// Receive a single frame as raw bytes, reserving room for a message of `hint` bytes
+    /// before reading.
+    ///
+    /// A large frame is otherwise read into a buffer that grows in steps as data arrives;
+    /// with a hint at least as large as the message, it is read without reallocating. The
+    /// frame's real length prefix still decides what is read, so a wrong hint costs memory
+    /// or reallocations but never correctness. The hint is capped at the maximum message
+    /// size, a hint that cannot be allocated is ignored, and room reserved beyond the usual
+    /// read-ahead is released once the frame is read. Message mode reads whole messages at
+    /// once and ignores the hint.
+    pub async fn receive_bytes_with_capacity(&mut self, hint: usize) -> Result<Vec<u8>> {
+        let max_len = usize::try_from(self.max_message_size).unwrap_or(usize::MAX);
+        let frame_len = hint
+            .min(max_len)
+            .saturating_add(self.frame_format.width.size())
+            .saturating_add(MAX_FRAME_OVERHEAD);
+        // Without the room, the buffer just grows as data arrives
+        let _ = self
+            .read_buffer
+            .try_reserve(frame_len.saturating_sub(self.read_buffer.len()));
+        let result = self.receive_bytes().await;
+        if self.read_buffer.capacity() > MAX_READ_RESERVE {
+            self.read_buffer
+                .shrink_to(MAX_READ_RESERVE.max(self.read_buffer.len()));
+        }
+        result
+    }
+
+    /// Send one frame whose payload is the next `len` bytes of `reader`, without holding
+    /// the payload in memory; the peer receives it with `receive_frame_into`.
+    ///
+    /// Meant for payloads too large to buffer, e.g. beyond 4 GiB with `LengthWidth::U64`.
+    /// Buffered frames are flushed first, then the payload is copied to the stream in
+    /// chunks as it is read. Fails with `InvalidConfig` with encryption, integrity checks,
+    /// message mode or raw framing, which all need the whole payload at once. If `reader`
+    /// ends early, fails with `UnexpectedEof` after the peer has seen part of the frame, so
+    /// the connection must be discarded.
+    pub async fn send_frame_from<R: AsyncRead + Unpin>(
+        &mut self,
+        reader: R,
+        len: u64,
+    ) -> Result<()> {
+        self.check_access(true)?;
+        self.check_streamed_frame()?;
+        if self.write_shutdown {
+            return Err(NamedPipeError::WriteShutdown);
+        }
+        let tag_len = u64::from(self.tagged);
+        let wire_len = len.saturating_add(tag_len);
+        check_frame_size(wire_len, self.max_message_size)?;
+
+        let queue = &mut self.write_queues[Priority::Normal as usize];
+        self.frame_format.encode_length(wire_len, queue)?;
+        if self.tagged {
+            queue.push(ContentType::Raw as u8);
+        }
+        self.flush().await?;
+
+        let mut reader = reader.take(len);
+        let mut chunk = vec![0u8; READ_AHEAD_SIZE];
+        let mut sent = 0;
+        while sent < len {
+            let read = reader.read(&mut chunk).await?;
+            if read == 0 {
+                return Err(NamedPipeError::Io(std::io::Error::new(
+                    std::io::ErrorKind::UnexpectedEof,
+                    format!("Reader ended after {} of {} bytes", sent, len),
+                )));
+            }
+            self.throttle(read).await;
+            self.stream
+                .write_all(&chunk[..read])
+                .await
+                .map_err(stream_error)?;
+            self.bytes_sent += read as u64;
+            self.report_byte_counts();
+            sent += read as u64;
+        }
+        self.stream.flush().await.map_err(stream_error)?;
+        Ok(())
+    }
+
+    /// Receive one frame into `writer` in chunks, returning its length, without holding the
+    /// payload in memory. Accepts any frame, including ones sent with `send_bytes`.
+    ///
+    /// The counterpart of `send_frame_from`, with the same restrictions. Frames that need
+    /// processing as a whole, such as compressed frames, are received the regular way.
+    /// Not cancel-safe: a cancelled receive leaves the rest of the frame unread, so the
+    /// connection must be discarded.
+    pub async fn receive_frame_into<W: AsyncWrite + Unpin>(
+        &mut self,
+        mut writer: W,
+    ) -> Result<u64> {
+        self.check_access(false)?;
+        self.check_streamed_frame()?;
+        if !self.unread_frames.is_empty() {
+            return self.receive_whole_frame_into(&mut writer).await;
+        }
+        if self.flush_before_receive && self.buffered_len() > 0 {
+            self.flush().await?;
+        }
+
+        let prefix_len = self.frame_format.width.size();
+        while self.read_buffer.len() < prefix_len {
+            self.fill_read_buffer(prefix_len).await?;
+        }
+        let wire_len = self
+            .frame_format
+            .decode_length(&self.read_buffer[..prefix_len]);
+        check_frame_size(wire_len, self.max_message_size)?;
+
+        let mut header_len = prefix_len;
+        if self.tagged && wire_len > 0 {
+            while self.read_buffer.len() <= prefix_len {
+                self.fill_read_buffer(prefix_len + 1).await?;
+            }
+            let tag = self.read_buffer[prefix_len];
+            if is_control_tag(tag) || split_tag(tag)?.1 != 0 {
+                return self.receive_whole_frame_into(&mut writer).await;
+            }
+            header_len += 1;
+        }
+        let len = wire_len - (header_len - prefix_len) as u64;
+        self.throttle(header_len).await;
+        self.read_buffer.drain(..header_len);
+
+        let mut remaining = len;
+        while remaining > 0 {
+            if self.read_buffer.is_empty() {
+                self.fill_read_buffer(1).await?;
+            }
+            let take = remaining.min(self.read_buffer.len() as u64) as usize;
+            self.throttle(take).await;
+            writer.write_all(&self.read_buffer[..take]).await?;
+            self.read_buffer.drain(..take);
+            remaining -= take as u64;
+        }
+        writer.flush().await?;
+        Ok(len)
+    }
+
+    /// Receive one frame the regular way and write its payload to `writer`
+    async fn receive_whole_frame_into<W: AsyncWrite + Unpin>(
+        &mut self,
+        writer: &mut W,
+    ) -> Result<u64> {
+        let data = self.receive_bytes().await?;
+        writer.write_all(&data).await?;
+        writer.flush().await?;
+        Ok(data.len() as u64)
+    }
+
+    /// Fail if the stream uses a feature that needs whole frame payloads
+    fn check_streamed_frame(&self) -> Result<()> {
+        let conflict = if self.framing == Framing::None {
+            "raw framing"
+        } else if self.pipe_mode == PipeMode::Message {
+            "message mode"
+        } else if self.cipher.is_some() {
+            "encryption"
+        } else if self.integrity_check {
+            "integrity checks"
+        } else {
+            return Ok(());
+        };
+        Err(NamedPipeError::InvalidConfig(format!(
+            "streamed frames cannot be combined with {}",
+            conflict
+        )))
+    }
+
+    /// Receive a single frame decoded according to its content type.
+    /// Without frame tags every message is returned as `Message::Raw`.
+    pub async fn receive_any(&mut self) -> Result<Message> {
+        let (content_type, data) = self.receive_tagged().await?;
+        Message::decode(content_type, data)
+    }
+
+    /// Receive frames and forward each into `sink` until the peer closes the connection,
+    /// returning how many were forwarded.
+    ///
+    /// Every frame is sent and flushed into the sink before the next one is received, so a
+    /// sink that is not ready holds back the reads, and with them the peer. On peer close
+    /// the sink is flushed but not closed. Sink failures are returned as
+    /// `NamedPipeError::Sink`; the frame being forwarded is lost.
+    pub async fn drain_to<Si>(&mut self, mut sink: Si) -> Result<u64>
+    where
+        Si: Sink<Vec<u8>> + Unpin,
+        Si::Error: std::error::Error + Send + Sync + 'static,
+    {
+        let mut forwarded = 0;
+        loop {
+            match self.receive_bytes().await {
+                Ok(frame) => {
+                    sink.send(frame).await.map_err(sink_error)?;
+                    forwarded += 1;
+                }
+                Err(e) if e.is_disconnect() => {
+                    sink.flush().await.map_err(sink_error)?;
+                    return Ok(forwarded);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Seal and send one frame, prefixing `data` with `tag` if tags are enabled.
+    /// Compression, if enabled, is applied before sealing.
+    async fn send_tagged(&mut self, tag: u8, data: &[u8], priority: Priority) -> Result<()> {
+        self.send_tagged_with_headers(tag, data, priority, None)
+            .await
+    }
+
+    /// `send_tagged`, attaching the default headers overridden by `extra` to data frames
+    async fn send_tagged_with_headers(
+        &mut self,
+        tag: u8,
+        data: &[u8],
+        priority: Priority,
+        extra: Option<&Headers>,
+    ) -> Result<()> {
+        if self.write_shutdown && !is_control_tag(tag) {
+            return Err(NamedPipeError::WriteShutdown);
+        }
+        let with_headers = self.attach_headers(tag, data, extra)?;
+        let (tag, data) = match with_headers.as_deref() {
+            Some(body) => (tag | FLAG_HEADERS, body),
+            None => (tag, data),
+        };
+        let payload = if self.tagged {
+            let compressed = if self.compression && !is_control_tag(tag) {
+                compress(data, self.compression_threshold)
+            } else {
+                None
+            };
+            let (tag, body) = match compressed.as_deref() {
+                Some(compressed) => (tag | FLAG_COMPRESSED, compressed),
+                None => (tag, data),
+            };
+            let mut message = Vec::with_capacity(1 + body.len());
+            message.push(tag);
+            message.extend_from_slice(body);
+            self.seal(&message)?
+        } else {
+            self.seal(data)?
+        };
+        self.send_payload(&payload, priority).await
+    }
+
+    /// Prefix a data frame's body with its headers, if it has any
+    fn attach_headers(
+        &self,
+        tag: u8,
+        data: &[u8],
+        extra: Option<&Headers>,
+    ) -> Result<Option<Vec<u8>>> {
+        let merged;
+        let headers = match extra {
+            Some(extra) if !extra.is_empty() && !self.default_headers.is_empty() => {
+                merged = self
+                    .default_headers
+                    .iter()
+                    .chain(extra)
+                    .map(|(name, value)| (name.clone(), value.clone()))
+                    .collect();
+                &merged
+            }
+            Some(extra) if !extra.is_empty() => extra,
+            _ => &self.default_headers,
+        };
+        if headers.is_empty() || is_control_tag(tag) {
+            return Ok(None);
+        }
+        if !self.tagged {
+            return Err(NamedPipeError::Io(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "Headers require frame tags",
+            )));
+        }
+        encode_headers(headers, data).map(Some)
+    }
+
+    /// Receive and open one frame, splitting off its content type if tags are enabled.
+    /// Error frames are returned as `NamedPipeError::RemoteError` and rejections as
+    /// `NamedPipeError::RejectedByServer`; stray acks are skipped.
+    async fn receive_tagged(&mut self) -> Result<(Option<ContentType>, Vec<u8>)> {
+        self.receive_tagged_with_progress(None).await
+    }
+
+    /// `receive_tagged`, handing progress frames to `on_progress` instead of skipping them
+    async fn receive_tagged_with_progress(
+        &mut self,
+        mut on_progress: Option<&mut (dyn FnMut(Progress) + Send)>,
+    ) -> Result<(Option<ContentType>, Vec<u8>)> {
+        loop {
+            let incoming = match self.unread_frames.pop_front() {
+                Some(incoming) => incoming,
+                None if self.peer_write_shutdown => return Err(NamedPipeError::PeerWriteShutdown),
+                None => self.receive_incoming().await?,
+            };
+            match incoming {
+                Incoming::Frame(content_type, data, headers) => {
+                    self.received_headers = headers;
+                    return Ok((content_type, data));
+                }
+                Incoming::Remote(e) => return Err(e),
+                Incoming::PeerUpgraded => {
+                    self.peer_upgraded = true;
+                    return Err(NamedPipeError::PeerUpgraded);
+                }
+                Incoming::Progress(progress) => {
+                    if let Some(on_progress) = on_progress.as_mut() {
+                        on_progress(progress);
+                    }
+                }
+                // Keep the handle for `receive_handle` rather than leak it
+                Incoming::Handle(value) => {
+                    self.unread_frames.push_front(Incoming::Handle(value));
+                    return Err(NamedPipeError::Io(std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        "Received a handle; call receive_handle",
+                    )));
+                }
+                // The ack of a send that timed out, or control frames already handled
+                Incoming::Ack(_) | Incoming::ResyncRequested | Incoming::ResyncAnswered => {}
+            }
+        }
+    }
+
+    /// Receive and open one frame, answering it with an ack if it requests one.
+    ///
+    /// An acked frame is kept in `unread_frames` until its ack is written, so a failed or
+    /// cancelled ack write loses nothing: the frame is returned by the next receive, and the
+    /// ack is retried before another frame is read.
+    async fn receive_incoming(&mut self) -> Result<Incoming> {
+        if !self.pending_acks.is_empty() {
+            self.send_pending_acks().await?;
+        }
+        let payload = self.receive_payload().await?;
+        let mut message = self.open(payload)?;
+        if !self.tagged {
+            return Ok(Incoming::Frame(None, message, Headers::new()));
+        }
+
+        let Some(&tag) = message.first() else {
+            return Err(NamedPipeError::Io(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "Frame is missing its tag",
+            )));
+        };
+        message.remove(0);
+        match tag {
+            ERROR_TAG => return Ok(Incoming::Remote(decode_error(message))),
+            REJECT_TAG => return Ok(Incoming::Remote(decode_rejection(&message))),
+            ACK_TAG => return decode_ack_sequence(&message).map(Incoming::Ack),
+            CLOSE_TAG => return Ok(Incoming::Remote(decode_close(&message))),
+            RESYNC_TAG => return self.answer_resync(&message).await,
+            PROGRESS_TAG => return decode_progress(&message).map(Incoming::Progress),
+            UPGRADE_TAG => return Ok(Incoming::PeerUpgraded),
+            HANDLE_TAG => return decode_handle(&message).map(Incoming::Handle),
+            SHUTDOWN_TAG => {
+                self.peer_write_shutdown = true;
+                return Ok(Incoming::Remote(NamedPipeError::PeerWriteShutdown));
+            }
+            _ => {}
+        }
+        let (content_type, flags) = split_tag(tag)?;
+        if flags & FLAG_COMPRESSED != 0 {
+            message = decompress(&message, self.max_message_size)?;
+        }
+        let headers = if flags & FLAG_HEADERS != 0 {
+            decode_headers(&mut message)?
+        } else {
+            Headers::new()
+        };
+        let sequence = if flags & FLAG_ACK_REQUESTED != 0 {
+            let sequence = decode_ack_sequence(&message)?;
+            message.drain(..ACK_SEQUENCE_LEN);
+            Some(sequence)
+        } else {
+            None
+        };
+        let frame = Incoming::Frame(Some(content_type), message, headers);
+        if let Some(sequence) = sequence {
+            self.pending_acks.push_back(sequence);
+            self.unread_frames.push_back(frame);
+            self.send_pending_acks().await?;
+            return Ok(self
+                .unread_frames
+                .pop_back()
+                .expect("acked frame was just queued"));
+        }
+        Ok(frame)
+    }
+
+    /// Write the acks still owed to the peer, highest priority first
+    async fn send_pending_acks(&mut self) -> Result<()> {
+        while let Some(&sequence) = self.pending_acks.front() {
+            self.send_tagged(ACK_TAG, &sequence.to_le_bytes(), Priority::High)
+                .await?;
+            self.pending_acks.pop_front();
+        }
+        self.flush().await
+    }
+
+    /// Handle a resync frame: answer a request after resetting this end's crypto state, or
+    /// report the peer's answer
+    async fn answer_resync(&mut self, body: &[u8]) -> Result<Incoming> {
+        let rekey = match decode_resync(body)? {
+            Resync::Request(rekey) => rekey,
+            Resync::Reply => return Ok(Incoming::ResyncAnswered),
+        };
+        self.replay_window = ReplayWindow::default();
+        let new_key = match rekey {
+            Some(rekey) => {
+                let ring = self
+                    .key_ring
+                    .as_ref()
+                    .filter(|ring| ring.ciphers.contains_key(&rekey.base))
+                    .ok_or(NamedPipeError::UnknownKeyId(rekey.base))?;
+                let base = ring.keys.get(&rekey.base).ok_or_else(rekey_needs_key)?;
+                let key = hkdf_sha256(&**base, &rekey.salt, REKEY_INFO);
+                self.add_key(rekey.id, &key)?;
+                Some(rekey.id)
+            }
+            None => None,
+        };
+
+        self.send_tagged(RESYNC_TAG, &Resync::Reply.encode(), Priority::Normal)
+            .await?;
+        self.flush().await?;
+        self.send_sequence = 0;
+        if let Some(id) = new_key {
+            self.set_active_key(id)?;
+        }
+        Ok(Incoming::ResyncRequested)
+    }
+
+    /// Send `payload` as one frame as-is, without encryption or checksum
+    pub(crate) async fn send_payload(&mut self, payload: &[u8], priority: Priority) -> Result<()> {
+        self.check_access(true)?;
+        check_frame_size(payload.len() as u64, self.max_message_size)?;
+        self.check_framing()?;
+        if self.pipe_mode == PipeMode::Message {
+            return self.send_message(payload).await;
+        }
+
+        let queue = &mut self.write_queues[priority as usize];
+        let start = queue.len();
+        if self.framing == Framing::LengthPrefixed {
+            self.frame_format
+                .encode_length(payload.len() as u64, queue)?;
+        }
+        queue.extend_from_slice(payload);
+        let frame_len = queue.len() - start;
+
+        self.throttle(frame_len).await;
+
+        if self.auto_flush {
+            self.flush().await?;
+        }
+        Ok(())
+    }
+
+    /// Receive the payload of one frame as-is, without decryption or checksum verification.
+    ///
+    /// Cancel-safe: a partially read frame stays in the read-ahead buffer (or, in message
+    /// mode, a read message stays pending) until it is complete and rate-limited, so a
+    /// cancelled receive loses nothing and the next one picks up where it left off.
+    pub(crate) async fn receive_payload(&mut self) -> Result<Vec<u8>> {
+        self.check_access(false)?;
+        if self.flush_before_receive && self.buffered_len() > 0 {
+            self.flush().await?;
+        }
+        self.check_framing()?;
+
+        if self.framing == Framing::None {
+            if self.read_buffer.is_empty() {
+                self.fill_read_buffer(1).await?;
+            }
+            self.throttle(self.read_buffer.len()).await;
+            return Ok(std::mem::take(&mut self.read_buffer));
+        }
+
+        if self.pipe_mode == PipeMode::Message {
+            let pending = match self.pending_message.take() {
+                Some(payload) => payload,
+                None => {
+                    let payload = read_message(&mut self.stream).await?;
+                    self.bytes_received += payload.len() as u64;
+                    self.report_byte_counts();
+                    check_frame_size(payload.len() as u64, self.max_message_size)?;
+                    payload
+                }
+            };
+            let wire_len = pending.len();
+            self.pending_message = Some(pending);
+            self.throttle(wire_len).await;
+            return Ok(self.pending_message.take().unwrap_or_default());
+        }
+
+        let frame_len = loop {
+            match self
+                .frame_format
+                .frame_len(&self.read_buffer, self.max_message_size)?
+            {
+                FrameLen::Complete(len) => break len,
+                FrameLen::Needs(needed) => self.fill_read_buffer(needed).await?,
+            }
+        };
+
+        self.throttle(frame_len).await;
+
+        let payload = self.read_buffer[self.frame_format.width.size()..frame_len].to_vec();
+        self.read_buffer.drain(..frame_len);
+        Ok(payload)
+    }
+
+    /// Fail if raw framing is combined with a feature that needs frames
+    fn check_framing(&self) -> Result<()> {
+        let conflict = if self.framing == Framing::LengthPrefixed {
+            return Ok(());
+        } else if self.pipe_mode == PipeMode::Message {
+            "message mode"
+        } else if self.cipher.is_some() {
+            "encryption"
+        } else if self.integrity_check {
+            "integrity checks"
+        } else if self.tagged {
+            "frame tags"
+        } else {
+            return Ok(());
+        };
+        Err(NamedPipeError::InvalidConfig(format!(
+            "raw framing cannot be combined with {}",
+            conflict
+        )))
+    }
+
+    /// Wait until the rate limit, if any, allows `bytes` more to be transferred. The
+    /// limiter is shared with the other half of a split stream.
+    async fn throttle(&mut self, bytes: usize) {
+        if let Some(limiter) = &self.rate_limiter {
+            limiter.lock().await.acquire(bytes).await;
+        }
+    }
+
+    /// Read more of the stream into the read-ahead buffer, which needs `needed` bytes in
+    /// total to hold the next frame
+    async fn fill_read_buffer(&mut self, needed: usize) -> Result<()> {
+        let missing = needed.saturating_sub(self.read_buffer.len());
+        self.read_buffer
+            .reserve(missing.clamp(READ_AHEAD_SIZE, MAX_READ_RESERVE));
+
+        let read = self
+            .stream
+            .read_buf(&mut self.read_buffer)
+            .await
+            .map_err(stream_error)?;
+        if read == 0 {
+            return Err(NamedPipeError::ConnectionClosed);
+        }
+        self.bytes_received += read as u64;
+        self.report_byte_counts();
+        Ok(())
+    }
+
+    /// Write `payload` as a single pipe message
+    async fn send_message(&mut self, payload: &[u8]) -> Result<()> {
+        // A zero-byte write sends no message at all, and a zero-byte read means end of stream
+        if payload.is_empty() {
+            return Err(NamedPipeError::Io(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "Message mode cannot send an empty frame without tags, encryption or checksum",
+            )));
+        }
+        if payload.len() > MAX_MESSAGE_MODE_PAYLOAD {
+            return Err(NamedPipeError::Io(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "Payload of {} bytes exceeds the message mode limit of {} bytes",
+                    payload.len(),
+                    MAX_MESSAGE_MODE_PAYLOAD
+                ),
+            )));
+        }
+
+        self.throttle(payload.len()).await;
+
+        // A message must go out in one write; retrying the rest would turn it into two
+        // messages, so a short write fails the send instead
+        let written = self.stream.write(payload).await.map_err(stream_error)?;
+        self.bytes_sent += written as u64;
+        self.report_byte_counts();
+        if written < payload.len() {
+            return Err(NamedPipeError::Io(std::io::Error::new(
+                std::io::ErrorKind::WriteZero,
+                format!(
+                    "Only {} of {} bytes of a message were written",
+                    written,
+                    payload.len()
+                ),
+            )));
+        }
+        self.stream.flush().await.map_err(stream_error)?;
+        Ok(())
+    }
+
+    /// Send a string message
+    pub async fn send_string(&mut self, message: &str) -> Result<()> {
+        self.send_tagged(
+            ContentType::Utf8 as u8,
+            message.as_bytes(),
+            Priority::Normal,
+        )
+        .await
+    }
+
+    /// Receive a string message. JSON frames are accepted as text too; other tagged
+    /// frames fail with `NamedPipeError::ContentTypeMismatch`.
+    pub async fn receive_string(&mut self) -> Result<String> {
+        let (content_type, data) = self.receive_tagged().await?;
+        expect_content_type(content_type, ContentType::Utf8, &[ContentType::Json])?;
+        decode_utf8(data)
+    }
+
+    /// Send a string message in the given encoding. UTF-8 strings are sent like
+    /// `send_string`; UTF-16LE strings are tagged as raw bytes.
+    pub async fn send_string_as(&mut self, message: &str, encoding: TextEncoding) -> Result<()> {
+        self.send_tagged(
+            encoding.content_type() as u8,
+            &encoding.encode(message),
+            Priority::Normal,
+        )
+        .await
+    }
+
+    /// Receive a string message in the given encoding, failing on bytes that are invalid
+    /// in it. UTF-16LE strings must arrive untagged or tagged as raw bytes.
+    pub async fn receive_string_as(&mut self, encoding: TextEncoding) -> Result<String> {
+        if encoding == TextEncoding::Utf8 {
+            return self.receive_string().await;
+        }
+        let (content_type, data) = self.receive_tagged().await?;
+        expect_content_type(content_type, encoding.content_type(), &[])?;
+        encoding.decode(data)
+    }
+
+    /// Send a JSON message
+    pub async fn send_json<T: serde::Serialize>(&mut self, message: &T) -> Result<()> {
+        let json = serde_json::to_string(message).map_err(|e| {
+            NamedPipeError::Io(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("JSON serialization failed: {}", e),
+            ))
+        })?;
+        self.send_tagged(ContentType::Json as u8, json.as_bytes(), Priority::Normal)
+            .await
+    }
+
+    /// Receive a JSON message. Tagged frames that are not JSON fail with
+    /// `NamedPipeError::ContentTypeMismatch` before any parsing is attempted.
+    pub async fn receive_json<T: serde::de::DeserializeOwned>(&mut self) -> Result<T> {
+        let (content_type, data) = self.receive_tagged().await?;
+        expect_content_type(content_type, ContentType::Json, &[])?;
+        decode_json(&data)
+    }
+}
+
+impl<S> Framed<S> {
+    /// Call `callback` with the bytes sent and received together each time they reach
+    /// another multiple of `every`
+    pub(crate) fn set_byte_milestones(&mut self, every: u64, callback: MilestoneCallback) {
+        self.milestones = (every > 0).then(|| ByteMilestones {
+            every,
+            next: (self.bytes_sent + self.bytes_received) / every * every + every,
+            callback,
+        });
+    }
+
+    /// Publish the byte counts to readers on other tasks, if they are shared
+    pub(crate) fn share_byte_counts(&mut self) -> Arc<SharedByteCounts> {
+        let counts = Arc::new(SharedByteCounts {
+            sent: AtomicU64::new(self.bytes_sent),
+            received: AtomicU64::new(self.bytes_received),
+        });
+        self.shared_counts = Some(Arc::clone(&counts));
+        counts
+    }
+
+    /// Publish the byte counts if they are shared, and report the last milestone passed if
+    /// they passed one since the last call
+    fn report_byte_counts(&mut self) {
+        if let Some(counts) = self.shared_counts.as_ref() {
+            counts.sent.store(self.bytes_sent, Ordering::Relaxed);
+            counts
+                .received
+                .store(self.bytes_received, Ordering::Relaxed);
+        }
+        let Some(milestones) = self.milestones.as_mut() else {
+            return;
+        };
+        let total = self.bytes_sent + self.bytes_received;
+        if total >= milestones.next {
+            let reached = total / milestones.every * milestones.every;
+            milestones.next = reached + milestones.every;
+            (milestones.callback)(reached);
+        }
+    }
+
+    /// Turn a message into the payload carried by its frame
+    fn seal(&mut self, data: &[u8]) -> Result<Vec<u8>> {
+        if let Some(ref cipher) = self.cipher {
+            let ciphertext = if self.replay_protection {
+                let mut sequenced = Vec::with_capacity(SEQUENCE_LEN + data.len());
+                sequenced.extend_from_slice(&self.send_sequence.to_le_bytes());
+                sequenced.extend_from_slice(data);
+                self.send_sequence += 1;
+                cipher.encrypt(&sequenced)?
+            } else {
+                cipher.encrypt(data)?
+            };
+            return Ok(match self.key_ring {
+                Some(ref ring) => {
+                    let mut payload = Vec::with_capacity(1 + ciphertext.len());
+                    payload.push(ring.active);
+                    payload.extend_from_slice(&ciphertext);
+                    payload
+                }
+                None => ciphertext,
+            });
+        }
+
+        let mut payload = Vec::with_capacity(data.len() + CHECKSUM_LEN);
+        payload.extend_from_slice(data);
+        if self.integrity_check {
+            payload.extend_from_slice(&crc32c::crc32c(data).to_le_bytes());
+        }
+        Ok(payload)
+    }
+
+    /// Recover a message from the payload carried by its frame
+    fn open(&mut self, mut payload: Vec<u8>) -> Result<Vec<u8>> {
+        if let Some(ref cipher) = self.cipher {
+            let mut message = match self.key_ring {
+                Some(ref ring) => {
+                    let (&id, ciphertext) = payload.split_first().ok_or_else(|| {
+                        NamedPipeError::Io(std::io::Error::new(
+                            std::io::ErrorKind::InvalidData,
+                            "Encrypted frame is missing its key id",
+                        ))
+                    })?;
+                    let cipher = ring
+                        .ciphers
+                        .get(&id)
+                        .ok_or(NamedPipeError::UnknownKeyId(id))?;
+                    cipher.decrypt(ciphertext)?
+                }
+                None => cipher.decrypt(&payload)?,
+            };
+            if !self.replay_protection {
+                return Ok(message);
+            }
+            let sequence = message
+                .get(..SEQUENCE_LEN)
+                .and_then(|bytes| <[u8; SEQUENCE_LEN]>::try_from(bytes).ok())
+                .ok_or(NamedPipeError::ReplayDetected)?;
+            self.replay_window.check(u64::from_le_bytes(sequence))?;
+            message.drain(..SEQUENCE_LEN);
+            return Ok(message);
+        }
+
+        if self.integrity_check {
+            if payload.len() < CHECKSUM_LEN {
+                return Err(NamedPipeError::ChecksumMismatch);
+            }
+            let checksum = payload.split_off(payload.len() - CHECKSUM_LEN);
+            let expected = crc32c::crc32c(&payload).to_le_bytes();
+            if checksum != expected {
+                return Err(NamedPipeError::ChecksumMismatch);
+            }
+        }
+        Ok(payload)
+    }
+}
+
+fn sink_error<E: std::error::Error + Send + Sync + 'static>(e: E) -> NamedPipeError {
+    NamedPipeError::Sink(Box::new(e))
+}
+
+/// Fail if a frame payload of `size` bytes exceeds `limit`
+fn check_frame_size(size: u64, limit: u64) -> Result<()> {
+    if size > limit {
+        return Err(NamedPipeError::MessageTooLarge { size, limit });
+    }
+    Ok(())
+}
+
+/// Error for rekeying from a key that was given only as a `Cipher`
+fn rekey_needs_key() -> NamedPipeError {
+    NamedPipeError::InvalidConfig(
+        "rekeying needs the key the active cipher was built from".to_string(),
+    )
+}
+
+/// Error for a buffer holding `have` bytes where a frame needs `need`
+fn truncated(need: usize, have: usize) -> NamedPipeError {
+    NamedPipeError::Io(std::io::Error::new(
+        std::io::ErrorKind::UnexpectedEof,
+        format!("Truncated frame: need {} bytes, have {}", need, have),
+    ))
+}
+
+/// Read the payload of one pipe message from `reader`
+async fn read_message<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Vec<u8>> {
+    let mut buffer = vec![0u8; MAX_MESSAGE_MODE_PAYLOAD];
+    let len = reader.read(&mut buffer).await.map_err(stream_error)?;
+    if len == 0 {
+        return Err(NamedPipeError::ConnectionClosed);
+    }
+
+    buffer.truncate(len);
+    Ok(buffer)
+}
+
+/// Convert a stream IO error, reporting a vanished peer as `Disconnected`. A write in
+/// flight when the peer closes fails with `ERROR_NO_DATA` rather than a broken pipe.
+fn stream_error(e: std::io::Error) -> NamedPipeError {
+    let peer_gone = [ERROR_BROKEN_PIPE, ERROR_NO_DATA, ERROR_PIPE_NOT_CONNECTED]
+        .iter()
+        .any(|code| e.raw_os_error() == Some(code.0 as i32));
+
+    if peer_gone || e.kind() == std::io::ErrorKind::BrokenPipe {
+        NamedPipeError::Disconnected
+    } else {
+        NamedPipeError::Io(e)
+    }
+}
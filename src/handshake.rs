@@ -0,0 +1,433 @@
+//! Connection handshake run right after a pipe opens, before any application data.
+//!
+//! # Hello
+//!
+//! The client opens with a plaintext hello frame: the magic `PGRD`, the protocol version, a
+//! flags byte whose lowest bit says whether it encrypts and whose next bit selects
+//! XChaCha20Poly1305 over ChaCha20Poly1305, and its `Features` as a little-endian `u32`.
+//! The server answers with the magic, its version, a status byte and the features both
+//! sides support: the connection is accepted with or without encryption, or rejected
+//! because the server requires encryption or refuses it (or the client's cipher suite).
+//! Each side then turns off the convenience features, compression and acks, that the other
+//! lacks. The hello is not authenticated, so security features are never turned off this
+//! way: a side whose integrity check or replay protection the other lacks fails with
+//! `NamedPipeError::FeatureMismatch`. Both frames bypass encryption and checksums, so
+//! mismatched peers fail cleanly instead of misreading each other's frames. Once the hello
+//! succeeds, both sides start tagging their frames with a content type (see the `message`
+//! module).
+//!
+//! A server that refuses the client before the hello, e.g. because its executable path
+//! does not match, may answer the client's hello with the status `4` followed by a UTF-8
+//! reason of at most 256 bytes instead of dropping it silently. A client that fails
+//! authentication may likewise get a rejection frame (tag `0xFE`) carrying the reason.
+//!
+//! # Per-connection keys
+//!
+//! A client created with `new_encrypted_derived` sets the hello flag `0x04`, and the server
+//! accepts it only if it derives keys too. After the hello, the client sends a random
+//! 32-byte salt and the server answers with its own, both as plaintext frames. Each side
+//! then replaces the master key with `HKDF-SHA256(master, client salt || server salt)`, so
+//! every connection is encrypted under its own key and a nonce reused on one connection
+//! reveals nothing about another.
+//!
+//! # Shared-secret authentication
+//!
+//! If configured, authentication follows the hello:
+//!
+//! 1. The server sends a random 32-byte challenge.
+//! 2. The client sends its own challenge and `HMAC-SHA256(secret, "client" ‖ server
+//!    challenge ‖ client challenge)`.
+//! 3. The server verifies the reply and, if it matches, answers with `HMAC-SHA256(secret,
+//!    "server" ‖ client challenge ‖ server challenge)`; otherwise it closes the connection.
+//! 4. The client verifies the server's answer.
+//!
+//! Both sides therefore prove knowledge of the secret without ever sending it. The role
+//! labels keep a tag computed by one side from being reflected back as the other's.
+
+use crate::cipher::{Cipher, CipherSuite};
+use crate::error::{NamedPipeError, Result};
+use crate::framing::{Features, Framed, Priority};
+use crate::message::{bounded_reason, decode_rejection};
+use crate::server::EncryptionPolicy;
+use chacha20poly1305::aead::{rand_core::RngCore, OsRng};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Length of the random challenges exchanged by both sides
+const CHALLENGE_LEN: usize = 32;
+
+/// Labels that keep client and server tags apart
+const CLIENT_ROLE: &[u8] = b"client";
+const SERVER_ROLE: &[u8] = b"server";
+
+/// Length of an HMAC-SHA256 tag
+const TAG_LEN: usize = 32;
+
+/// Magic bytes opening every hello frame
+const HELLO_MAGIC: &[u8; 4] = b"PGRD";
+
+/// Version of the handshake protocol
+pub(crate) const PROTOCOL_VERSION: u8 = 3;
+
+/// Client hello flag: the client encrypts its frames
+const FLAG_ENCRYPTED: u8 = 0x01;
+/// Client hello flag: the client encrypts with XChaCha20Poly1305
+const FLAG_XCHACHA: u8 = 0x02;
+/// Client hello flag: the client derives a per-connection key from its master key
+const FLAG_DERIVED: u8 = 0x04;
+
+/// Length of the random salt each side contributes to a derived key
+const SALT_LEN: usize = 32;
+
+/// HKDF info binding derived keys to their use
+const DERIVED_KEY_INFO: &[u8] = b"pipeguard connection key";
+
+/// Server hello status: accepted, frames are not encrypted
+const STATUS_PLAINTEXT: u8 = 0;
+/// Server hello status: accepted, frames are encrypted
+const STATUS_ENCRYPTED: u8 = 1;
+/// Server hello status: rejected, the server only accepts encrypted clients
+const STATUS_ENCRYPTION_REQUIRED: u8 = 2;
+/// Server hello status: rejected, the server does not accept encrypted clients
+const STATUS_ENCRYPTION_REFUSED: u8 = 3;
+/// Server hello status: rejected for the reason that follows the status
+const STATUS_REJECTED: u8 = 4;
+
+/// Run the client side of the hello exchange, announcing whether this side encrypts and,
+/// if it does, whether it derives a per-connection key
+pub(crate) async fn send_hello<S>(framed: &mut Framed<S>, derive_keys: bool) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let flags = match framed.cipher_suite() {
+        Some(suite) if derive_keys => FLAG_ENCRYPTED | suite_flag(suite) | FLAG_DERIVED,
+        Some(suite) => FLAG_ENCRYPTED | suite_flag(suite),
+        None => 0,
+    };
+    let capabilities = framed.capabilities();
+    send_hello_frame(framed, flags, capabilities).await?;
+
+    let hello = framed.receive_payload().await?;
+    if let Some(&[PROTOCOL_VERSION, STATUS_REJECTED, ref reason @ ..]) =
+        hello.strip_prefix(HELLO_MAGIC)
+    {
+        return Err(decode_rejection(reason));
+    }
+    match parse_hello(&hello)? {
+        (status @ (STATUS_PLAINTEXT | STATUS_ENCRYPTED), negotiated) => {
+            if (status == STATUS_ENCRYPTED) != (flags & FLAG_ENCRYPTED != 0) {
+                return Err(invalid_hello(
+                    "status does not match the client's encryption",
+                ));
+            }
+            framed.apply_capabilities(negotiated)?;
+            framed.set_frame_tags(true);
+            Ok(())
+        }
+        (STATUS_ENCRYPTION_REQUIRED, _) => Err(NamedPipeError::EncryptionRequired),
+        (STATUS_ENCRYPTION_REFUSED, _) => Err(NamedPipeError::EncryptionMismatch),
+        (status, _) => Err(invalid_hello(&format!("unknown status {}", status))),
+    }
+}
+
+/// Run the server side of the hello exchange, applying `policy` to the client's choice.
+/// Turns encryption off on `framed` if the connection is accepted in plaintext, and any
+/// convenience feature the client does not support; clients lacking a security feature the
+/// server uses are rejected. Encrypted clients are refused unless they agree with the server
+/// on deriving keys.
+pub(crate) async fn accept_hello<S>(
+    framed: &mut Framed<S>,
+    policy: EncryptionPolicy,
+    derive_keys: bool,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (flags, client_capabilities) = receive_hello_frame(framed).await?;
+    let client_encrypts = flags & FLAG_ENCRYPTED != 0;
+    let suite_matches = framed
+        .cipher_suite()
+        .is_some_and(|suite| suite_flag(suite) == flags & FLAG_XCHACHA)
+        && (flags & FLAG_DERIVED != 0) == derive_keys;
+
+    let (status, result) = match (client_encrypts, policy) {
+        (true, policy) if policy == EncryptionPolicy::Refused || !suite_matches => (
+            STATUS_ENCRYPTION_REFUSED,
+            Err(NamedPipeError::EncryptionMismatch),
+        ),
+        (true, _) => (STATUS_ENCRYPTED, Ok(())),
+        (false, EncryptionPolicy::Required) => (
+            STATUS_ENCRYPTION_REQUIRED,
+            Err(NamedPipeError::EncryptionRequired),
+        ),
+        (false, _) => {
+            framed.set_cipher(None);
+            (STATUS_PLAINTEXT, Ok(()))
+        }
+    };
+
+    let negotiated = client_capabilities & framed.capabilities();
+    let missing = framed.missing_security_features(negotiated);
+    if result.is_ok() && missing != Features::empty() {
+        send_rejection_frame(framed, "client lacks required security features").await?;
+        return Err(NamedPipeError::FeatureMismatch(missing));
+    }
+    send_hello_frame(framed, status, negotiated).await?;
+    if result.is_ok() {
+        framed.apply_capabilities(negotiated)?;
+        framed.set_frame_tags(true);
+    }
+    result
+}
+
+fn suite_flag(suite: CipherSuite) -> u8 {
+    match suite {
+        CipherSuite::ChaCha20Poly1305 => 0,
+        CipherSuite::XChaCha20Poly1305 => FLAG_XCHACHA,
+    }
+}
+
+async fn send_hello_frame<S>(
+    framed: &mut Framed<S>,
+    value: u8,
+    capabilities: Features,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut hello = Vec::with_capacity(HELLO_MAGIC.len() + 6);
+    hello.extend_from_slice(HELLO_MAGIC);
+    hello.push(PROTOCOL_VERSION);
+    hello.push(value);
+    hello.extend_from_slice(&capabilities.bits().to_le_bytes());
+    framed.send_payload(&hello, Priority::High).await?;
+    framed.flush().await
+}
+
+/// Answer the client's hello with a rejection carrying `reason`, bounded to 256 bytes
+pub(crate) async fn reject_hello<S>(framed: &mut Framed<S>, reason: &str) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    receive_hello_frame(framed).await?;
+    send_rejection_frame(framed, reason).await
+}
+
+/// Send a hello with the rejected status and `reason`, bounded to 256 bytes
+async fn send_rejection_frame<S>(framed: &mut Framed<S>, reason: &str) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let reason = bounded_reason(reason);
+    let mut hello = Vec::with_capacity(HELLO_MAGIC.len() + 2 + reason.len());
+    hello.extend_from_slice(HELLO_MAGIC);
+    hello.push(PROTOCOL_VERSION);
+    hello.push(STATUS_REJECTED);
+    hello.extend_from_slice(reason.as_bytes());
+    framed.send_payload(&hello, Priority::High).await?;
+    framed.flush().await
+}
+
+/// Receive a hello frame and return its flags or status byte and its capabilities
+async fn receive_hello_frame<S>(framed: &mut Framed<S>) -> Result<(u8, Features)>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let hello = framed.receive_payload().await?;
+    parse_hello(&hello)
+}
+
+fn parse_hello(hello: &[u8]) -> Result<(u8, Features)> {
+    match hello.strip_prefix(HELLO_MAGIC) {
+        Some(&[PROTOCOL_VERSION, value, a, b, c, d]) => {
+            let bits = u32::from_le_bytes([a, b, c, d]);
+            Ok((value, Features::from_bits(bits)))
+        }
+        Some(&[version, ..]) if version != PROTOCOL_VERSION => Err(invalid_hello(&format!(
+            "unsupported protocol version {}",
+            version
+        ))),
+        _ => Err(invalid_hello("peer did not send a hello")),
+    }
+}
+
+fn invalid_hello(reason: &str) -> NamedPipeError {
+    NamedPipeError::Io(std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        format!("Invalid handshake: {}", reason),
+    ))
+}
+
+/// Run the client side of the key derivation, switching `framed` to the connection key
+pub(crate) async fn derive_client_key<S>(
+    framed: &mut Framed<S>,
+    master_key: &[u8; 32],
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let client_salt = random_challenge();
+    send_salt(framed, &client_salt).await?;
+    let server_salt = receive_salt(framed).await?;
+    install_derived_key(framed, master_key, &client_salt, &server_salt)
+}
+
+/// Run the server side of the key derivation, switching `framed` to the connection key.
+/// Does nothing if the client was accepted without encryption.
+pub(crate) async fn derive_server_key<S>(
+    framed: &mut Framed<S>,
+    master_key: &[u8; 32],
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    if !framed.is_encrypted() {
+        return Ok(());
+    }
+    let client_salt = receive_salt(framed).await?;
+    let server_salt = random_challenge();
+    send_salt(framed, &server_salt).await?;
+    install_derived_key(framed, master_key, &client_salt, &server_salt)
+}
+
+async fn send_salt<S>(framed: &mut Framed<S>, salt: &[u8; SALT_LEN]) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    framed.send_payload(salt, Priority::High).await?;
+    framed.flush().await
+}
+
+async fn receive_salt<S>(framed: &mut Framed<S>) -> Result<[u8; SALT_LEN]>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let salt = framed.receive_payload().await?;
+    salt.try_into()
+        .map_err(|_| invalid_hello("key derivation salt has the wrong length"))
+}
+
+fn install_derived_key<S>(
+    framed: &mut Framed<S>,
+    master_key: &[u8; 32],
+    client_salt: &[u8; SALT_LEN],
+    server_salt: &[u8; SALT_LEN],
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let suite = framed
+        .cipher_suite()
+        .ok_or(NamedPipeError::EncryptionMismatch)?;
+    let salt = [client_salt.as_slice(), server_salt.as_slice()].concat();
+    let key = hkdf_sha256(master_key, &salt, DERIVED_KEY_INFO);
+    framed.set_cipher(Some(Cipher::new(suite, &key)));
+    framed.set_cipher_key(Some(&key));
+    Ok(())
+}
+
+/// HKDF-SHA256 (RFC 5869) producing a single 32-byte output block
+pub(crate) fn hkdf_sha256(ikm: &[u8], salt: &[u8], info: &[u8]) -> [u8; 32] {
+    let mut extract = new_mac(salt);
+    extract.update(ikm);
+    let prk = extract.finalize().into_bytes();
+
+    let mut expand = new_mac(&prk);
+    expand.update(info);
+    expand.update(&[1]);
+    expand.finalize().into_bytes().into()
+}
+
+/// Run the server side of the challenge-response exchange
+pub(crate) async fn authenticate_client<S>(framed: &mut Framed<S>, secret: &[u8]) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let challenge = random_challenge();
+    framed.send_bytes(&challenge).await?;
+    framed.flush().await?;
+
+    let reply = framed.receive_bytes().await?;
+    if reply.len() != TAG_LEN + CHALLENGE_LEN {
+        return Err(NamedPipeError::AuthFailed);
+    }
+    let (tag, client_challenge) = reply.split_at(TAG_LEN);
+    verify_tag(secret, CLIENT_ROLE, &challenge, client_challenge, tag)?;
+
+    framed
+        .send_bytes(&compute_tag(
+            secret,
+            SERVER_ROLE,
+            client_challenge,
+            &challenge,
+        ))
+        .await?;
+    framed.flush().await
+}
+
+/// Run the client side of the challenge-response exchange
+pub(crate) async fn authenticate_server<S>(framed: &mut Framed<S>, secret: &[u8]) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let challenge = framed.receive_bytes().await?;
+    if challenge.len() != CHALLENGE_LEN {
+        return Err(NamedPipeError::AuthFailed);
+    }
+
+    let client_challenge = random_challenge();
+    let mut reply = compute_tag(secret, CLIENT_ROLE, &challenge, &client_challenge);
+    reply.extend_from_slice(&client_challenge);
+    framed.send_bytes(&reply).await?;
+    framed.flush().await?;
+
+    // The server hangs up instead of answering if it rejected our tag
+    let tag = match framed.receive_bytes().await {
+        Ok(tag) => tag,
+        Err(e) if e.is_disconnect() => return Err(NamedPipeError::AuthFailed),
+        Err(e) => return Err(e),
+    };
+    verify_tag(secret, SERVER_ROLE, &client_challenge, &challenge, &tag)
+}
+
+fn random_challenge() -> [u8; CHALLENGE_LEN] {
+    let mut challenge = [0u8; CHALLENGE_LEN];
+    OsRng.fill_bytes(&mut challenge);
+    challenge
+}
+
+fn new_mac(secret: &[u8]) -> HmacSha256 {
+    HmacSha256::new_from_slice(secret).expect("HMAC accepts keys of any length")
+}
+
+/// Start a tag over a role label, the challenge being answered and the sender's own
+fn tag_mac(secret: &[u8], role: &[u8], peer_challenge: &[u8], own_challenge: &[u8]) -> HmacSha256 {
+    let mut mac = new_mac(secret);
+    mac.update(role);
+    mac.update(peer_challenge);
+    mac.update(own_challenge);
+    mac
+}
+
+fn compute_tag(secret: &[u8], role: &[u8], peer_challenge: &[u8], own_challenge: &[u8]) -> Vec<u8> {
+    tag_mac(secret, role, peer_challenge, own_challenge)
+        .finalize()
+        .into_bytes()
+        .to_vec()
+}
+
+/// Check `tag` against the expected HMAC in constant time
+fn verify_tag(
+    secret: &[u8],
+    role: &[u8],
+    peer_challenge: &[u8],
+    own_challenge: &[u8],
+    tag: &[u8],
+) -> Result<()> {
+    tag_mac(secret, role, peer_challenge, own_challenge)
+        .verify_slice(tag)
+        .map_err(|_| NamedPipeError::AuthFailed)
+}
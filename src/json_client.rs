@@ -0,0 +1,70 @@
+//! Typed request/response calls for services that speak plain JSON.
+//!
+//! `JsonClient` pairs one request type with one response type: every request is sent with
+//! `send_json` and answered by exactly one JSON frame, which is deserialized into the
+//! response type. For method dispatch, batches and error objects, use JSON-RPC instead.
+
+use crate::client::NamedPipeClientStruct;
+use crate::error::{NamedPipeError, Result};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::marker::PhantomData;
+use tokio::time::Duration;
+
+/// A client sending `Req` values and receiving one `Resp` for each.
+///
+/// Like `NamedPipeClientStruct::request`, there is no correlation between requests and
+/// responses: the server must answer every request with exactly one frame, in order.
+pub struct JsonClient<Req, Resp> {
+    client: NamedPipeClientStruct,
+    timeout: Duration,
+    _types: PhantomData<fn(&Req) -> Resp>,
+}
+
+impl<Req: Serialize, Resp: DeserializeOwned> JsonClient<Req, Resp> {
+    /// Wrap a client, which may be connected before or after wrapping. Requests time out
+    /// after the client's request timeout.
+    pub fn new(client: NamedPipeClientStruct) -> Self {
+        let timeout = client.effective_config().request_timeout;
+        Self {
+            client,
+            timeout,
+            _types: PhantomData,
+        }
+    }
+
+    /// Set how long `send_request` waits for its response
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// Get a mutable reference to the wrapped client, e.g. to connect it
+    pub fn client_mut(&mut self) -> &mut NamedPipeClientStruct {
+        &mut self.client
+    }
+
+    /// Consume the wrapper, returning the wrapped client
+    pub fn into_inner(self) -> NamedPipeClientStruct {
+        self.client
+    }
+
+    /// Send `request` as JSON and wait for the response.
+    ///
+    /// A response that is not a valid `Resp` fails with `NamedPipeError::Json`, keeping
+    /// serde's description of what did not match. If no response arrives in time, this
+    /// returns `NamedPipeError::Timeout` and disconnects the client, since a late response
+    /// would otherwise be taken as the answer to the next request.
+    pub async fn send_request(&mut self, request: &Req) -> Result<Resp> {
+        self.client.send_json(request).await?;
+        self.client.flush().await?;
+
+        let response = match tokio::time::timeout(self.timeout, self.client.receive_bytes()).await {
+            Ok(response) => response?,
+            Err(_) => {
+                self.client.disconnect();
+                return Err(NamedPipeError::Timeout);
+            }
+        };
+        serde_json::from_slice(&response).map_err(NamedPipeError::Json)
+    }
+}
@@ -0,0 +1,326 @@
+//! JSON-RPC 2.0 over named pipes.
+//!
+//! Each JSON-RPC message travels as one frame, serialized with the same helpers as
+//! `send_json`/`receive_json`. `JsonRpcClient` sends calls and notifications and matches
+//! responses to calls by id; `JsonRpcServer` dispatches incoming requests, including
+//! batches, to registered method handlers and answers them per the specification.
+
+use crate::client::NamedPipeClientStruct;
+use crate::error::{NamedPipeError, Result};
+use crate::server::NamedPipeConnection;
+use futures::future::BoxFuture;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fmt;
+
+/// Protocol version string carried by every message
+pub const JSONRPC_VERSION: &str = "2.0";
+
+/// Invalid JSON was received
+pub const PARSE_ERROR: i64 = -32700;
+/// The JSON sent is not a valid request object
+pub const INVALID_REQUEST: i64 = -32600;
+/// The method does not exist or is not available
+pub const METHOD_NOT_FOUND: i64 = -32601;
+/// Invalid method parameters
+pub const INVALID_PARAMS: i64 = -32602;
+/// Internal JSON-RPC error
+pub const INTERNAL_ERROR: i64 = -32603;
+
+/// Identifier correlating a request with its response
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Id {
+    /// A numeric id, e.g. `1`
+    Number(i64),
+    /// A string id, e.g. `"abc"`
+    String(String),
+}
+
+/// A call expecting a response, or a notification if `id` is `None`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Request {
+    /// Protocol version, always `"2.0"`
+    pub jsonrpc: String,
+    /// Name of the method to call
+    pub method: String,
+    /// Arguments of the call, by position or by name
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub params: Option<Value>,
+    /// Id the response echoes; `None` for a notification
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<Id>,
+}
+
+/// A request that expects no response
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Notification {
+    /// Protocol version, always `"2.0"`
+    pub jsonrpc: String,
+    /// Name of the method to call
+    pub method: String,
+    /// Arguments of the call, by position or by name
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub params: Option<Value>,
+}
+
+/// The answer to a request; `id` is `None` only if the request's id could not be read
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Response {
+    /// Protocol version, always `"2.0"`
+    pub jsonrpc: String,
+    /// The call's result or error
+    #[serde(flatten)]
+    pub payload: ResponsePayload,
+    /// Id of the request this answers
+    pub id: Option<Id>,
+}
+
+/// Either the result of a successful call or the error it failed with
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ResponsePayload {
+    /// The value the method returned
+    Result(Value),
+    /// Why the call failed
+    Error(ErrorObject),
+}
+
+/// A JSON-RPC error object
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ErrorObject {
+    /// Error code, one of the constants in this module for protocol errors
+    pub code: i64,
+    /// Short description of the error
+    pub message: String,
+    /// Additional details defined by the server
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data: Option<Value>,
+}
+
+impl ErrorObject {
+    /// Create an error object without additional data
+    pub fn new(code: i64, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+            data: None,
+        }
+    }
+
+    /// Create an `INVALID_PARAMS` error, for handlers rejecting their parameters
+    pub fn invalid_params(message: impl Into<String>) -> Self {
+        Self::new(INVALID_PARAMS, message)
+    }
+}
+
+impl fmt::Display for ErrorObject {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({})", self.message, self.code)
+    }
+}
+
+impl Response {
+    fn new(payload: ResponsePayload, id: Option<Id>) -> Self {
+        Self {
+            jsonrpc: JSONRPC_VERSION.to_string(),
+            payload,
+            id,
+        }
+    }
+}
+
+/// A JSON-RPC client over a named pipe connection.
+///
+/// Calls are sequential: `call` sends one request and waits for the response carrying
+/// its id, skipping anything else the server sends in between.
+pub struct JsonRpcClient {
+    client: NamedPipeClientStruct,
+    next_id: i64,
+}
+
+impl JsonRpcClient {
+    /// Wrap a client; it may be connected before or after wrapping
+    pub fn new(client: NamedPipeClientStruct) -> Self {
+        Self { client, next_id: 1 }
+    }
+
+    /// Get a mutable reference to the wrapped client, e.g. to connect it
+    pub fn client_mut(&mut self) -> &mut NamedPipeClientStruct {
+        &mut self.client
+    }
+
+    /// Consume the wrapper, returning the wrapped client
+    pub fn into_inner(self) -> NamedPipeClientStruct {
+        self.client
+    }
+
+    /// Call `method` and wait for its result.
+    /// An error response is returned as `NamedPipeError::JsonRpc`.
+    pub async fn call(&mut self, method: &str, params: Option<Value>) -> Result<Value> {
+        let id = Id::Number(self.next_id);
+        self.next_id += 1;
+
+        let request = Request {
+            jsonrpc: JSONRPC_VERSION.to_string(),
+            method: method.to_string(),
+            params,
+            id: Some(id.clone()),
+        };
+        self.client.send_json(&request).await?;
+        self.client.flush().await?;
+
+        loop {
+            let message: Value = self.client.receive_json().await?;
+            let Ok(response) = serde_json::from_value::<Response>(message) else {
+                continue;
+            };
+            if response.id.as_ref() != Some(&id) {
+                continue;
+            }
+
+            return match response.payload {
+                ResponsePayload::Result(result) => Ok(result),
+                ResponsePayload::Error(error) => Err(NamedPipeError::JsonRpc(error)),
+            };
+        }
+    }
+
+    /// Send a notification, which the server never answers
+    pub async fn notify(&mut self, method: &str, params: Option<Value>) -> Result<()> {
+        let notification = Notification {
+            jsonrpc: JSONRPC_VERSION.to_string(),
+            method: method.to_string(),
+            params,
+        };
+        self.client.send_json(&notification).await?;
+        self.client.flush().await
+    }
+}
+
+type MethodHandler = Box<
+    dyn Fn(Option<Value>) -> BoxFuture<'static, std::result::Result<Value, ErrorObject>>
+        + Send
+        + Sync,
+>;
+
+/// Dispatches JSON-RPC requests arriving on server connections to registered handlers.
+///
+/// Register methods, wrap the server in an `Arc` and call `serve` from the pipe server's
+/// connection handler.
+#[derive(Default)]
+pub struct JsonRpcServer {
+    methods: HashMap<String, MethodHandler>,
+}
+
+impl JsonRpcServer {
+    /// Create a server with no methods
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register the handler for `method`, replacing any previous one.
+    /// Returning `Err` answers the call with that error object.
+    pub fn register<F, Fut>(&mut self, method: &str, handler: F)
+    where
+        F: Fn(Option<Value>) -> Fut + Send + Sync + 'static,
+        Fut: std::future::Future<Output = std::result::Result<Value, ErrorObject>> + Send + 'static,
+    {
+        self.methods.insert(
+            method.to_string(),
+            Box::new(move |params| Box::pin(handler(params))),
+        );
+    }
+
+    /// Answer requests on `connection` until the client disconnects
+    pub async fn serve(&self, mut connection: NamedPipeConnection) -> Result<()> {
+        loop {
+            let message = match connection.receive_string().await {
+                Ok(message) => message,
+                Err(e) if e.is_disconnect() => return Ok(()),
+                Err(e) => return Err(e),
+            };
+
+            if let Some(reply) = self.handle_message(&message).await {
+                connection.send_json(&reply).await?;
+                connection.flush().await?;
+            }
+        }
+    }
+
+    /// Process one incoming message, returning the reply to send, if any
+    pub async fn handle_message(&self, message: &str) -> Option<Value> {
+        let value: Value = match serde_json::from_str(message) {
+            Ok(value) => value,
+            Err(e) => {
+                let error = ErrorObject::new(PARSE_ERROR, format!("Parse error: {}", e));
+                return Some(to_value(&Response::new(
+                    ResponsePayload::Error(error),
+                    None,
+                )));
+            }
+        };
+
+        match value {
+            Value::Array(batch) if batch.is_empty() => {
+                let error = ErrorObject::new(INVALID_REQUEST, "Empty batch");
+                Some(to_value(&Response::new(
+                    ResponsePayload::Error(error),
+                    None,
+                )))
+            }
+            Value::Array(batch) => {
+                let mut replies = Vec::new();
+                for item in batch {
+                    if let Some(reply) = self.handle_request(item).await {
+                        replies.push(to_value(&reply));
+                    }
+                }
+                (!replies.is_empty()).then_some(Value::Array(replies))
+            }
+            item => self
+                .handle_request(item)
+                .await
+                .map(|reply| to_value(&reply)),
+        }
+    }
+
+    /// Run a single request, returning its response unless it is a notification
+    async fn handle_request(&self, item: Value) -> Option<Response> {
+        // Answer even an invalid request with its id, if it has a readable one
+        let id = item
+            .get("id")
+            .and_then(|id| serde_json::from_value::<Id>(id.clone()).ok());
+        let request = match serde_json::from_value::<Request>(item) {
+            Ok(request) if request.jsonrpc == JSONRPC_VERSION => request,
+            _ => {
+                let error = ErrorObject::new(INVALID_REQUEST, "Invalid request");
+                return Some(Response::new(ResponsePayload::Error(error), id));
+            }
+        };
+
+        let outcome = match self.methods.get(&request.method) {
+            Some(handler) => handler(request.params).await,
+            None => Err(ErrorObject::new(
+                METHOD_NOT_FOUND,
+                format!("Method not found: {}", request.method),
+            )),
+        };
+
+        // Notifications are never answered, not even with errors
+        let id = request.id?;
+        let payload = match outcome {
+            Ok(result) => ResponsePayload::Result(result),
+            Err(error) => ResponsePayload::Error(error),
+        };
+        Some(Response::new(payload, Some(id)))
+    }
+}
+
+fn to_value(response: &Response) -> Value {
+    serde_json::to_value(response).unwrap_or_else(|e| {
+        let error = ErrorObject::new(INTERNAL_ERROR, format!("Internal error: {}", e));
+        serde_json::json!({ "jsonrpc": JSONRPC_VERSION, "error": error, "id": null })
+    })
+}
@@ -9,7 +9,10 @@
 //! - JSON serialization support
 //! - Connection management
 //! - Error handling
+//! - Optional shared-secret challenge-response authentication
 //! - Multiple connection support for servers
+//! - Resumable streaming of large payloads
+//! - In-memory connections for testing protocol logic without OS pipes
 //!
 //! # Examples
 //!
@@ -55,14 +58,73 @@
 // Include the compile-time generated default key
 include!(concat!(env!("OUT_DIR"), "/default_key.rs"));
 
+// Fail the build if the build script produced a placeholder key, by the same rule as
+// `cipher::check_key`; the declared type already pins its length to 32 bytes
+const _: () = {
+    let key = DEFAULT_ENCRYPTION_KEY;
+    let mut i = 1;
+    while i < key.len() && key[i] == key[0] {
+        i += 1;
+    }
+    assert!(
+        i < key.len(),
+        "the generated default encryption key is weak; check build.rs"
+    );
+};
+
+pub mod broadcast;
+pub mod cipher;
 pub mod client;
+pub mod endpoint;
 pub mod error;
+pub mod events;
+pub mod framing;
+mod handshake;
+pub mod json_client;
+pub mod jsonrpc;
+pub mod memory;
+pub mod message;
+pub mod multi;
+mod rate_limit;
+pub mod resilient;
 pub mod server;
+pub mod split;
+pub mod stream;
+#[cfg(feature = "test-util")]
+pub mod test_util;
+pub mod transport;
 pub mod utils;
 
 #[cfg(test)]
 mod tests;
 
-pub use client::NamedPipeClientStruct;
+pub use broadcast::Broadcaster;
+pub use cipher::{Cipher, CipherSuite, PipeCipher};
+pub use client::{ClientConfig, NamedPipeClientBuilder, NamedPipeClientStruct};
+pub use endpoint::PipeEndpoint;
 pub use error::{NamedPipeError, Result};
-pub use server::{NamedPipeConnection, NamedPipeServerStruct};
+pub use events::{EventStream, PipeEvent, DEFAULT_BYTE_MILESTONE, EVENT_STREAM_CAPACITY};
+pub use framing::{
+    decode_frame, ConnectionParams, Endian, Features, Frame, FrameFormat, Framed, Framing,
+    LengthWidth, Priority, DEFAULT_ACK_TIMEOUT, MAX_MESSAGE_MODE_PAYLOAD,
+};
+pub use json_client::JsonClient;
+pub use jsonrpc::{JsonRpcClient, JsonRpcServer};
+pub use memory::InMemoryConnection;
+pub use message::{
+    CloseCode, ContentType, Headers, Message, Progress, TextEncoding,
+    DEFAULT_COMPRESSION_THRESHOLD, MAX_HEADERS_LEN,
+};
+pub use multi::MultiClient;
+pub use resilient::{OutboxOverflow, ReconnectPolicy, ResilientClient};
+pub use server::{
+    AuditEvent, AuditOutcome, ConnectionInfo, ConnectionSnapshot, EncryptionPolicy,
+    NamedPipeConnection, NamedPipeServerStruct, PanicPolicy, ReadySignal, RejectionKind,
+    ServerConfig, ServerHandle, DEFAULT_PIPE_BUFFER_SIZE, MAX_PIPE_BUFFER_SIZE,
+};
+pub use split::{FramedReadHalf, FramedWriteHalf, WriteGuard};
+pub use stream::{STREAM_ACK_INTERVAL, STREAM_CHUNK_SIZE};
+pub use tokio::net::windows::named_pipe::PipeMode;
+pub use tokio_util::sync::CancellationToken;
+pub use transport::{accept_framed, connect_framed, NamedPipeTransport, TcpTransport, Transport};
+pub use utils::ConnectionId;
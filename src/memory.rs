@@ -0,0 +1,407 @@
+use crate::cipher::{check_key, Cipher, CipherSuite};
+use crate::error::{NamedPipeError, Result};
+use crate::framing::{Endian, FrameFormat, Framed, Priority};
+use crate::message::{CloseCode, ContentType, Headers, Message, Progress, TextEncoding};
+use crate::split::{FramedReadHalf, FramedWriteHalf};
+use futures::Sink;
+use tokio::io::{duplex, AsyncRead, AsyncWrite, DuplexStream};
+use tokio::time::Duration;
+
+/// Default capacity of each direction of an in-memory connection pair
+const DEFAULT_BUFFER_SIZE: usize = 64 * 1024;
+
+/// An in-memory connection with the same send/receive surface as a named pipe connection.
+///
+/// Useful for testing protocol logic without creating OS pipes. Connections are created in
+/// pairs; whatever one end sends, the other receives, using the same framing and encryption
+/// as the named pipe client and server.
+pub struct InMemoryConnection {
+    framed: Option<Framed<DuplexStream>>,
+}
+
+impl InMemoryConnection {
+    /// Create a connected pair of unencrypted in-memory connections
+    pub fn pair() -> (Self, Self) {
+        Self::pair_with_buffer_size(DEFAULT_BUFFER_SIZE)
+    }
+
+    /// Create a connected pair of unencrypted in-memory connections, buffering at most
+    /// `max_buf_size` bytes in each direction before writes wait for the reader
+    pub fn pair_with_buffer_size(max_buf_size: usize) -> (Self, Self) {
+        let (a, b) = duplex(max_buf_size);
+        (
+            Self::from_framed(Framed::new(a)),
+            Self::from_framed(Framed::new(b)),
+        )
+    }
+
+    /// Create a connected pair of encrypted in-memory connections.
+    /// If key is None, uses a secure compile-time generated default key.
+    /// If key is Some(key), uses the provided custom key, which is rejected with
+    /// `NamedPipeError::WeakKey` if it is obviously weak.
+    pub fn pair_encrypted(key: Option<&[u8; 32]>) -> Result<(Self, Self)> {
+        Self::pair_encrypted_with_suite(key, CipherSuite::default())
+    }
+
+    /// Create a connected pair of in-memory connections encrypted with `suite`.
+    /// If key is None, uses the compile-time generated default key.
+    pub fn pair_encrypted_with_suite(
+        key: Option<&[u8; 32]>,
+        suite: CipherSuite,
+    ) -> Result<(Self, Self)> {
+        let key_to_use = key.unwrap_or(&crate::DEFAULT_ENCRYPTION_KEY);
+        check_key(key_to_use)?;
+        let cipher = Cipher::new(suite, key_to_use);
+
+        let (a, b) = duplex(DEFAULT_BUFFER_SIZE);
+        let mut a = Framed::with_cipher(a, Some(cipher.clone()));
+        let mut b = Framed::with_cipher(b, Some(cipher));
+        a.set_cipher_key(Some(key_to_use));
+        b.set_cipher_key(Some(key_to_use));
+        Ok((Self::from_framed(a), Self::from_framed(b)))
+    }
+
+    fn from_framed(mut framed: Framed<DuplexStream>) -> Self {
+        // Tag frames like a connection that went through the handshake
+        framed.set_frame_tags(true);
+        Self {
+            framed: Some(framed),
+        }
+    }
+
+    fn framed(&mut self) -> Result<&mut Framed<DuplexStream>> {
+        self.framed.as_mut().ok_or(NamedPipeError::NotConnected)
+    }
+
+    /// Enable or disable flushing after every send (enabled by default)
+    pub fn set_auto_flush(&mut self, enabled: bool) {
+        if let Some(framed) = self.framed.as_mut() {
+            framed.set_auto_flush(enabled);
+        }
+    }
+
+    /// Flush buffered frames before every receive (enabled by default)
+    pub fn set_flush_before_receive(&mut self, enabled: bool) {
+        if let Some(framed) = self.framed.as_mut() {
+            framed.set_flush_before_receive(enabled);
+        }
+    }
+
+    /// Allow `send_bytes_acked` (disabled by default)
+    pub fn set_acks(&mut self, enabled: bool) {
+        if let Some(framed) = self.framed.as_mut() {
+            framed.set_acks(enabled);
+        }
+    }
+
+    /// Set how long `send_bytes_acked` waits for the peer's ack (30 seconds by default)
+    pub fn set_ack_timeout(&mut self, timeout: Duration) {
+        if let Some(framed) = self.framed.as_mut() {
+            framed.set_ack_timeout(timeout);
+        }
+    }
+
+    /// Append a CRC32C to every unencrypted frame and verify it on receive.
+    /// Has no effect on encrypted connections. The peer must use the same setting.
+    pub fn set_integrity_check(&mut self, enabled: bool) {
+        if let Some(framed) = self.framed.as_mut() {
+            framed.set_integrity_check(enabled);
+        }
+    }
+
+    /// Register another key under `id` for key rotation; see `Framed::add_key`
+    pub fn add_key(&mut self, id: u8, key: &[u8; 32]) -> Result<()> {
+        self.framed()?.add_key(id, key)
+    }
+
+    /// Seal frames sent to the peer with the key registered under `id`
+    pub fn set_active_key(&mut self, id: u8) -> Result<()> {
+        self.framed()?.set_active_key(id)
+    }
+
+    /// Forget the key registered under `id`; the active key cannot be removed
+    pub fn remove_key(&mut self, id: u8) -> Result<()> {
+        self.framed()?.remove_key(id)
+    }
+
+    /// Compress frames sent to the peer; see `Framed::set_compression`
+    pub fn set_compression(&mut self, enabled: bool) {
+        if let Some(framed) = self.framed.as_mut() {
+            framed.set_compression(enabled);
+        }
+    }
+
+    /// Only compress frames whose body is larger than `bytes`
+    pub fn set_compression_threshold(&mut self, bytes: usize) {
+        if let Some(framed) = self.framed.as_mut() {
+            framed.set_compression_threshold(bytes);
+        }
+    }
+
+    /// Reject replayed encrypted frames with `NamedPipeError::ReplayDetected`.
+    /// Has no effect on unencrypted connections. The peer must use the same setting.
+    pub fn enable_replay_protection(&mut self, enabled: bool) {
+        if let Some(framed) = self.framed.as_mut() {
+            framed.enable_replay_protection(enabled);
+        }
+    }
+
+    /// Set the byte order of the frame length prefix; the peer must use the same order
+    pub fn set_frame_endianness(&mut self, endian: Endian) {
+        if let Some(framed) = self.framed.as_mut() {
+            framed.set_frame_endianness(endian);
+        }
+    }
+
+    /// Set the layout of the frame length prefix; the peer must use the same layout
+    pub fn set_frame_format(&mut self, format: FrameFormat) {
+        if let Some(framed) = self.framed.as_mut() {
+            framed.set_frame_format(format);
+        }
+    }
+
+    /// Reject frames whose payload is larger than `max_size` bytes (unlimited by default)
+    pub fn set_max_message_size(&mut self, max_size: u64) {
+        if let Some(framed) = self.framed.as_mut() {
+            framed.set_max_message_size(max_size);
+        }
+    }
+
+    /// Set the chunk size of `send_stream` and the amount `receive_stream` collects before
+    /// writing; see `Framed::set_stream_chunk_size`
+    pub fn set_stream_chunk_size(&mut self, bytes: usize) {
+        if let Some(framed) = self.framed.as_mut() {
+            framed.set_stream_chunk_size(bytes);
+        }
+    }
+
+    /// Attach `headers` to every data frame sent to the peer; see
+    /// `Framed::set_default_headers`
+    pub fn set_default_headers(&mut self, headers: Headers) {
+        if let Some(framed) = self.framed.as_mut() {
+            framed.set_default_headers(headers);
+        }
+    }
+
+    /// Send raw bytes with `headers` on top of the default headers
+    pub async fn send_bytes_with_headers(&mut self, data: &[u8], headers: &Headers) -> Result<()> {
+        self.framed()?.send_bytes_with_headers(data, headers).await
+    }
+
+    /// Get the headers of the last frame received from the peer. Fails with
+    /// `NamedPipeError::NotConnected` once closed.
+    pub fn received_headers(&self) -> Result<&Headers> {
+        let framed = self.framed.as_ref().ok_or(NamedPipeError::NotConnected)?;
+        Ok(framed.received_headers())
+    }
+
+    /// Write any buffered frames to the peer
+    pub async fn flush(&mut self) -> Result<()> {
+        self.framed()?.flush().await
+    }
+
+    /// Flush any buffered frames and close the connection.
+    /// The peer's next receive returns `ConnectionClosed`.
+    pub async fn close(&mut self) -> Result<()> {
+        if let Some(framed) = self.framed.as_mut() {
+            framed.flush().await?;
+        }
+        self.framed = None;
+        Ok(())
+    }
+
+    /// Restart the sequence numbers of both ends, optionally switching to a derived key; see
+    /// `Framed::reset_crypto_state`
+    pub async fn reset_crypto_state(&mut self, rekey: bool) -> Result<()> {
+        self.framed()?.reset_crypto_state(rekey).await
+    }
+
+    /// Switch a plaintext connection to encryption with `key` under `suite` together with
+    /// the peer; see `Framed::upgrade_to_encrypted`
+    pub async fn upgrade_to_encrypted(&mut self, key: &[u8; 32], suite: CipherSuite) -> Result<()> {
+        self.framed()?.upgrade_to_encrypted(key, suite).await
+    }
+
+    /// Finish sending but keep receiving, e.g. after the last part of a request; see
+    /// `Framed::shutdown_write`. The peer's receives fail with
+    /// `NamedPipeError::PeerWriteShutdown` once it has read everything sent before.
+    pub async fn shutdown_write(&mut self) -> Result<()> {
+        self.framed()?.shutdown_write().await
+    }
+
+    /// Flush any buffered frames, send a close frame with `code` and close the connection.
+    /// The peer's next receive fails with `NamedPipeError::ClosedWithCode(code)`.
+    pub async fn close_with_code(&mut self, code: CloseCode) -> Result<()> {
+        if let Some(framed) = self.framed.as_mut() {
+            framed.send_close(code).await?;
+        }
+        self.framed = None;
+        Ok(())
+    }
+
+    /// Check if the connection is still open
+    pub fn is_connected(&self) -> bool {
+        self.framed.is_some()
+    }
+
+    /// Split into a read half and a write half for separate tasks; see `Framed::into_split`
+    pub fn into_split(
+        mut self,
+    ) -> Result<(FramedReadHalf<DuplexStream>, FramedWriteHalf<DuplexStream>)> {
+        let framed = self.framed.take().ok_or(NamedPipeError::NotConnected)?;
+        framed.into_split()
+    }
+
+    /// Number of bytes of frames buffered for the peer but not yet written; 0 when closed
+    pub fn pending_write_bytes(&self) -> usize {
+        self.framed.as_ref().map_or(0, Framed::buffered_len)
+    }
+
+    /// Send raw bytes to the peer
+    pub async fn send_bytes(&mut self, data: &[u8]) -> Result<()> {
+        self.framed()?.send_bytes(data).await
+    }
+
+    /// Send raw bytes to the peer and wait for it to confirm receipt; see
+    /// `Framed::send_bytes_acked`. Needs `set_acks(true)` on both ends.
+    pub async fn send_bytes_acked(&mut self, data: &[u8]) -> Result<()> {
+        self.framed()?.send_bytes_acked(data).await
+    }
+
+    /// Send raw bytes ahead of buffered frames of lower priority; see `Framed::send_bytes_priority`
+    pub async fn send_bytes_priority(&mut self, data: &[u8], priority: Priority) -> Result<()> {
+        self.framed()?.send_bytes_priority(data, priority).await
+    }
+
+    /// Send bytes already encoded as `content_type`, e.g. a bincode-serialized value
+    pub async fn send_with_content_type(
+        &mut self,
+        data: &[u8],
+        content_type: ContentType,
+    ) -> Result<()> {
+        self.framed()?
+            .send_with_content_type(data, content_type)
+            .await
+    }
+
+    /// Send an application-level error that the peer receives as `NamedPipeError::RemoteError`
+    pub async fn send_error(&mut self, code: u32, msg: &str) -> Result<()> {
+        self.framed()?.send_error(code, msg).await
+    }
+
+    /// Send everything `reader` yields to the peer as a resumable stream; see `Framed::send_stream`
+    pub async fn send_stream<R: AsyncRead + Unpin>(&mut self, reader: R) -> Result<u64> {
+        self.framed()?.send_stream(reader).await
+    }
+
+    /// Resume an interrupted stream from the offset the peer acknowledged
+    pub async fn resume_stream<R: AsyncRead + Unpin>(
+        &mut self,
+        from_offset: u64,
+        reader: R,
+    ) -> Result<u64> {
+        self.framed()?.resume_stream(from_offset, reader).await
+    }
+
+    /// Receive a stream from the peer into `writer`, returning its total length
+    pub async fn receive_stream<W: AsyncWrite + Unpin>(&mut self, writer: W) -> Result<u64> {
+        self.framed()?.receive_stream(writer).await
+    }
+
+    /// Receive the rest of an interrupted stream of which `from_offset` bytes were written
+    pub async fn resume_receive_stream<W: AsyncWrite + Unpin>(
+        &mut self,
+        from_offset: u64,
+        writer: W,
+    ) -> Result<u64> {
+        self.framed()?
+            .resume_receive_stream(from_offset, writer)
+            .await
+    }
+
+    /// Forward every frame from the peer into `sink` until it disconnects; see
+    /// `Framed::drain_to`
+    pub async fn drain_to<Si>(&mut self, sink: Si) -> Result<u64>
+    where
+        Si: Sink<Vec<u8>> + Unpin,
+        Si::Error: std::error::Error + Send + Sync + 'static,
+    {
+        self.framed()?.drain_to(sink).await
+    }
+
+    /// Receive raw bytes from the peer, reserving room for `hint` bytes up front; see
+    /// `Framed::receive_bytes_with_capacity`
+    pub async fn receive_bytes_with_capacity(&mut self, hint: usize) -> Result<Vec<u8>> {
+        self.framed()?.receive_bytes_with_capacity(hint).await
+    }
+
+    /// Send the next `len` bytes of `reader` as one frame without buffering it; see
+    /// `Framed::send_frame_from`
+    pub async fn send_frame_from<R: AsyncRead + Unpin>(
+        &mut self,
+        reader: R,
+        len: u64,
+    ) -> Result<()> {
+        self.framed()?.send_frame_from(reader, len).await
+    }
+
+    /// Receive one frame into `writer` without buffering it, returning its length; see
+    /// `Framed::receive_frame_into`
+    pub async fn receive_frame_into<W: AsyncWrite + Unpin>(&mut self, writer: W) -> Result<u64> {
+        self.framed()?.receive_frame_into(writer).await
+    }
+
+    /// Receive one message from the peer, decoded according to its content type
+    pub async fn receive_any(&mut self) -> Result<Message> {
+        self.framed()?.receive_any().await
+    }
+
+    /// Receive raw bytes from the peer
+    pub async fn receive_bytes(&mut self) -> Result<Vec<u8>> {
+        self.framed()?.receive_bytes().await
+    }
+
+    /// Receive the result of a long operation from the peer, calling `on_progress` with
+    /// each progress update sent before it; see `Framed::receive_with_progress`
+    pub async fn receive_with_progress<F: FnMut(Progress) + Send>(
+        &mut self,
+        on_progress: F,
+    ) -> Result<Vec<u8>> {
+        self.framed()?.receive_with_progress(on_progress).await
+    }
+
+    /// Send a progress update to the peer; see `Framed::send_progress`
+    pub async fn send_progress(&mut self, progress: &Progress) -> Result<()> {
+        self.framed()?.send_progress(progress).await
+    }
+
+    /// Send a string message to the peer
+    pub async fn send_string(&mut self, message: &str) -> Result<()> {
+        self.framed()?.send_string(message).await
+    }
+
+    /// Receive a string message from the peer
+    pub async fn receive_string(&mut self) -> Result<String> {
+        self.framed()?.receive_string().await
+    }
+
+    /// Send a string message to the peer in the given encoding
+    pub async fn send_string_as(&mut self, message: &str, encoding: TextEncoding) -> Result<()> {
+        self.framed()?.send_string_as(message, encoding).await
+    }
+
+    /// Receive a string message in the given encoding from the peer
+    pub async fn receive_string_as(&mut self, encoding: TextEncoding) -> Result<String> {
+        self.framed()?.receive_string_as(encoding).await
+    }
+
+    /// Send a JSON message to the peer
+    pub async fn send_json<T: serde::Serialize>(&mut self, message: &T) -> Result<()> {
+        self.framed()?.send_json(message).await
+    }
+
+    /// Receive a JSON message from the peer
+    pub async fn receive_json<T: serde::de::DeserializeOwned>(&mut self) -> Result<T> {
+        self.framed()?.receive_json().await
+    }
+}
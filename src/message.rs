@@ -0,0 +1,621 @@
+//! Self-describing frames.
+//!
+//! Once the handshake has run, every frame starts with a one-byte tag saying what its body
+//! holds, so a receiver can tell raw bytes, text and JSON apart without out-of-band
+//! knowledge. The tag sits inside the sealed message, so it is covered by encryption and
+//! checksums like the body. Connections without a handshake send untagged frames, which
+//! keeps the wire compatible with peers that only implement the length-prefix framing.
+//!
+//! Bit `0x40` of the tag flags a compressed body (see `Framed::set_compression`). The body
+//! is always compressed before the frame is sealed and decompressed after it is opened, so
+//! with encryption the order is compress-then-encrypt; it is not configurable, since
+//! ciphertext does not compress.
+//!
+//! Tag `0xFF` is reserved for error frames sent with `send_error`. Their body is a
+//! big-endian `u32` code followed by a UTF-8 message, and every `receive_*` method reports
+//! them as `NamedPipeError::RemoteError` instead of returning data. Tag `0xFE` is reserved
+//! for the rejection a server sends when authentication fails; its body is the UTF-8
+//! reason, reported as `NamedPipeError::RejectedByServer`.
+//!
+//! Bit `0x20` flags a frame sent with `send_bytes_acked`: its body starts with a
+//! little-endian `u64` sequence number, which the receiver answers with an ack frame. Ack
+//! frames use tag `0xFD` and carry only the sequence number; they never reach the
+//! application.
+//!
+//! Tag `0xFC` is reserved for the close frame sent by `close_with_code` just before a peer
+//! disconnects. Its body is the big-endian `u16` `CloseCode`, reported to the other side as
+//! `NamedPipeError::ClosedWithCode`.
+//!
+//! Bit `0x10` flags a body that starts with headers (see `Framed::set_default_headers`): a
+//! big-endian `u16` length of the header block, then for each header a `u8` name length,
+//! the name, a big-endian `u16` value length and the value, all UTF-8. The block is at most
+//! `MAX_HEADERS_LEN` bytes. It comes before the ack sequence number of an acked frame and
+//! is compressed along with the body.
+//!
+//! Tag `0xF9` is reserved for progress frames sent with `send_progress`: a `u8` that is 1
+//! when a total follows, a big-endian `u64` of completed units, the big-endian `u64` total
+//! if present and a UTF-8 message. Only `receive_with_progress` hands them out; every other
+//! receive skips them.
+//!
+//! Tag `0xF8` is reserved for the empty upgrade frame sent by `upgrade_to_encrypted`. It is
+//! the last plaintext frame its sender sends; every frame after it is encrypted.
+//!
+//! Tag `0xF7` is reserved for handle frames sent by `NamedPipeConnection::send_handle`. The
+//! body is the little-endian `u64` value of a handle already duplicated into the receiving
+//! process. Only `receive_handle` hands them out; every other receive fails on one and
+//! leaves it for `receive_handle`, so the handle is never lost.
+//!
+//! The low four bits of a data frame's tag hold its content type. Bit `0x80` is reserved
+//! for future flags: a frame using it, e.g. from a newer peer, fails with
+//! `NamedPipeError::UnknownFrameFlags` rather than being misread.
+
+use crate::error::{NamedPipeError, Result};
+use serde_json::Value;
+use std::collections::BTreeMap;
+
+/// Tag of an error frame sent with `send_error`; never a content type
+pub(crate) const ERROR_TAG: u8 = 0xFF;
+
+/// Tag of a rejection frame, sent by a server before it drops a client; never a content type
+pub(crate) const REJECT_TAG: u8 = 0xFE;
+
+/// Tag of an ack frame, confirming receipt of a frame that requested it; never a content type
+pub(crate) const ACK_TAG: u8 = 0xFD;
+
+/// Tag of a close frame, announcing why the peer disconnects; never a content type
+pub(crate) const CLOSE_TAG: u8 = 0xFC;
+
+/// Tag of a resync frame, resetting the crypto state of both ends; never a content type
+pub(crate) const RESYNC_TAG: u8 = 0xFB;
+
+/// Tag of a shutdown frame, announcing the peer sends no more data; never a content type
+pub(crate) const SHUTDOWN_TAG: u8 = 0xFA;
+
+/// Tag of a progress frame, reporting on a long operation before its result; never a content type
+pub(crate) const PROGRESS_TAG: u8 = 0xF9;
+
+/// Tag of an upgrade frame, announcing the peer encrypts from then on; never a content type
+pub(crate) const UPGRADE_TAG: u8 = 0xF8;
+
+/// Tag of a handle frame, carrying a handle duplicated into the receiver; never a content type
+pub(crate) const HANDLE_TAG: u8 = 0xF7;
+
+/// Length of the salt a resync frame carries to derive a new key
+pub(crate) const RESYNC_SALT_LEN: usize = 24;
+
+/// Longest rejection reason sent or reported, in bytes
+pub(crate) const MAX_REJECTION_REASON_LEN: usize = 256;
+
+/// Tag flag: the frame body is deflate-compressed
+pub(crate) const FLAG_COMPRESSED: u8 = 0x40;
+
+/// Tag flag: the body starts with a sequence number the receiver must acknowledge
+pub(crate) const FLAG_ACK_REQUESTED: u8 = 0x20;
+
+/// Tag flag: the body starts with a header block
+pub(crate) const FLAG_HEADERS: u8 = 0x10;
+
+/// Tag bits holding the content type of a data frame; the others are flags
+const CONTENT_TYPE_MASK: u8 = 0x0F;
+
+/// Every tag flag this version understands
+const KNOWN_FLAGS: u8 = FLAG_COMPRESSED | FLAG_ACK_REQUESTED | FLAG_HEADERS;
+
+/// Largest header block a frame may carry, in bytes, names and values included
+pub const MAX_HEADERS_LEN: usize = 4096;
+
+/// Key-value headers attached to a frame outside its payload, e.g. a W3C `traceparent`
+pub type Headers = BTreeMap<String, String>;
+
+/// Length of the sequence number of an acknowledged frame
+pub(crate) const ACK_SEQUENCE_LEN: usize = 8;
+
+/// Default size up to which bodies are never compressed; the saving could not pay for the
+/// effort. See `Framed::set_compression_threshold`.
+pub const DEFAULT_COMPRESSION_THRESHOLD: usize = 64;
+
+/// Deflate level used for compressed frames
+const COMPRESSION_LEVEL: u8 = 6;
+
+/// The encoding of a frame's body, announced by its tag
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ContentType {
+    /// Opaque bytes, as sent by `send_bytes`
+    Raw = 0,
+    /// A UTF-8 string, as sent by `send_string`
+    Utf8 = 1,
+    /// A JSON document, as sent by `send_json`
+    Json = 2,
+    /// Bytes encoded with bincode by the application
+    Bincode = 3,
+}
+
+impl ContentType {
+    /// Parse a frame tag
+    pub(crate) fn from_tag(tag: u8) -> Result<Self> {
+        match tag {
+            0 => Ok(ContentType::Raw),
+            1 => Ok(ContentType::Utf8),
+            2 => Ok(ContentType::Json),
+            3 => Ok(ContentType::Bincode),
+            _ => Err(NamedPipeError::Io(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Unknown frame tag {}", tag),
+            ))),
+        }
+    }
+}
+
+/// Split the tag of a data frame into its content type and flags, rejecting flags this
+/// version does not know
+pub(crate) fn split_tag(tag: u8) -> Result<(ContentType, u8)> {
+    let unknown = tag & !(CONTENT_TYPE_MASK | KNOWN_FLAGS);
+    if unknown != 0 {
+        return Err(NamedPipeError::UnknownFrameFlags(unknown));
+    }
+    Ok((
+        ContentType::from_tag(tag & CONTENT_TYPE_MASK)?,
+        tag & KNOWN_FLAGS,
+    ))
+}
+
+/// Whether `tag` marks a control frame, whose body is never compressed or handed out as data
+pub(crate) fn is_control_tag(tag: u8) -> bool {
+    matches!(
+        tag,
+        ERROR_TAG
+            | REJECT_TAG
+            | ACK_TAG
+            | CLOSE_TAG
+            | RESYNC_TAG
+            | SHUTDOWN_TAG
+            | PROGRESS_TAG
+            | UPGRADE_TAG
+            | HANDLE_TAG
+    )
+}
+
+/// Why a peer closed the connection on purpose, sent with `close_with_code`. The values
+/// follow WebSocket close codes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CloseCode {
+    /// The conversation is over (1000)
+    Normal,
+    /// The peer is shutting down or restarting (1001)
+    GoingAway,
+    /// The other side broke the application protocol (1002)
+    ProtocolError,
+    /// The other side's credentials or session expired (3000)
+    AuthExpired,
+    /// An application-defined code, conventionally 4000 to 4999
+    Other(u16),
+}
+
+impl CloseCode {
+    /// The code as sent on the wire
+    pub fn code(self) -> u16 {
+        match self {
+            CloseCode::Normal => 1000,
+            CloseCode::GoingAway => 1001,
+            CloseCode::ProtocolError => 1002,
+            CloseCode::AuthExpired => 3000,
+            CloseCode::Other(code) => code,
+        }
+    }
+
+    /// Parse a code from the wire; codes without a variant of their own become `Other`
+    pub fn from_code(code: u16) -> Self {
+        match code {
+            1000 => CloseCode::Normal,
+            1001 => CloseCode::GoingAway,
+            1002 => CloseCode::ProtocolError,
+            3000 => CloseCode::AuthExpired,
+            code => CloseCode::Other(code),
+        }
+    }
+}
+
+/// Turn the body of a close frame into the `ClosedWithCode` it reports
+pub(crate) fn decode_close(body: &[u8]) -> NamedPipeError {
+    match body.first_chunk::<2>() {
+        Some(&code) => {
+            NamedPipeError::ClosedWithCode(CloseCode::from_code(u16::from_be_bytes(code)))
+        }
+        None => NamedPipeError::Io(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "Close frame is too short",
+        )),
+    }
+}
+
+/// How both ends derive the new key of a rekeying resync
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct ResyncKey {
+    /// Id of the key the new one is derived from
+    pub base: u8,
+    /// Id the new key is registered under
+    pub id: u8,
+    pub salt: [u8; RESYNC_SALT_LEN],
+}
+
+/// The body of a resync frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Resync {
+    /// Reset the crypto state, rekeying if a key is given
+    Request(Option<ResyncKey>),
+    /// The peer has reset its crypto state
+    Reply,
+}
+
+impl Resync {
+    /// Encode as the body of a resync frame
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        match self {
+            Resync::Request(None) => vec![0],
+            Resync::Request(Some(key)) => {
+                let mut body = vec![1, key.base, key.id];
+                body.extend_from_slice(&key.salt);
+                body
+            }
+            Resync::Reply => vec![2],
+        }
+    }
+}
+
+/// Parse the body of a resync frame
+pub(crate) fn decode_resync(body: &[u8]) -> Result<Resync> {
+    match body {
+        [0] => Ok(Resync::Request(None)),
+        [1, base, id, salt @ ..] if salt.len() == RESYNC_SALT_LEN => {
+            let mut key = ResyncKey {
+                base: *base,
+                id: *id,
+                salt: [0; RESYNC_SALT_LEN],
+            };
+            key.salt.copy_from_slice(salt);
+            Ok(Resync::Request(Some(key)))
+        }
+        [2] => Ok(Resync::Reply),
+        _ => Err(NamedPipeError::Io(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "Malformed resync frame",
+        ))),
+    }
+}
+
+/// A progress update on a long operation, sent with `send_progress` before its result
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Progress {
+    /// Units of work done so far
+    pub completed: u64,
+    /// Units of work in total, if known
+    pub total: Option<u64>,
+    /// What the operation is doing, for display
+    pub message: String,
+}
+
+impl Progress {
+    /// Encode as the body of a progress frame
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let mut body = Vec::with_capacity(17 + self.message.len());
+        body.push(self.total.is_some() as u8);
+        body.extend_from_slice(&self.completed.to_be_bytes());
+        if let Some(total) = self.total {
+            body.extend_from_slice(&total.to_be_bytes());
+        }
+        body.extend_from_slice(self.message.as_bytes());
+        body
+    }
+}
+
+/// Parse the body of a progress frame
+pub(crate) fn decode_progress(body: &[u8]) -> Result<Progress> {
+    let malformed = || {
+        NamedPipeError::Io(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "Malformed progress frame",
+        ))
+    };
+    let (total, rest) = match body {
+        [0, rest @ ..] => (None, rest),
+        [1, rest @ ..] => (Some(()), rest),
+        _ => return Err(malformed()),
+    };
+    let (completed, rest) = rest.split_first_chunk::<8>().ok_or_else(malformed)?;
+    let (total, message) = match total {
+        Some(()) => {
+            let (total, rest) = rest.split_first_chunk::<8>().ok_or_else(malformed)?;
+            (Some(u64::from_be_bytes(*total)), rest)
+        }
+        None => (None, rest),
+    };
+    Ok(Progress {
+        completed: u64::from_be_bytes(*completed),
+        total,
+        message: String::from_utf8(message.to_vec()).map_err(|_| malformed())?,
+    })
+}
+
+/// The encoding of a string sent with `send_string_as`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum TextEncoding {
+    /// UTF-8, as used by `send_string`
+    #[default]
+    Utf8,
+    /// UTF-16 little-endian without a byte order mark, as used by Windows APIs
+    Utf16Le,
+}
+
+impl TextEncoding {
+    /// The content type frames of this encoding are tagged with. UTF-16 text is sent as
+    /// raw bytes, since the `Utf8` tag promises UTF-8.
+    pub(crate) fn content_type(self) -> ContentType {
+        match self {
+            TextEncoding::Utf8 => ContentType::Utf8,
+            TextEncoding::Utf16Le => ContentType::Raw,
+        }
+    }
+
+    /// Encode `text` as a frame body
+    pub(crate) fn encode(self, text: &str) -> Vec<u8> {
+        match self {
+            TextEncoding::Utf8 => text.as_bytes().to_vec(),
+            TextEncoding::Utf16Le => text.encode_utf16().flat_map(u16::to_le_bytes).collect(),
+        }
+    }
+
+    /// Decode a frame body, failing on bytes that are invalid in this encoding
+    pub(crate) fn decode(self, data: Vec<u8>) -> Result<String> {
+        match self {
+            TextEncoding::Utf8 => decode_utf8(data),
+            TextEncoding::Utf16Le => decode_utf16le(&data),
+        }
+    }
+}
+
+/// A received message, decoded according to its content type
+#[derive(Debug, Clone, PartialEq)]
+pub enum Message {
+    /// Opaque bytes; also every message of an untagged connection
+    Raw(Vec<u8>),
+    /// A UTF-8 string
+    Text(String),
+    /// A parsed JSON document
+    Json(Value),
+    /// Bincode-encoded bytes, left for the application to decode
+    Bincode(Vec<u8>),
+}
+
+impl Message {
+    /// Decode a frame body of the given content type
+    pub(crate) fn decode(content_type: Option<ContentType>, data: Vec<u8>) -> Result<Self> {
+        match content_type {
+            None | Some(ContentType::Raw) => Ok(Message::Raw(data)),
+            Some(ContentType::Utf8) => decode_utf8(data).map(Message::Text),
+            Some(ContentType::Json) => decode_json(&data).map(Message::Json),
+            Some(ContentType::Bincode) => Ok(Message::Bincode(data)),
+        }
+    }
+
+    /// The content type the message arrived with
+    pub fn content_type(&self) -> ContentType {
+        match self {
+            Message::Raw(_) => ContentType::Raw,
+            Message::Text(_) => ContentType::Utf8,
+            Message::Json(_) => ContentType::Json,
+            Message::Bincode(_) => ContentType::Bincode,
+        }
+    }
+}
+
+/// Fail unless a frame of `actual` type can be read as `expected`; untagged frames always can
+pub(crate) fn expect_content_type(
+    actual: Option<ContentType>,
+    expected: ContentType,
+    also_accepted: &[ContentType],
+) -> Result<()> {
+    match actual {
+        Some(actual) if actual != expected && !also_accepted.contains(&actual) => {
+            Err(NamedPipeError::ContentTypeMismatch { expected, actual })
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Encode an error frame body: the code as a big-endian `u32`, then the UTF-8 message
+pub(crate) fn encode_error(code: u32, message: &str) -> Vec<u8> {
+    let mut body = Vec::with_capacity(4 + message.len());
+    body.extend_from_slice(&code.to_be_bytes());
+    body.extend_from_slice(message.as_bytes());
+    body
+}
+
+/// Turn the body of an error frame into the `RemoteError` it reports
+pub(crate) fn decode_error(body: Vec<u8>) -> NamedPipeError {
+    if body.len() < 4 {
+        return NamedPipeError::Io(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "Error frame is too short",
+        ));
+    }
+    let code = u32::from_be_bytes([body[0], body[1], body[2], body[3]]);
+    let msg = String::from_utf8_lossy(&body[4..]).into_owned();
+    NamedPipeError::RemoteError { code, msg }
+}
+
+/// Cut a rejection reason down to `MAX_REJECTION_REASON_LEN` bytes, at a character boundary
+pub(crate) fn bounded_reason(reason: &str) -> &str {
+    let mut end = reason.len().min(MAX_REJECTION_REASON_LEN);
+    while !reason.is_char_boundary(end) {
+        end -= 1;
+    }
+    &reason[..end]
+}
+
+/// Turn the body of a rejection into the `RejectedByServer` it reports
+pub(crate) fn decode_rejection(body: &[u8]) -> NamedPipeError {
+    let body = &body[..body.len().min(MAX_REJECTION_REASON_LEN)];
+    NamedPipeError::RejectedByServer {
+        reason: String::from_utf8_lossy(body).into_owned(),
+    }
+}
+
+/// Read the sequence number an ack frame or an ack-requesting body starts with
+pub(crate) fn decode_ack_sequence(body: &[u8]) -> Result<u64> {
+    match body.first_chunk::<ACK_SEQUENCE_LEN>() {
+        Some(&sequence) => Ok(u64::from_le_bytes(sequence)),
+        None => Err(NamedPipeError::Io(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "Frame is missing its ack sequence number",
+        ))),
+    }
+}
+
+/// Read the handle value from the body of a handle frame
+pub(crate) fn decode_handle(body: &[u8]) -> Result<u64> {
+    match <[u8; 8]>::try_from(body) {
+        Ok(value) => Ok(u64::from_le_bytes(value)),
+        Err(_) => Err(NamedPipeError::Io(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "Malformed handle frame",
+        ))),
+    }
+}
+
+/// Prefix `body` with a header block holding `headers`
+pub(crate) fn encode_headers(headers: &Headers, body: &[u8]) -> Result<Vec<u8>> {
+    let mut block = Vec::new();
+    for (name, value) in headers {
+        let name_len = u8::try_from(name.len()).map_err(|_| {
+            NamedPipeError::Io(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "Header name of {} bytes is longer than 255 bytes",
+                    name.len()
+                ),
+            ))
+        })?;
+        block.push(name_len);
+        block.extend_from_slice(name.as_bytes());
+        // A value too long for its length field fails the size check below
+        let value_len = u16::try_from(value.len()).unwrap_or(u16::MAX);
+        block.extend_from_slice(&value_len.to_be_bytes());
+        block.extend_from_slice(value.as_bytes());
+    }
+    if block.len() > MAX_HEADERS_LEN {
+        return Err(NamedPipeError::HeadersTooLarge {
+            size: block.len(),
+            limit: MAX_HEADERS_LEN,
+        });
+    }
+
+    let mut message = Vec::with_capacity(2 + block.len() + body.len());
+    message.extend_from_slice(&(block.len() as u16).to_be_bytes());
+    message.extend_from_slice(&block);
+    message.extend_from_slice(body);
+    Ok(message)
+}
+
+/// Remove the header block from the front of `message` and decode it
+pub(crate) fn decode_headers(message: &mut Vec<u8>) -> Result<Headers> {
+    let Some(&block_len) = message.first_chunk::<2>() else {
+        return Err(invalid_headers("block is missing its length"));
+    };
+    let block_len = u16::from_be_bytes(block_len) as usize;
+    if block_len > MAX_HEADERS_LEN {
+        return Err(NamedPipeError::HeadersTooLarge {
+            size: block_len,
+            limit: MAX_HEADERS_LEN,
+        });
+    }
+    let mut block = message
+        .get(2..2 + block_len)
+        .ok_or_else(|| invalid_headers("block is longer than the frame"))?;
+
+    let mut headers = Headers::new();
+    while let Some((&name_len, rest)) = block.split_first() {
+        let (name, rest) = split_header_field(rest, name_len as usize)?;
+        let Some((&value_len, rest)) = rest.split_first_chunk::<2>() else {
+            return Err(invalid_headers("value is missing its length"));
+        };
+        let (value, rest) = split_header_field(rest, u16::from_be_bytes(value_len) as usize)?;
+        headers.insert(name, value);
+        block = rest;
+    }
+    message.drain(..2 + block_len);
+    Ok(headers)
+}
+
+/// Split a UTF-8 header name or value of `len` bytes off the front of `bytes`
+fn split_header_field(bytes: &[u8], len: usize) -> Result<(String, &[u8])> {
+    if bytes.len() < len {
+        return Err(invalid_headers("field is longer than the block"));
+    }
+    let (field, rest) = bytes.split_at(len);
+    let field = std::str::from_utf8(field).map_err(|_| invalid_headers("field is not UTF-8"))?;
+    Ok((field.to_string(), rest))
+}
+
+fn invalid_headers(reason: &str) -> NamedPipeError {
+    NamedPipeError::Io(std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        format!("Invalid frame headers: {}", reason),
+    ))
+}
+
+/// Compress `data` if it is larger than `threshold` bytes and compressing makes it smaller,
+/// returning the compressed body
+pub(crate) fn compress(data: &[u8], threshold: usize) -> Option<Vec<u8>> {
+    if data.len() <= threshold {
+        return None;
+    }
+    let compressed = miniz_oxide::deflate::compress_to_vec(data, COMPRESSION_LEVEL);
+    (compressed.len() < data.len()).then_some(compressed)
+}
+
+/// Decompress a frame body, refusing to inflate it beyond `limit` bytes
+pub(crate) fn decompress(data: &[u8], limit: u64) -> Result<Vec<u8>> {
+    let limit = usize::try_from(limit).unwrap_or(usize::MAX);
+    miniz_oxide::inflate::decompress_to_vec_with_limit(data, limit).map_err(|e| {
+        NamedPipeError::Io(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("Decompression failed: {}", e),
+        ))
+    })
+}
+
+pub(crate) fn decode_utf8(data: Vec<u8>) -> Result<String> {
+    String::from_utf8(data).map_err(|e| {
+        NamedPipeError::Io(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("Invalid UTF-8 string: {}", e),
+        ))
+    })
+}
+
+fn decode_utf16le(data: &[u8]) -> Result<String> {
+    let invalid = |reason: String| {
+        NamedPipeError::Io(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("Invalid UTF-16LE string: {}", reason),
+        ))
+    };
+    if !data.len().is_multiple_of(2) {
+        return Err(invalid(format!("odd length {}", data.len())));
+    }
+    let units = data
+        .chunks_exact(2)
+        .map(|unit| u16::from_le_bytes([unit[0], unit[1]]));
+    char::decode_utf16(units)
+        .collect::<std::result::Result<String, _>>()
+        .map_err(|e| invalid(e.to_string()))
+}
+
+pub(crate) fn decode_json<T: serde::de::DeserializeOwned>(data: &[u8]) -> Result<T> {
+    serde_json::from_slice(data).map_err(|e| {
+        NamedPipeError::Io(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("JSON deserialization failed: {}", e),
+        ))
+    })
+}
@@ -0,0 +1,114 @@
+//! Redundant requests to several servers, taking whichever answers first.
+//!
+//! `MultiClient` holds one `NamedPipeClientStruct` per server. `race_request` sends the
+//! same request to all of them at once and returns the first successful response. Servers
+//! that are down or fail only count once every server has failed; a client that is not
+//! connected is connected as part of the race, so servers that come back are used again.
+
+use crate::client::NamedPipeClientStruct;
+use crate::error::{NamedPipeError, Result};
+use futures::stream::{FuturesUnordered, StreamExt};
+
+/// Clients for several servers offering the same service
+pub struct MultiClient {
+    clients: Vec<NamedPipeClientStruct>,
+}
+
+impl MultiClient {
+    /// Create a client with default settings for each of `pipe_names`
+    pub fn new<I, S>(pipe_names: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let clients = pipe_names
+            .into_iter()
+            .map(|name| NamedPipeClientStruct::new(name.as_ref()))
+            .collect();
+        Self { clients }
+    }
+
+    /// Use clients configured beforehand, e.g. with encryption; they may already be connected
+    pub fn from_clients(clients: Vec<NamedPipeClientStruct>) -> Self {
+        Self { clients }
+    }
+
+    /// The clients, in the order they were given
+    pub fn clients(&self) -> &[NamedPipeClientStruct] {
+        &self.clients
+    }
+
+    /// Connect every client that is not connected yet, returning how many are connected.
+    /// Fails with `NamedPipeError::AllFailed` only if none is.
+    pub async fn connect(&mut self) -> Result<usize> {
+        let attempts = self
+            .clients
+            .iter_mut()
+            .filter(|client| !client.is_connected())
+            .map(NamedPipeClientStruct::connect);
+        let errors: Vec<_> = futures::future::join_all(attempts)
+            .await
+            .into_iter()
+            .filter_map(Result::err)
+            .collect();
+
+        match self.clients.iter().filter(|c| c.is_connected()).count() {
+            0 => Err(NamedPipeError::AllFailed(errors)),
+            connected => Ok(connected),
+        }
+    }
+
+    /// Send `data` to every server with `NamedPipeClientStruct::request`, connecting clients
+    /// that are not connected, and return the first successful response.
+    ///
+    /// The other requests are cancelled once a response arrives. Their servers may still
+    /// answer, so those clients are disconnected rather than risk taking a late response
+    /// as the answer to the next request; the next race connects them again. Fails with
+    /// `NamedPipeError::AllFailed`, holding every server's error, if no server answers.
+    pub async fn race_request(&mut self, data: &[u8]) -> Result<Vec<u8>> {
+        if self.clients.is_empty() {
+            return Err(NamedPipeError::InvalidConfig(
+                "racing a request needs at least one server".to_string(),
+            ));
+        }
+
+        let mut answer = None;
+        let mut errors = Vec::new();
+        {
+            let mut requests: FuturesUnordered<_> = self
+                .clients
+                .iter_mut()
+                .enumerate()
+                .map(|(index, client)| async move { (index, ask(client, data).await) })
+                .collect();
+            while let Some((index, result)) = requests.next().await {
+                match result {
+                    Ok(response) => {
+                        answer = Some((index, response));
+                        break;
+                    }
+                    Err(e) => errors.push(e),
+                }
+            }
+        }
+
+        let winner = answer.as_ref().map(|&(index, _)| index);
+        for (index, client) in self.clients.iter_mut().enumerate() {
+            if Some(index) != winner {
+                client.disconnect();
+            }
+        }
+        match answer {
+            Some((_, response)) => Ok(response),
+            None => Err(NamedPipeError::AllFailed(errors)),
+        }
+    }
+}
+
+/// Connect `client` if needed and send it the request
+async fn ask(client: &mut NamedPipeClientStruct, data: &[u8]) -> Result<Vec<u8>> {
+    if !client.is_connected() {
+        client.connect().await?;
+    }
+    client.request(data).await
+}
@@ -0,0 +1,42 @@
+use tokio::time::{sleep, Duration, Instant};
+
+/// A token-bucket rate limiter measured in bytes per second.
+///
+/// The bucket holds at most one second's worth of tokens. Transfers larger than the
+/// available tokens drive the balance negative and the caller is delayed until it is
+/// repaid, so traffic is smoothed rather than rejected.
+#[derive(Debug, Clone)]
+pub(crate) struct RateLimiter {
+    bytes_per_sec: u64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl RateLimiter {
+    /// Create a limiter allowing `bytes_per_sec` bytes per second, starting with a full bucket.
+    /// `bytes_per_sec` must not be 0; `Framed::set_rate_limit` treats 0 as no limit instead.
+    pub(crate) fn new(bytes_per_sec: u64) -> Self {
+        debug_assert!(bytes_per_sec > 0, "a zero rate would wait forever");
+        Self {
+            bytes_per_sec,
+            tokens: bytes_per_sec as f64,
+            last_refill: Instant::now(),
+        }
+    }
+
+    /// Account for `bytes` transferred, waiting as long as needed to stay within the rate
+    pub(crate) async fn acquire(&mut self, bytes: usize) {
+        let rate = self.bytes_per_sec as f64;
+
+        // Refill based on the time elapsed since the last transfer
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(rate);
+        self.last_refill = now;
+
+        self.tokens -= bytes as f64;
+        if self.tokens < 0.0 {
+            sleep(Duration::from_secs_f64(-self.tokens / rate)).await;
+        }
+    }
+}
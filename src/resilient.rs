@@ -0,0 +1,278 @@
+use crate::client::NamedPipeClientStruct;
+use crate::error::{NamedPipeError, Result};
+use std::collections::VecDeque;
+use tokio::time::{sleep, Duration};
+
+/// Default number of times an operation is retried after the first attempt
+const DEFAULT_MAX_RETRIES: u32 = 3;
+
+/// Default delay before each reconnect attempt
+const DEFAULT_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// How a `ResilientClient` retries failed operations
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReconnectPolicy {
+    /// How many times a failed operation is retried after the first attempt
+    pub max_retries: u32,
+    /// Delay before each reconnect attempt
+    pub retry_delay: Duration,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: DEFAULT_MAX_RETRIES,
+            retry_delay: DEFAULT_RETRY_DELAY,
+        }
+    }
+}
+
+/// Which message an outbox gives up when it is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OutboxOverflow {
+    /// Drop the oldest queued message to make room (the default)
+    #[default]
+    DropOldest,
+    /// Drop the message being queued
+    DropNewest,
+}
+
+/// Callback told about messages dropped from a full outbox
+type DropCallback = Box<dyn FnMut(Vec<u8>) + Send>;
+
+/// A client that transparently reconnects and retries operations that fail with an IO error.
+///
+/// Every reconnect goes through `NamedPipeClientStruct::connect`, so path enforcement and
+/// authentication run again on each new connection.
+///
+/// # Delivery semantics
+///
+/// A failed send may still have reached the server before the error surfaced, so retrying
+/// it can deliver the message twice. The retrying methods are therefore only appropriate
+/// for idempotent messages. Use the `_once` variants for anything that must not be
+/// repeated; they reconnect if needed before sending but never resend.
+///
+/// # Outbox
+///
+/// With `set_outbox_capacity`, `send_bytes_queued` becomes store-and-forward: a message
+/// that cannot be sent because the server is unreachable, or whose send fails midway, is
+/// queued instead of failing, and the queue is sent in order before anything else once a
+/// connection is made again. This is at-least-once delivery: a message that failed midway
+/// may already have reached the server and is then delivered twice. Messages dropped
+/// because the outbox is full, or still queued when the client is dropped, are never
+/// delivered, so without an outbox or beyond its capacity delivery is at-most-once.
+pub struct ResilientClient {
+    client: NamedPipeClientStruct,
+    max_retries: u32,
+    retry_delay: Duration,
+    outbox: VecDeque<Vec<u8>>,
+    outbox_capacity: usize,
+    outbox_overflow: OutboxOverflow,
+    on_outbox_drop: Option<DropCallback>,
+}
+
+impl ResilientClient {
+    /// Wrap a configured (not necessarily connected) client
+    pub fn new(client: NamedPipeClientStruct) -> Self {
+        Self::with_policy(client, ReconnectPolicy::default())
+    }
+
+    /// Wrap a configured client, retrying according to `policy`
+    pub fn with_policy(client: NamedPipeClientStruct, policy: ReconnectPolicy) -> Self {
+        Self {
+            client,
+            max_retries: policy.max_retries,
+            retry_delay: policy.retry_delay,
+            outbox: VecDeque::new(),
+            outbox_capacity: 0,
+            outbox_overflow: OutboxOverflow::default(),
+            on_outbox_drop: None,
+        }
+    }
+
+    /// Set how many times a failed operation is retried after the first attempt
+    pub fn set_max_retries(&mut self, max_retries: u32) {
+        self.max_retries = max_retries;
+    }
+
+    /// Set the delay before each reconnect attempt
+    pub fn set_retry_delay(&mut self, delay: Duration) {
+        self.retry_delay = delay;
+    }
+
+    /// Queue up to `capacity` messages of `send_bytes_queued` while the server is
+    /// unreachable (0, the default, disables the outbox). See the type-level docs.
+    pub fn set_outbox_capacity(&mut self, capacity: usize) {
+        self.outbox_capacity = capacity;
+        while self.outbox.len() > capacity {
+            if let Some(message) = self.outbox.pop_front() {
+                self.dropped(message);
+            }
+        }
+    }
+
+    /// Choose which message is dropped when the outbox is full
+    pub fn set_outbox_overflow(&mut self, overflow: OutboxOverflow) {
+        self.outbox_overflow = overflow;
+    }
+
+    /// Call `callback` with every message dropped from the outbox without being sent
+    pub fn on_outbox_drop<F>(&mut self, callback: F)
+    where
+        F: FnMut(Vec<u8>) + Send + 'static,
+    {
+        self.on_outbox_drop = Some(Box::new(callback));
+    }
+
+    /// Number of messages waiting in the outbox
+    pub fn outbox_len(&self) -> usize {
+        self.outbox.len()
+    }
+
+    /// Get a reference to the wrapped client
+    pub fn client(&self) -> &NamedPipeClientStruct {
+        &self.client
+    }
+
+    /// Get a mutable reference to the wrapped client
+    pub fn client_mut(&mut self) -> &mut NamedPipeClientStruct {
+        &mut self.client
+    }
+
+    /// Consume the wrapper, returning the wrapped client
+    pub fn into_inner(self) -> NamedPipeClientStruct {
+        self.client
+    }
+
+    /// Connect to the server, retrying on failure
+    pub async fn connect(&mut self) -> Result<()> {
+        self.with_retry(self.max_retries, async |_| Ok(())).await
+    }
+
+    /// Send raw bytes, reconnecting and resending on failure.
+    /// Only use this for idempotent messages; see the type-level docs.
+    pub async fn send_bytes(&mut self, data: &[u8]) -> Result<()> {
+        self.with_retry(self.max_retries, async |client| {
+            client.send_bytes(data).await
+        })
+        .await
+    }
+
+    /// Send raw bytes at most once, reconnecting first if the client is disconnected
+    pub async fn send_bytes_once(&mut self, data: &[u8]) -> Result<()> {
+        self.with_retry(0, async |client| client.send_bytes(data).await)
+            .await
+    }
+
+    /// Send raw bytes, queueing them in the outbox if the server cannot be reached.
+    ///
+    /// Returns `Ok` once the message is sent or queued; other errors are returned as they
+    /// are. Without an outbox this behaves like `send_bytes_once`.
+    pub async fn send_bytes_queued(&mut self, data: &[u8]) -> Result<()> {
+        match self.send_bytes_once(data).await {
+            Err(e) if self.outbox_capacity > 0 && is_retryable(&e) => {
+                self.enqueue(data.to_vec());
+                Ok(())
+            }
+            result => result,
+        }
+    }
+
+    /// Receive raw bytes, reconnecting and waiting on the new connection on failure.
+    /// A message that was in flight when the connection broke is lost.
+    pub async fn receive_bytes(&mut self) -> Result<Vec<u8>> {
+        self.with_retry(self.max_retries, async |client| {
+            client.receive_bytes().await
+        })
+        .await
+    }
+
+    /// Send a request and wait for its response, repeating the whole exchange on failure.
+    /// Only use this for idempotent requests; see the type-level docs.
+    pub async fn request(&mut self, data: &[u8]) -> Result<Vec<u8>> {
+        self.with_retry(self.max_retries, async |client| client.request(data).await)
+            .await
+    }
+
+    /// Send a request and wait for its response without ever resending it
+    pub async fn request_once(&mut self, data: &[u8]) -> Result<Vec<u8>> {
+        self.with_retry(0, async |client| client.request(data).await)
+            .await
+    }
+
+    /// Run `op` on a connected client, reconnecting and rerunning it up to `max_retries`
+    /// times while it fails with a retryable error
+    async fn with_retry<T>(
+        &mut self,
+        max_retries: u32,
+        mut op: impl AsyncFnMut(&mut NamedPipeClientStruct) -> Result<T>,
+    ) -> Result<T> {
+        let mut attempt = 0;
+        loop {
+            let result = match self.prepare().await {
+                Ok(()) => op(&mut self.client).await,
+                Err(e) => Err(e),
+            };
+
+            match result {
+                Err(e) if is_retryable(&e) && attempt < max_retries => {
+                    attempt += 1;
+                    self.client.disconnect();
+                    sleep(self.retry_delay).await;
+                }
+                Err(e) if is_retryable(&e) => {
+                    self.client.disconnect();
+                    return Err(e);
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Connect if needed, then send everything waiting in the outbox
+    async fn prepare(&mut self) -> Result<()> {
+        if !self.client.is_connected() {
+            self.client.connect().await?;
+        }
+        while let Some(message) = self.outbox.front() {
+            self.client.send_bytes(message).await?;
+            self.outbox.pop_front();
+        }
+        Ok(())
+    }
+
+    /// Queue a message, dropping one according to the overflow policy if the outbox is full
+    fn enqueue(&mut self, message: Vec<u8>) {
+        if self.outbox.len() < self.outbox_capacity {
+            self.outbox.push_back(message);
+            return;
+        }
+        let dropped = match self.outbox_overflow {
+            OutboxOverflow::DropOldest => {
+                self.outbox.push_back(message);
+                self.outbox.pop_front()
+            }
+            OutboxOverflow::DropNewest => Some(message),
+        };
+        if let Some(dropped) = dropped {
+            self.dropped(dropped);
+        }
+    }
+
+    fn dropped(&mut self, message: Vec<u8>) {
+        if let Some(callback) = self.on_outbox_drop.as_mut() {
+            callback(message);
+        }
+    }
+}
+
+/// Whether an error means the connection is broken and reconnecting may help
+fn is_retryable(e: &NamedPipeError) -> bool {
+    match e {
+        NamedPipeError::Io(e) => e.kind() != std::io::ErrorKind::InvalidData,
+        NamedPipeError::ConnectionClosed
+        | NamedPipeError::Disconnected
+        | NamedPipeError::NotConnected => true,
+        _ => false,
+    }
+}
@@ -1,168 +1,1070 @@
+use crate::cipher::{check_key, Cipher, CipherSuite, PipeCipher};
 use crate::error::{NamedPipeError, Result};
+use crate::events::{EventSender, EventStream, PipeEvent, DEFAULT_BYTE_MILESTONE};
+use crate::framing::{
+    ConnectionParams, Endian, Features, FrameFormat, Framed, Framing, Priority, SharedByteCounts,
+};
+use crate::handshake::{accept_hello, authenticate_client, derive_server_key, reject_hello};
+use crate::message::{
+    CloseCode, ContentType, Headers, Message, Progress, TextEncoding, DEFAULT_COMPRESSION_THRESHOLD,
+};
+use crate::split::{FramedReadHalf, FramedWriteHalf};
 use crate::utils::{
-    decrypt_message, encrypt_message, format_pipe_name, get_client_pid, verify_same_path,
+    close_handle_in_process, duplicate_handle_to_process, format_pipe_name, get_client_pid,
+    get_pipe_mode, get_process_path, next_connection_id, verify_same_path, ConnectionId,
 };
-use chacha20poly1305::{aead::KeyInit, ChaCha20Poly1305, Key};
-use std::os::windows::prelude::AsRawHandle;
+use futures::stream::{self, Stream, StreamExt};
+use futures::{FutureExt, Sink};
+use std::any::{Any, TypeId};
+use std::collections::{BTreeMap, HashMap};
+use std::os::windows::prelude::{AsRawHandle, RawHandle};
+use std::panic::AssertUnwindSafe;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::windows::named_pipe::{NamedPipeServer, ServerOptions};
-use tokio::sync::{broadcast, Mutex};
+use std::time::SystemTime;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::windows::named_pipe::{NamedPipeServer, PipeMode, ServerOptions};
+use tokio::sync::{broadcast, mpsc, watch, Mutex};
 use tokio::task::JoinHandle;
+use tokio::time::Duration;
+use tokio_util::sync::CancellationToken;
+use windows::Win32::Foundation::{ERROR_ACCESS_DENIED, ERROR_ALREADY_EXISTS, HANDLE};
+use windows::Win32::Security::RevertToSelf;
+use windows::Win32::System::Pipes::ImpersonateNamedPipeClient;
+
+/// Number of set-up connections `incoming` buffers before the consumer takes them
+const INCOMING_QUEUE_SIZE: usize = 16;
+
+/// Default size of each pipe instance's input and output buffers
+pub const DEFAULT_PIPE_BUFFER_SIZE: u32 = 65536;
+
+/// Largest buffer size `set_buffer_sizes` accepts. Pipe buffers are charged against the
+/// nonpaged pool, so Windows fails or silently caps requests far beyond this.
+pub const MAX_PIPE_BUFFER_SIZE: u32 = 16 * 1024 * 1024;
+
+/// Whether a server accepts encrypted and plaintext clients, decided per connection during
+/// the handshake
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EncryptionPolicy {
+    /// Only encrypted clients are accepted; plaintext clients fail with
+    /// `NamedPipeError::EncryptionRequired`. The default for servers with a key.
+    Required,
+    /// Both are accepted, and each connection encrypts if its client does
+    Optional,
+    /// Only plaintext clients are accepted; encrypted clients fail with
+    /// `NamedPipeError::EncryptionMismatch`. The default for servers without a key.
+    Refused,
+}
+
+/// What the server does when a connection handler passed to `start` panics
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PanicPolicy {
+    /// Catch the panic, log it and drop that connection; the server keeps serving (the
+    /// default)
+    #[default]
+    Isolate,
+    /// Stop the server; `start` returns `NamedPipeError::HandlerPanicked`
+    Propagate,
+}
+
+/// What is known about a client before its connection is set up, as passed to the accept
+/// filter
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectionInfo {
+    /// Id the connection will have
+    pub id: ConnectionId,
+    /// Process id of the client
+    pub pid: u32,
+    /// Executable path of the client, if it could be queried
+    pub path: Option<String>,
+}
+
+/// A connection the server has handed out, as listed by `NamedPipeServerStruct::connections`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectionSnapshot {
+    /// Id of the connection
+    pub id: ConnectionId,
+    /// Process id of the client, if it could be queried
+    pub pid: Option<u32>,
+    /// Executable path of the client, if it could be queried
+    pub path: Option<String>,
+    /// When the connection passed its checks and was handed out
+    pub connected_at: SystemTime,
+    /// Bytes written to the client so far, including framing
+    pub bytes_sent: u64,
+    /// Bytes read from the client so far, including framing
+    pub bytes_received: u64,
+}
+
+/// A live connection's entry in the server's registry
+struct Registered {
+    pid: Option<u32>,
+    path: Option<String>,
+    connected_at: SystemTime,
+    counts: Arc<SharedByteCounts>,
+    disconnect: Arc<DisconnectRequest>,
+}
+
+/// Asks a registered connection to disconnect, shared by the registry and the connection
+#[derive(Default)]
+struct DisconnectRequest {
+    token: CancellationToken,
+    code: std::sync::Mutex<Option<CloseCode>>,
+}
+
+/// The connections a server has handed out and that have not been dropped yet
+type Registry = Arc<std::sync::Mutex<BTreeMap<ConnectionId, Registered>>>;
+
+/// List the registered connections, holding the registry lock once
+fn snapshot(registry: &Registry) -> Vec<ConnectionSnapshot> {
+    let registry = registry.lock().unwrap();
+    registry
+        .iter()
+        .map(|(&id, entry)| ConnectionSnapshot {
+            id,
+            pid: entry.pid,
+            path: entry.path.clone(),
+            connected_at: entry.connected_at,
+            bytes_sent: entry.counts.sent.load(Ordering::Relaxed),
+            bytes_received: entry.counts.received.load(Ordering::Relaxed),
+        })
+        .collect()
+}
+
+/// Ask the registered connection `id` to disconnect, with a close frame if `code` is given
+fn request_disconnect(
+    registry: &Registry,
+    id: ConnectionId,
+    code: Option<CloseCode>,
+) -> Result<()> {
+    let registry = registry.lock().unwrap();
+    let entry = registry
+        .get(&id)
+        .ok_or(NamedPipeError::ConnectionNotFound(id))?;
+    *entry.disconnect.code.lock().unwrap() = code;
+    entry.disconnect.token.cancel();
+    Ok(())
+}
+
+/// Wait for `receive` unless the server asks to disconnect the connection first, in which
+/// case `None` is returned. Only for cancel-safe receives, which lose nothing when dropped.
+async fn unless_disconnected<T>(
+    request: Option<&DisconnectRequest>,
+    receive: impl std::future::Future<Output = Result<T>>,
+) -> Option<Result<T>> {
+    let Some(request) = request else {
+        return Some(receive.await);
+    };
+    tokio::select! {
+        biased;
+        _ = request.token.cancelled() => None,
+        result = receive => Some(result),
+    }
+}
+
+/// Decides from a `ConnectionInfo` whether a client may connect
+type AcceptFilter = Arc<dyn Fn(&ConnectionInfo) -> bool + Send + Sync>;
+
+/// Receives every `AuditEvent` the server records
+type AuditCallback = Arc<dyn Fn(AuditEvent) + Send + Sync>;
+
+/// The setup step a refused connection failed at, as reported in an `AuditEvent`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RejectionKind {
+    /// Path enforcement found a client running a different executable
+    PathMismatch,
+    /// The accept filter refused the client
+    Filtered,
+    /// Negotiating encryption or deriving the connection key failed
+    Handshake,
+    /// The client failed shared-secret authentication
+    AuthFailed,
+    /// The client sent a frame larger than the server's `max_message_size`
+    FrameTooLarge,
+    /// Receiving the client's metadata failed
+    Metadata,
+}
+
+/// Whether an audited connection was handed out or refused
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuditOutcome {
+    /// The connection passed every check; only reported with `set_audit_accepts(true)`
+    Accepted,
+    /// The connection was closed during setup, with the error it failed with
+    Rejected { kind: RejectionKind, reason: String },
+}
+
+/// A connection decision recorded through `NamedPipeServerStruct::set_audit_callback`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuditEvent {
+    /// Id of the connection
+    pub id: ConnectionId,
+    /// Process id of the client, if it could be queried
+    pub pid: Option<u32>,
+    /// Executable path of the client, if it could be queried
+    pub path: Option<String>,
+    /// Full name of the server's pipe
+    pub pipe_name: String,
+    /// Whether the connection was handed out or refused, and why
+    pub outcome: AuditOutcome,
+}
 
 /// A connection handler for named pipe server
 pub struct NamedPipeConnection {
-    server: NamedPipeServer,
-    id: usize,
-    cipher: Option<ChaCha20Poly1305>,
+    framed: FramedSlot,
+    id: ConnectionId,
     enforce_same_path_client: bool,
+    metadata: Vec<u8>,
+    extensions: HashMap<TypeId, Box<dyn Any + Send + Sync>>,
+    events: Option<EventSender>,
+    registry: Option<Registry>,
+    disconnect: Option<Arc<DisconnectRequest>>,
+    handshake: bool,
+    authenticated: bool,
+    closed: bool,
 }
 
 impl NamedPipeConnection {
     /// Create a new connection without encryption
-    pub fn new(server: NamedPipeServer, id: usize, enforce_same_path_client: bool) -> Self {
+    pub fn new(server: NamedPipeServer, id: ConnectionId, enforce_same_path_client: bool) -> Self {
         Self {
-            server,
+            framed: FramedSlot(Some(Framed::new(server))),
             id,
-            cipher: None,
             enforce_same_path_client,
+            metadata: Vec::new(),
+            extensions: HashMap::new(),
+            events: None,
+            registry: None,
+            disconnect: None,
+            handshake: false,
+            authenticated: false,
+            closed: false,
         }
     }
 
-    /// Create a new connection with encryption using a pre-shared key
+    /// Create a new connection with encryption using a pre-shared key.
+    /// Fails with `NamedPipeError::WeakKey` if the key is obviously weak.
     pub fn new_encrypted(
         server: NamedPipeServer,
-        id: usize,
+        id: ConnectionId,
         key: &[u8; 32],
         enforce_same_path_client: bool,
-    ) -> Self {
-        let key = Key::from_slice(key);
-        let cipher = ChaCha20Poly1305::new(key);
+    ) -> Result<Self> {
+        check_key(key)?;
+        let cipher = Cipher::new(CipherSuite::ChaCha20Poly1305, key);
+        let mut connection = Self::with_cipher(server, id, cipher, enforce_same_path_client);
+        connection.framed.set_cipher_key(Some(key));
+        Ok(connection)
+    }
 
+    /// Create a new connection encrypted with the given cipher
+    pub fn with_cipher(
+        server: NamedPipeServer,
+        id: ConnectionId,
+        cipher: Cipher,
+        enforce_same_path_client: bool,
+    ) -> Self {
         Self {
-            server,
+            framed: FramedSlot(Some(Framed::with_cipher(server, Some(cipher)))),
             id,
-            cipher: Some(cipher),
             enforce_same_path_client,
+            metadata: Vec::new(),
+            extensions: HashMap::new(),
+            events: None,
+            registry: None,
+            disconnect: None,
+            handshake: false,
+            authenticated: false,
+            closed: false,
         }
     }
 
-    /// Get the connection ID
-    pub fn id(&self) -> usize {
+    /// Get the connection ID, unique and monotonically increasing within this process
+    pub fn id(&self) -> ConnectionId {
         self.id
     }
 
+    /// Get the metadata the client sent with `connect_with_metadata`.
+    ///
+    /// Only populated when the server expects metadata (see
+    /// `NamedPipeServerStruct::set_expect_metadata`); empty otherwise.
+    pub fn metadata(&self) -> &[u8] {
+        &self.metadata
+    }
+
+    /// Attach a value of type `T` to the connection, replacing and returning any previous
+    /// one. Values are keyed by type, so wrap plain types like `u32` in a newtype to give
+    /// them a meaning; they live as long as the connection.
+    pub fn set_ext<T: Send + Sync + 'static>(&mut self, value: T) -> Option<T> {
+        self.extensions
+            .insert(TypeId::of::<T>(), Box::new(value))
+            .and_then(|previous| previous.downcast().ok())
+            .map(|previous| *previous)
+    }
+
+    /// Get the value of type `T` attached with `set_ext`
+    pub fn get_ext<T: Send + Sync + 'static>(&self) -> Option<&T> {
+        self.extensions
+            .get(&TypeId::of::<T>())
+            .and_then(|value| value.downcast_ref())
+    }
+
+    /// Get the value of type `T` attached with `set_ext` for modification
+    pub fn get_ext_mut<T: Send + Sync + 'static>(&mut self) -> Option<&mut T> {
+        self.extensions
+            .get_mut(&TypeId::of::<T>())
+            .and_then(|value| value.downcast_mut())
+    }
+
+    /// Detach and return the value of type `T`
+    pub fn remove_ext<T: Send + Sync + 'static>(&mut self) -> Option<T> {
+        self.extensions
+            .remove(&TypeId::of::<T>())
+            .and_then(|value| value.downcast().ok())
+            .map(|value| *value)
+    }
+
+    /// Split the connection into a read half and a write half for separate tasks; see
+    /// `Framed::into_split`. The halves no longer warn about unsent data when dropped, and
+    /// no longer report events to the server's event stream or appear in its `connections`.
+    /// Fails with `NamedPipeError::InvalidConfig` while acks are enabled.
+    pub fn into_split(
+        mut self,
+    ) -> Result<(
+        FramedReadHalf<NamedPipeServer>,
+        FramedWriteHalf<NamedPipeServer>,
+    )> {
+        self.framed.take().into_split()
+    }
+
+    /// Report this connection's byte milestones and closing to `events`
+    fn attach_events(&mut self, events: EventSender, byte_milestone: u64) {
+        let id = self.id;
+        let milestones = events.clone();
+        self.framed.set_byte_milestones(
+            byte_milestone,
+            Arc::new(move |total| milestones.send(PipeEvent::BytesMilestone { id, total })),
+        );
+        self.events = Some(events);
+    }
+
+    /// List this connection in `registry` until it is dropped
+    fn register(&mut self, registry: Registry) {
+        let pid = get_client_pid(self.framed.get_ref()).ok();
+        let entry = Registered {
+            pid,
+            path: pid.and_then(|pid| get_process_path(pid).ok()),
+            connected_at: SystemTime::now(),
+            counts: self.framed.share_byte_counts(),
+            disconnect: Arc::clone(self.disconnect.get_or_insert_with(Arc::default)),
+        };
+        registry.lock().unwrap().insert(self.id, entry);
+        self.registry = Some(registry);
+    }
+
+    /// Complete once the server asks to disconnect this connection through
+    /// `disconnect_connection`, for handlers to select on next to work other than receiving.
+    /// Never completes for connections the server has not handed out.
+    pub async fn disconnect_requested(&self) {
+        match self.disconnect.as_ref() {
+            Some(request) => request.token.cancelled().await,
+            None => std::future::pending().await,
+        }
+    }
+
+    /// Close the connection the server asked to disconnect, sending the close code it gave
+    /// first, and return the error the interrupted receive fails with
+    async fn finish_disconnect(&mut self) -> NamedPipeError {
+        if !self.closed {
+            let code = self
+                .disconnect
+                .as_ref()
+                .and_then(|request| *request.code.lock().unwrap());
+            if let Some(code) = code {
+                if let Err(e) = self.framed.send_close(code).await {
+                    eprintln!(
+                        "Failed to send close frame to connection {}: {}",
+                        self.id, e
+                    );
+                }
+            }
+            if let Err(e) = self.framed.get_ref().disconnect() {
+                eprintln!("Failed to disconnect connection {}: {}", self.id, e);
+            }
+            self.closed = true;
+            self.unregister();
+        }
+        NamedPipeError::DisconnectRequested
+    }
+
+    /// Remove this connection from the server's registry, if it is listed
+    fn unregister(&mut self) {
+        if let Some(registry) = self.registry.take() {
+            registry.lock().unwrap().remove(&self.id);
+        }
+    }
+
+    /// Number of bytes of frames buffered for the client but not yet written, e.g. to decide
+    /// at shutdown whether to `flush` first; see `Framed::buffered_len`
+    pub fn pending_write_bytes(&self) -> usize {
+        self.framed.buffered_len()
+    }
+
+    /// Total bytes sent to the client over this connection, framing overhead included
+    pub fn bytes_sent(&self) -> u64 {
+        self.framed.bytes_sent()
+    }
+
+    /// Total bytes received from the client over this connection, framing overhead included
+    pub fn bytes_received(&self) -> u64 {
+        self.framed.bytes_received()
+    }
+
+    /// Ask Windows whether this pipe instance reads in byte or message mode
+    pub fn pipe_mode(&self) -> Result<PipeMode> {
+        get_pipe_mode(self.framed.get_ref())
+    }
+
     /// Verify that the client has the same executable path as this process, if enforcement is enabled.
     pub fn verify_client_path(&self) -> Result<()> {
         if !self.enforce_same_path_client {
             return Ok(());
         }
 
-        let client_pid = get_client_pid(&self.server)?;
+        let client_pid = get_client_pid(self.framed.get_ref())?;
         verify_same_path(client_pid)
     }
 
-    /// Send raw bytes to the client
-    pub async fn send_bytes(&mut self, data: &[u8]) -> Result<()> {
-        if let Some(ref cipher) = self.cipher {
-            let encrypted_message = encrypt_message(cipher, data)?;
+    /// Run `f` on the current thread while impersonating the client's security context.
+    ///
+    /// Calls `ImpersonateNamedPipeClient` before `f` and `RevertToSelf` after it, also when
+    /// `f` panics. Windows only allows impersonation once something has been read from the
+    /// client. Impersonation applies to the calling thread, so `f` is synchronous: it must
+    /// not hand work to other threads or await anything that could resume elsewhere. If
+    /// reverting fails, the process aborts rather than keep running as the client.
+    pub fn impersonate<F, R>(&self, f: F) -> Result<R>
+    where
+        F: FnOnce() -> R,
+    {
+        unsafe { ImpersonateNamedPipeClient(HANDLE(self.as_raw_handle())) }
+            .map_err(|e| NamedPipeError::Io(e.into()))?;
+        let _revert = RevertOnDrop;
+        Ok(f())
+    }
 
-            // Send length-prefixed encrypted message
-            let len = encrypted_message.len() as u32;
-            self.server.write_all(&len.to_le_bytes()).await?;
-            self.server.write_all(&encrypted_message).await?;
-        } else {
-            // Send unencrypted data with length prefix
-            let len = data.len() as u32;
-            self.server.write_all(&len.to_le_bytes()).await?;
-            self.server.write_all(data).await?;
+    /// Run the server side of the hello exchange
+    pub(crate) async fn accept_hello(
+        &mut self,
+        policy: EncryptionPolicy,
+        derive_keys: bool,
+    ) -> Result<()> {
+        accept_hello(&mut *self.framed, policy, derive_keys).await?;
+        self.handshake = true;
+        Ok(())
+    }
+
+    /// Switch to a key derived from `master_key` for this connection alone
+    pub(crate) async fn derive_key(&mut self, master_key: &[u8; 32]) -> Result<()> {
+        derive_server_key(&mut *self.framed, master_key).await
+    }
+
+    /// Whether frames on this connection are encrypted, as negotiated with the client
+    pub fn is_encrypted(&self) -> bool {
+        self.framed.is_encrypted()
+    }
+
+    /// The optional features agreed with the client in the handshake. Without the
+    /// handshake, the features this connection was configured with.
+    pub fn negotiated_capabilities(&self) -> Features {
+        self.framed.capabilities()
+    }
+
+    /// What this connection actually uses: the protocol version, cipher suite, features and
+    /// framing agreed in the handshake, and which client checks it passed
+    pub fn connection_params(&self) -> ConnectionParams {
+        ConnectionParams {
+            authenticated: self.authenticated,
+            path_verified: self.enforce_same_path_client,
+            ..self.framed.connection_params(self.handshake)
         }
+    }
+
+    /// Run the server side of the shared-secret challenge-response exchange
+    pub(crate) async fn authenticate(&mut self, secret: &[u8]) -> Result<()> {
+        authenticate_client(&mut *self.framed, secret).await?;
+        self.authenticated = true;
+        Ok(())
+    }
+
+    /// Enable or disable flushing after every send (enabled by default).
+    ///
+    /// When disabled, `send_bytes` appends each frame to an internal buffer and nothing
+    /// reaches the pipe until `flush` is called. `close` flushes implicitly; dropping the
+    /// connection discards anything still buffered.
+    pub fn set_auto_flush(&mut self, enabled: bool) {
+        self.framed.set_auto_flush(enabled);
+    }
+
+    /// Flush buffered frames before every receive (enabled by default); see
+    /// `Framed::set_flush_before_receive`
+    pub fn set_flush_before_receive(&mut self, enabled: bool) {
+        self.framed.set_flush_before_receive(enabled);
+    }
+
+    /// Limit this connection's combined send and receive throughput to `bytes_per_sec`.
+    ///
+    /// Uses a token bucket holding one second's worth of bytes. Sends and receives that
+    /// exceed it are delayed rather than failed, smoothing the load a single client can put
+    /// on the server. Passing 0 disables the limit.
+    pub fn set_rate_limit(&mut self, bytes_per_sec: u64) {
+        self.framed.set_rate_limit(bytes_per_sec);
+    }
+
+    /// Append a CRC32C to every unencrypted frame and verify it on receive.
+    /// Has no effect on encrypted connections. The client must use the same setting.
+    pub fn set_integrity_check(&mut self, enabled: bool) {
+        self.framed.set_integrity_check(enabled);
+    }
+
+    /// Register another key under `id` for key rotation; see `Framed::add_key`
+    pub fn add_key(&mut self, id: u8, key: &[u8; 32]) -> Result<()> {
+        self.framed.add_key(id, key)
+    }
+
+    /// Seal frames sent to the client with the key registered under `id`
+    pub fn set_active_key(&mut self, id: u8) -> Result<()> {
+        self.framed.set_active_key(id)
+    }
+
+    /// Forget the key registered under `id`; the active key cannot be removed
+    pub fn remove_key(&mut self, id: u8) -> Result<()> {
+        self.framed.remove_key(id)
+    }
+
+    /// Compress frames sent to the client; see `Framed::set_compression`
+    pub fn set_compression(&mut self, enabled: bool) {
+        self.framed.set_compression(enabled);
+    }
+
+    /// Only compress frames whose body is larger than `bytes`
+    pub fn set_compression_threshold(&mut self, bytes: usize) {
+        self.framed.set_compression_threshold(bytes);
+    }
+
+    /// Set how long `send_bytes_acked` waits for the client's ack (30 seconds by default)
+    pub fn set_ack_timeout(&mut self, timeout: Duration) {
+        self.framed.set_ack_timeout(timeout);
+    }
+
+    /// Reject replayed encrypted frames with `NamedPipeError::ReplayDetected`.
+    /// Has no effect on unencrypted connections. The client must use the same setting.
+    pub fn enable_replay_protection(&mut self, enabled: bool) {
+        self.framed.enable_replay_protection(enabled);
+    }
 
-        self.server.flush().await?;
+    /// Set the byte order of the frame length prefix; must match the client
+    pub fn set_frame_endianness(&mut self, endian: Endian) {
+        self.framed.set_frame_endianness(endian);
+    }
+
+    /// Reject frames whose payload is larger than `max_size` bytes
+    pub fn set_max_message_size(&mut self, max_size: u64) {
+        self.framed.set_max_message_size(max_size);
+    }
+
+    /// Set the chunk size of `send_stream` and the amount `receive_stream` collects before
+    /// writing; see `Framed::set_stream_chunk_size`
+    pub fn set_stream_chunk_size(&mut self, bytes: usize) {
+        self.framed.set_stream_chunk_size(bytes);
+    }
+
+    /// Attach `headers` to every data frame sent to the client, e.g. a trace id; see
+    /// `Framed::set_default_headers`
+    pub fn set_default_headers(&mut self, headers: Headers) {
+        self.framed.set_default_headers(headers);
+    }
+
+    /// Send raw bytes to the client with `headers` on top of the default headers
+    pub async fn send_bytes_with_headers(&mut self, data: &[u8], headers: &Headers) -> Result<()> {
+        self.framed.send_bytes_with_headers(data, headers).await
+    }
+
+    /// Get the headers of the last frame received from the client
+    pub fn received_headers(&self) -> &Headers {
+        self.framed.received_headers()
+    }
+
+    /// Set the layout (byte order and width) of the frame length prefix; must match the client
+    pub fn set_frame_format(&mut self, format: FrameFormat) {
+        self.framed.set_frame_format(format);
+    }
+
+    /// Write any buffered frames to the pipe and flush it
+    pub async fn flush(&mut self) -> Result<()> {
+        self.framed.flush().await
+    }
+
+    /// Flush any buffered frames and disconnect the client
+    pub async fn close(&mut self) -> Result<()> {
+        self.framed.flush().await?;
+        self.framed.get_ref().disconnect()?;
+        self.closed = true;
+        Ok(())
+    }
+
+    /// Restart the sequence numbers of both ends, optionally switching to a derived key; see
+    /// `Framed::reset_crypto_state`
+    pub async fn reset_crypto_state(&mut self, rekey: bool) -> Result<()> {
+        self.framed.reset_crypto_state(rekey).await
+    }
+
+    /// Switch a plaintext connection to encryption with `key` under `suite` together with
+    /// the client; see `Framed::upgrade_to_encrypted`
+    pub async fn upgrade_to_encrypted(&mut self, key: &[u8; 32], suite: CipherSuite) -> Result<()> {
+        self.framed.upgrade_to_encrypted(key, suite).await
+    }
+
+    /// Finish sending but keep receiving, e.g. after the last part of a request; see
+    /// `Framed::shutdown_write`. The client's receives fail with
+    /// `NamedPipeError::PeerWriteShutdown` once it has read everything sent before.
+    pub async fn shutdown_write(&mut self) -> Result<()> {
+        self.framed.shutdown_write().await
+    }
+
+    /// Flush any buffered frames, tell the client why with a close frame and disconnect it.
+    /// The client's next receive fails with `NamedPipeError::ClosedWithCode(code)`.
+    pub async fn close_with_code(&mut self, code: CloseCode) -> Result<()> {
+        self.framed.send_close(code).await?;
+        self.framed.get_ref().disconnect()?;
+        self.closed = true;
         Ok(())
     }
 
-    /// Receive raw bytes from the client
+    /// Send raw bytes to the client.
+    ///
+    /// # Cancel safety
+    ///
+    /// This method is not cancel-safe. If the future is dropped after part of a frame has
+    /// been written, the peer sees a truncated frame and the connection should be discarded.
+    pub async fn send_bytes(&mut self, data: &[u8]) -> Result<()> {
+        self.framed.send_bytes(data).await
+    }
+
+    /// Send raw bytes to the client and wait for it to confirm receipt; see
+    /// `Framed::send_bytes_acked`. Needs acks enabled on the server and the client.
+    pub async fn send_bytes_acked(&mut self, data: &[u8]) -> Result<()> {
+        self.framed.send_bytes_acked(data).await
+    }
+
+    /// Send raw bytes ahead of buffered frames of lower priority; see `Framed::send_bytes_priority`
+    pub async fn send_bytes_priority(&mut self, data: &[u8], priority: Priority) -> Result<()> {
+        self.framed.send_bytes_priority(data, priority).await
+    }
+
+    /// Send bytes already encoded as `content_type`, e.g. a bincode-serialized value
+    pub async fn send_with_content_type(
+        &mut self,
+        data: &[u8],
+        content_type: ContentType,
+    ) -> Result<()> {
+        self.framed.send_with_content_type(data, content_type).await
+    }
+
+    /// Send an application-level error that the client receives as `NamedPipeError::RemoteError`
+    pub async fn send_error(&mut self, code: u32, msg: &str) -> Result<()> {
+        self.framed.send_error(code, msg).await
+    }
+
+    /// Send everything `reader` yields to the client as a resumable stream; see `Framed::send_stream`
+    pub async fn send_stream<R: AsyncRead + Unpin>(&mut self, reader: R) -> Result<u64> {
+        self.framed.send_stream(reader).await
+    }
+
+    /// Resume an interrupted stream from the offset the client acknowledged
+    pub async fn resume_stream<R: AsyncRead + Unpin>(
+        &mut self,
+        from_offset: u64,
+        reader: R,
+    ) -> Result<u64> {
+        self.framed.resume_stream(from_offset, reader).await
+    }
+
+    /// Receive a stream from the client into `writer`, returning its total length
+    pub async fn receive_stream<W: AsyncWrite + Unpin>(&mut self, writer: W) -> Result<u64> {
+        self.framed.receive_stream(writer).await
+    }
+
+    /// Receive the rest of an interrupted stream of which `from_offset` bytes were written
+    pub async fn resume_receive_stream<W: AsyncWrite + Unpin>(
+        &mut self,
+        from_offset: u64,
+        writer: W,
+    ) -> Result<u64> {
+        self.framed.resume_receive_stream(from_offset, writer).await
+    }
+
+    /// Forward every frame from the client into `sink` until it disconnects; see
+    /// `Framed::drain_to`
+    pub async fn drain_to<Si>(&mut self, sink: Si) -> Result<u64>
+    where
+        Si: Sink<Vec<u8>> + Unpin,
+        Si::Error: std::error::Error + Send + Sync + 'static,
+    {
+        self.framed.drain_to(sink).await
+    }
+
+    /// Receive raw bytes from the client, reserving room for `hint` bytes up front; see
+    /// `Framed::receive_bytes_with_capacity`
+    pub async fn receive_bytes_with_capacity(&mut self, hint: usize) -> Result<Vec<u8>> {
+        match unless_disconnected(
+            self.disconnect.as_deref(),
+            self.framed.receive_bytes_with_capacity(hint),
+        )
+        .await
+        {
+            Some(result) => result,
+            None => Err(self.finish_disconnect().await),
+        }
+    }
+
+    /// Send the next `len` bytes of `reader` as one frame without buffering it; see
+    /// `Framed::send_frame_from`
+    pub async fn send_frame_from<R: AsyncRead + Unpin>(
+        &mut self,
+        reader: R,
+        len: u64,
+    ) -> Result<()> {
+        self.framed.send_frame_from(reader, len).await
+    }
+
+    /// Receive one frame into `writer` without buffering it, returning its length; see
+    /// `Framed::receive_frame_into`
+    pub async fn receive_frame_into<W: AsyncWrite + Unpin>(&mut self, writer: W) -> Result<u64> {
+        self.framed.receive_frame_into(writer).await
+    }
+
+    /// Receive one message from the client, decoded according to its content type
+    pub async fn receive_any(&mut self) -> Result<Message> {
+        match unless_disconnected(self.disconnect.as_deref(), self.framed.receive_any()).await {
+            Some(result) => result,
+            None => Err(self.finish_disconnect().await),
+        }
+    }
+
+    /// Receive raw bytes from the client.
+    ///
+    /// # Cancel safety
+    ///
+    /// This method is cancel-safe. Bytes of a partially received frame are kept in the
+    /// read-ahead buffer, so if the future is dropped (e.g. when another `tokio::select!`
+    /// branch wins), the next receive continues the same frame instead of losing sync.
     pub async fn receive_bytes(&mut self) -> Result<Vec<u8>> {
-        // Read length first
-        let mut len_bytes = [0u8; 4];
-        match self.server.read_exact(&mut len_bytes).await {
-            Ok(_) => {}
-            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
-                return Err(NamedPipeError::ConnectionClosed);
-            }
-            Err(e) => return Err(NamedPipeError::Io(e)),
+        match unless_disconnected(self.disconnect.as_deref(), self.framed.receive_bytes()).await {
+            Some(result) => result,
+            None => Err(self.finish_disconnect().await),
         }
+    }
 
-        let len = u32::from_le_bytes(len_bytes) as usize;
+    /// Duplicate `handle` into the client process and send it the duplicated value.
+    ///
+    /// The client obtains the handle with `receive_handle` and owns it from then on,
+    /// including closing it. If sending fails, the duplicate is closed in the client process
+    /// again. The original `handle` stays open in this process. Requires the handshake.
+    pub fn send_handle(
+        &mut self,
+        handle: RawHandle,
+    ) -> impl std::future::Future<Output = Result<()>> + Send + '_ {
+        // Duplicate up front so the raw pointer is not held across an await
+        let duplicated = get_client_pid(self.framed.get_ref()).and_then(|client_pid| {
+            let duplicated = duplicate_handle_to_process(handle, client_pid)?;
+            Ok((client_pid, duplicated as usize as u64))
+        });
 
-        // Read data
-        let mut buffer = vec![0u8; len];
-        match self.server.read_exact(&mut buffer).await {
-            Ok(_) => {
-                if let Some(ref cipher) = self.cipher {
-                    let plaintext = decrypt_message(cipher, &buffer)?;
-                    Ok(plaintext)
-                } else {
-                    Ok(buffer)
-                }
+        async move {
+            let (client_pid, value) = duplicated?;
+            let result = self.framed.send_handle(value).await;
+            if result.is_err() {
+                // The client never learns of the duplicate, so close it on its behalf
+                let _ = close_handle_in_process(value as usize as RawHandle, client_pid);
             }
-            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
-                Err(NamedPipeError::ConnectionClosed)
-            }
-            Err(e) => Err(NamedPipeError::Io(e)),
+            result
         }
     }
 
     /// Send a string message to the client
     pub async fn send_string(&mut self, message: &str) -> Result<()> {
-        let data = message.as_bytes();
-        self.send_bytes(data).await
+        self.framed.send_string(message).await
+    }
+
+    /// Report progress on a long operation before sending its result; see
+    /// `Framed::send_progress`
+    pub async fn send_progress(&mut self, progress: &Progress) -> Result<()> {
+        self.framed.send_progress(progress).await
+    }
+
+    /// Receive the result of a long operation from the client, calling `on_progress` with
+    /// each progress update sent before it; see `Framed::receive_with_progress`
+    pub async fn receive_with_progress<F: FnMut(Progress) + Send>(
+        &mut self,
+        on_progress: F,
+    ) -> Result<Vec<u8>> {
+        match unless_disconnected(
+            self.disconnect.as_deref(),
+            self.framed.receive_with_progress(on_progress),
+        )
+        .await
+        {
+            Some(result) => result,
+            None => Err(self.finish_disconnect().await),
+        }
     }
 
     /// Receive a string message from the client
     pub async fn receive_string(&mut self) -> Result<String> {
-        let data = self.receive_bytes().await?;
-        String::from_utf8(data).map_err(|e| {
-            NamedPipeError::Io(std::io::Error::new(
-                std::io::ErrorKind::InvalidData,
-                format!("Invalid UTF-8 string: {}", e),
-            ))
-        })
+        match unless_disconnected(self.disconnect.as_deref(), self.framed.receive_string()).await {
+            Some(result) => result,
+            None => Err(self.finish_disconnect().await),
+        }
+    }
+
+    /// Send a string message to the client in the given encoding
+    pub async fn send_string_as(&mut self, message: &str, encoding: TextEncoding) -> Result<()> {
+        self.framed.send_string_as(message, encoding).await
+    }
+
+    /// Receive a string message in the given encoding from the client
+    pub async fn receive_string_as(&mut self, encoding: TextEncoding) -> Result<String> {
+        match unless_disconnected(
+            self.disconnect.as_deref(),
+            self.framed.receive_string_as(encoding),
+        )
+        .await
+        {
+            Some(result) => result,
+            None => Err(self.finish_disconnect().await),
+        }
     }
 
     /// Send a JSON message to the client
     pub async fn send_json<T: serde::Serialize>(&mut self, message: &T) -> Result<()> {
-        let json = serde_json::to_string(message).map_err(|e| {
-            NamedPipeError::Io(std::io::Error::new(
-                std::io::ErrorKind::InvalidData,
-                format!("JSON serialization failed: {}", e),
-            ))
-        })?;
-        self.send_string(&json).await
+        self.framed.send_json(message).await
     }
 
     /// Receive a JSON message from the client
     pub async fn receive_json<T: serde::de::DeserializeOwned>(&mut self) -> Result<T> {
-        let json = self.receive_string().await?;
-        serde_json::from_str(&json).map_err(|e| {
-            NamedPipeError::Io(std::io::Error::new(
-                std::io::ErrorKind::InvalidData,
-                format!("JSON deserialization failed: {}", e),
-            ))
-        })
+        match unless_disconnected(self.disconnect.as_deref(), self.framed.receive_json()).await {
+            Some(result) => result,
+            None => Err(self.finish_disconnect().await),
+        }
+    }
+}
+
+/// Exposes the pipe handle for calling Win32 pipe APIs the crate does not wrap.
+///
+/// Reading from or writing to the handle directly bypasses the framing layer; mixing that
+/// with `send_bytes`/`receive_bytes` is unsupported and will desynchronize the connection.
+impl AsRawHandle for NamedPipeConnection {
+    fn as_raw_handle(&self) -> RawHandle {
+        self.framed.get_ref().as_raw_handle()
+    }
+}
+
+/// Ends the calling thread's impersonation when dropped, including during unwinding
+struct RevertOnDrop;
+
+impl Drop for RevertOnDrop {
+    fn drop(&mut self) {
+        if unsafe { RevertToSelf() }.is_err() {
+            // Carrying on under the client's identity would leak its privileges
+            eprintln!("Failed to revert impersonation, aborting");
+            std::process::abort();
+        }
     }
 }
 
+impl Drop for NamedPipeConnection {
+    /// Warn when a connection is dropped without `close` while frames are still buffered
+    /// for sending or received data is still waiting to be read, typically because its
+    /// handler was cancelled or returned early; the client just sees the pipe break.
+    fn drop(&mut self) {
+        self.unregister();
+        // Split connections report nothing more
+        if self.framed.0.is_none() {
+            return;
+        }
+        if let Some(events) = self.events.as_ref() {
+            events.send(PipeEvent::Closed {
+                id: self.id,
+                bytes_sent: self.framed.bytes_sent(),
+                bytes_received: self.framed.bytes_received(),
+            });
+        }
+        if self.closed {
+            return;
+        }
+        let unsent = self.framed.buffered_len();
+        let unread = self.framed.read_ahead_len();
+        if unsent > 0 || unread > 0 {
+            eprintln!(
+                "Warning: connection {} dropped with {} unsent and {} unread bytes; call close() to end it cleanly",
+                self.id, unsent, unread
+            );
+        }
+    }
+}
+
+/// A connection's framed stream, which only `NamedPipeConnection::into_split` moves out
+struct FramedSlot(Option<Framed<NamedPipeServer>>);
+
+impl FramedSlot {
+    /// Move the stream out; the slot must not be used afterwards
+    fn take(&mut self) -> Framed<NamedPipeServer> {
+        self.0.take().expect("connection stream already taken")
+    }
+}
+
+impl std::ops::Deref for FramedSlot {
+    type Target = Framed<NamedPipeServer>;
+
+    fn deref(&self) -> &Self::Target {
+        self.0.as_ref().expect("connection stream already taken")
+    }
+}
+
+impl std::ops::DerefMut for FramedSlot {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.0.as_mut().expect("connection stream already taken")
+    }
+}
+
+/// A snapshot of a server's settings, returned by `NamedPipeServerStruct::effective_config`.
+/// Keys and secrets are reported only as present or absent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServerConfig {
+    /// Full pipe name, including the `\\.\pipe\` prefix
+    pub pipe_name: String,
+    /// Whether a key or shared cipher is configured
+    pub encrypted: bool,
+    /// Suite encrypted connections use
+    pub cipher_suite: CipherSuite,
+    /// Whether each connection derives its own key from the master key
+    pub derive_keys: bool,
+    /// Whether clients must, may or must not encrypt
+    pub encryption_policy: EncryptionPolicy,
+    /// Whether the connection handshake runs
+    pub handshake: bool,
+    /// Deadline for a client's connection setup, if any
+    pub handshake_timeout: Option<Duration>,
+    /// Whether clients must prove they know a shared secret
+    pub shared_secret: bool,
+    /// Whether clients must run the same executable as the server
+    pub enforce_same_path_client: bool,
+    /// Whether an accept filter is installed
+    pub accept_filter: bool,
+    /// Whether an audit callback is installed
+    pub audit_callback: bool,
+    /// Whether accepted connections are audited as well as rejected ones
+    pub audit_accepts: bool,
+    /// Whether an event stream is being fed
+    pub event_stream: bool,
+    /// Bytes between `PipeEvent::BytesMilestone` events; 0 when disabled
+    pub byte_milestone: u64,
+    /// Whether unencrypted frames carry a CRC32C
+    pub integrity_check: bool,
+    /// Whether frames are compressed
+    pub compression: bool,
+    /// Smallest body, in bytes, that is compressed
+    pub compression_threshold: usize,
+    /// Whether `send_bytes_acked` is allowed
+    pub acks: bool,
+    /// Whether encrypted frames carry sequence numbers checked against replays
+    pub replay_protection: bool,
+    /// Whether pipe instances read in byte or message mode
+    pub pipe_mode: PipeMode,
+    /// Layout of the length prefix in front of every frame
+    pub frame_format: FrameFormat,
+    /// How messages are delimited on the stream
+    pub framing: Framing,
+    /// Largest message accepted, in bytes
+    pub max_message_size: u64,
+    /// Whether clients send metadata as their first frame
+    pub expect_metadata: bool,
+    /// Whether the first instance must be the first of its name
+    pub first_pipe_instance: bool,
+    /// Whether the next instance is created as soon as a client connects
+    pub eager_instance: bool,
+    /// Input buffer size of every pipe instance, in bytes
+    pub in_buffer_size: u32,
+    /// Output buffer size of every pipe instance, in bytes
+    pub out_buffer_size: u32,
+    /// What happens when a connection handler panics
+    pub panic_policy: PanicPolicy,
+    /// Whether handlers run on Tokio's blocking pool
+    pub handler_on_blocking: bool,
+    /// Number of clients accepted before the server stops creating pipe instances
+    pub max_total_connections: u64,
+}
+
 /// A named pipe server for Windows
 pub struct NamedPipeServerStruct {
     pipe_name: String,
     is_running: Arc<Mutex<bool>>,
     shutdown_tx: Option<broadcast::Sender<()>>,
     server_handle: Option<JoinHandle<Result<()>>>,
-    connection_counter: Arc<Mutex<usize>>,
     cipher_key: Option<[u8; 32]>,
+    cipher_suite: CipherSuite,
+    shared_cipher: Option<PipeCipher>,
+    derive_keys: bool,
+    rejection_reasons: bool,
+    keys: Arc<BTreeMap<u8, [u8; 32]>>,
+    active_key: u8,
     enforce_same_path_client: bool,
+    shared_secret: Option<Arc<[u8]>>,
+    integrity_check: bool,
+    compression: bool,
+    compression_threshold: usize,
+    acks: bool,
+    replay_protection: bool,
+    pipe_mode: PipeMode,
+    frame_format: FrameFormat,
+    framing: Framing,
+    max_message_size: u64,
+    expect_metadata: bool,
+    first_pipe_instance: bool,
+    eager_instance: bool,
+    in_buffer_size: u32,
+    out_buffer_size: u32,
+    encryption_policy: EncryptionPolicy,
+    handshake: bool,
+    handshake_timeout: Option<Duration>,
+    panic_policy: PanicPolicy,
+    handler_on_blocking: bool,
+    max_total_connections: u64,
+    accept_filter: Option<AcceptFilter>,
+    audit: Option<AuditCallback>,
+    audit_accepts: bool,
+    events: Option<EventSender>,
+    byte_milestone: u64,
+    ready: Arc<watch::Sender<bool>>,
+    registry: Registry,
+}
+
+/// Per-connection steps `setup_connection` runs before handing a connection out
+#[derive(Clone)]
+struct SetupOptions {
+    accept_filter: Option<AcceptFilter>,
+    keys: Arc<BTreeMap<u8, [u8; 32]>>,
+    active_key: u8,
+    handshake: bool,
+    encryption_policy: EncryptionPolicy,
+    derive_from: Option<[u8; 32]>,
+    shared_secret: Option<Arc<[u8]>>,
+    expect_metadata: bool,
+    rejection_reasons: bool,
+    pipe_name: String,
+    audit: Option<AuditCallback>,
+    audit_accepts: bool,
+    handshake_timeout: Option<Duration>,
 }
 
 impl NamedPipeServerStruct {
@@ -173,26 +1075,113 @@ impl NamedPipeServerStruct {
             is_running: Arc::new(Mutex::new(false)),
             shutdown_tx: None,
             server_handle: None,
-            connection_counter: Arc::new(Mutex::new(0)),
             cipher_key: None,
+            cipher_suite: CipherSuite::default(),
+            shared_cipher: None,
+            derive_keys: false,
+            rejection_reasons: false,
+            keys: Arc::default(),
+            active_key: 0,
             enforce_same_path_client: false,
+            shared_secret: None,
+            integrity_check: false,
+            compression: false,
+            compression_threshold: DEFAULT_COMPRESSION_THRESHOLD,
+            acks: false,
+            replay_protection: false,
+            pipe_mode: PipeMode::Byte,
+            frame_format: FrameFormat::default(),
+            framing: Framing::default(),
+            max_message_size: u64::MAX,
+            expect_metadata: false,
+            first_pipe_instance: true,
+            eager_instance: false,
+            in_buffer_size: DEFAULT_PIPE_BUFFER_SIZE,
+            out_buffer_size: DEFAULT_PIPE_BUFFER_SIZE,
+            encryption_policy: EncryptionPolicy::Refused,
+            handshake: true,
+            handshake_timeout: None,
+            panic_policy: PanicPolicy::default(),
+            handler_on_blocking: false,
+            max_total_connections: u64::MAX,
+            accept_filter: None,
+            audit: None,
+            audit_accepts: false,
+            events: None,
+            byte_milestone: DEFAULT_BYTE_MILESTONE,
+            ready: Arc::new(watch::channel(false).0),
+            registry: Registry::default(),
         }
     }
 
     /// Create a new named pipe server with encryption.
     /// If key is None, uses a secure compile-time generated default key.
-    /// If key is Some(key), uses the provided custom key.
-    pub fn new_encrypted(pipe_name: &str, key: Option<[u8; 32]>) -> Self {
+    /// If key is Some(key), uses the provided custom key, which is rejected with
+    /// `NamedPipeError::WeakKey` if it is obviously weak (see `cipher::check_key`).
+    pub fn new_encrypted(pipe_name: &str, key: Option<[u8; 32]>) -> Result<Self> {
         let key_to_use = key.unwrap_or(crate::DEFAULT_ENCRYPTION_KEY);
-        Self {
+        check_key(&key_to_use)?;
+        Ok(Self {
             pipe_name: format_pipe_name(pipe_name),
             is_running: Arc::new(Mutex::new(false)),
             shutdown_tx: None,
             server_handle: None,
-            connection_counter: Arc::new(Mutex::new(0)),
             cipher_key: Some(key_to_use),
+            cipher_suite: CipherSuite::default(),
+            shared_cipher: None,
+            derive_keys: false,
+            rejection_reasons: false,
+            keys: Arc::default(),
+            active_key: 0,
             enforce_same_path_client: false,
-        }
+            shared_secret: None,
+            integrity_check: false,
+            compression: false,
+            compression_threshold: DEFAULT_COMPRESSION_THRESHOLD,
+            acks: false,
+            replay_protection: false,
+            pipe_mode: PipeMode::Byte,
+            frame_format: FrameFormat::default(),
+            framing: Framing::default(),
+            max_message_size: u64::MAX,
+            expect_metadata: false,
+            first_pipe_instance: true,
+            eager_instance: false,
+            in_buffer_size: DEFAULT_PIPE_BUFFER_SIZE,
+            out_buffer_size: DEFAULT_PIPE_BUFFER_SIZE,
+            encryption_policy: EncryptionPolicy::Required,
+            handshake: true,
+            handshake_timeout: None,
+            panic_policy: PanicPolicy::default(),
+            handler_on_blocking: false,
+            max_total_connections: u64::MAX,
+            accept_filter: None,
+            audit: None,
+            audit_accepts: false,
+            events: None,
+            byte_milestone: DEFAULT_BYTE_MILESTONE,
+            ready: Arc::new(watch::channel(false).0),
+            registry: Registry::default(),
+        })
+    }
+
+    /// Create a new named pipe server encrypted with a cipher built beforehand, which may
+    /// be shared with other servers and clients. The cipher fixes the suite.
+    pub fn new_encrypted_with_cipher(pipe_name: &str, cipher: PipeCipher) -> Self {
+        let mut server = Self::new(pipe_name);
+        server.shared_cipher = Some(cipher);
+        server.encryption_policy = EncryptionPolicy::Required;
+        server
+    }
+
+    /// Create a new named pipe server that encrypts each connection under its own key,
+    /// derived with HKDF-SHA256 from `master_key` and random salts exchanged during the
+    /// handshake. Clients must be created with `new_encrypted_derived` and the same master
+    /// key; other encrypted clients are refused with `NamedPipeError::EncryptionMismatch`.
+    pub fn new_encrypted_derived(pipe_name: &str, master_key: [u8; 32]) -> Result<Self> {
+        let mut server = Self::new_encrypted(pipe_name, Some(master_key))?;
+        server.derive_keys = true;
+        Ok(server)
     }
 
     /// Enable enforcement that the client must have the same executable path as the server.
@@ -200,14 +1189,337 @@ impl NamedPipeServerStruct {
         self.enforce_same_path_client = enforce;
     }
 
-    /// Create server with proper security attributes to allow all users
-    fn create_server_with_security(pipe_name: &str) -> Result<NamedPipeServer> {
-        // Create server with proper permissions
+    /// Choose the cipher suite for encrypted connections (ChaCha20Poly1305 by default).
+    /// Clients must use the same suite; the handshake refuses encrypted clients using
+    /// another one with `NamedPipeError::EncryptionMismatch`. Has no effect on servers
+    /// created with `new_encrypted_with_cipher`.
+    pub fn set_cipher_suite(&mut self, suite: CipherSuite) {
+        self.cipher_suite = suite;
+    }
+
+    /// Register another key under `id` for connections accepted from now on, for key
+    /// rotation; see `Framed::add_key`. Clients must register the same keys.
+    pub fn add_key(&mut self, id: u8, key: &[u8; 32]) -> Result<()> {
+        if self.cipher_key.is_none() && self.shared_cipher.is_none() {
+            return Err(NamedPipeError::InvalidConfig(
+                "key ids need an encrypted server".to_string(),
+            ));
+        }
+        if self.derive_keys {
+            return Err(NamedPipeError::InvalidConfig(
+                "key ids cannot be combined with derived keys".to_string(),
+            ));
+        }
+        check_key(key)?;
+        Arc::make_mut(&mut self.keys).insert(id, *key);
+        Ok(())
+    }
+
+    /// Seal frames of connections accepted from now on with the key registered under `id`
+    /// (0 is the key the server was created with). Connections already running keep their
+    /// key; switch them with `NamedPipeConnection::set_active_key`.
+    pub fn set_active_key(&mut self, id: u8) -> Result<()> {
+        if self.keys.is_empty() || (id != 0 && !self.keys.contains_key(&id)) {
+            return Err(NamedPipeError::UnknownKeyId(id));
+        }
+        self.active_key = id;
+        Ok(())
+    }
+
+    /// Choose whether clients must, may or must not encrypt.
+    ///
+    /// The policy is applied per connection during the handshake, so a mismatched client
+    /// fails with `NamedPipeError::EncryptionRequired` or `EncryptionMismatch` on both ends
+    /// instead of exchanging unreadable frames. `Required` fails with
+    /// `NamedPipeError::InvalidConfig` on a server without a key; such a server refuses
+    /// encrypted clients even when `Optional`.
+    pub fn set_encryption_policy(&mut self, policy: EncryptionPolicy) -> Result<()> {
+        self.encryption_policy = policy;
+        self.check_encryption()
+    }
+
+    /// Enable or disable the connection handshake (enabled by default); clients must match.
+    ///
+    /// The handshake negotiates encryption before any application data is exchanged.
+    /// Disable it only to talk to peers that speak the crate's framing but not its
+    /// handshake, such as a hand-written .NET client; encryption is then assumed to match
+    /// and is not checked.
+    pub fn set_handshake(&mut self, enabled: bool) {
+        self.handshake = enabled;
+    }
+
+    /// Drop clients that do not finish connection setup within `timeout` (no limit by
+    /// default), failing their setup with `NamedPipeError::HandshakeTimeout`.
+    ///
+    /// Setup covers every step before the handler runs: the peer checks, the handshake,
+    /// authentication and metadata. Without a limit, a client that connects and then stalls
+    /// holds its pipe instance and setup task forever, so set one on servers that untrusted
+    /// processes can reach.
+    pub fn set_handshake_timeout(&mut self, timeout: Option<Duration>) {
+        self.handshake_timeout = timeout;
+    }
+
+    /// Choose what happens when a handler passed to `start` (or its variants) panics.
+    ///
+    /// With `PanicPolicy::Isolate` the panic is caught and logged and only that
+    /// connection is lost, so one bad message cannot take down the endpoint. With
+    /// `PanicPolicy::Propagate` the server stops and `start` returns
+    /// `NamedPipeError::HandlerPanicked`; handlers of other connections keep running.
+    /// Connections taken from `incoming` are handled by the caller and unaffected.
+    pub fn set_panic_policy(&mut self, policy: PanicPolicy) {
+        self.panic_policy = policy;
+    }
+
+    /// Run each handler passed to `start` (or its variants) on its own thread from Tokio's
+    /// blocking pool instead of on the async workers (disabled by default).
+    ///
+    /// The connection's IO stays async and is still driven by the runtime; only the
+    /// handler's own code moves off the workers, so synchronous CPU-heavy work such as
+    /// parsing or crypto no longer stalls other connections. The cost is one pool thread
+    /// per connection for its whole lifetime: the pool is capped (512 threads by default),
+    /// and connections beyond the cap wait for a thread before their handler starts. On a
+    /// `current_thread` runtime, IO only makes progress while the runtime's own thread is
+    /// inside `block_on`. When only parts of a handler are heavy, prefer leaving this
+    /// disabled and offloading just those parts with `tokio::task::spawn_blocking`.
+    pub fn set_handler_on_blocking(&mut self, enabled: bool) {
+        self.handler_on_blocking = enabled;
+    }
+
+    /// Require clients to authenticate with a shared secret before the handler runs.
+    ///
+    /// Each accepted client must answer an HMAC-SHA256 challenge keyed with this secret,
+    /// and the server proves its own knowledge of the secret in return. Clients that fail
+    /// are disconnected with `NamedPipeError::AuthFailed` and never reach the handler.
+    pub fn set_shared_secret(&mut self, secret: &[u8]) {
+        self.shared_secret = Some(Arc::from(secret));
+    }
+
+    /// Tell clients why they are dropped (disabled by default). Clients refused by path
+    /// enforcement, the accept filter or shared-secret authentication then fail with
+    /// `NamedPipeError::RejectedByServer { reason }` instead of an unexplained disconnect.
+    /// The reason is sent before any data and needs the handshake. Leave this off if the
+    /// reasons would tell an attacker too much.
+    pub fn set_send_rejection_reasons(&mut self, enabled: bool) {
+        self.rejection_reasons = enabled;
+    }
+
+    /// Append a CRC32C to every unencrypted frame and verify it on receive (disabled by
+    /// default), so corrupted frames fail with `NamedPipeError::ChecksumMismatch` instead of
+    /// being parsed. Has no effect on encrypted servers, whose frames are already
+    /// authenticated. Clients must use the same setting.
+    pub fn set_integrity_check(&mut self, enabled: bool) {
+        self.integrity_check = enabled;
+    }
+
+    /// Compress frames sent to clients where that makes them smaller (disabled by default).
+    /// With the handshake, frames to a client are only compressed if it enables compression
+    /// too; otherwise clients decompress whatever their own setting. See
+    /// `Framed::set_compression` for the ordering with encryption and the risk of
+    /// compressing secrets.
+    pub fn set_compression(&mut self, enabled: bool) {
+        self.compression = enabled;
+    }
+
+    /// Only compress frames to clients whose body is larger than `bytes`
+    /// (`DEFAULT_COMPRESSION_THRESHOLD` by default)
+    pub fn set_compression_threshold(&mut self, bytes: usize) {
+        self.compression_threshold = bytes;
+    }
+
+    /// Allow `send_bytes_acked` on connections (disabled by default); the handshake keeps
+    /// it enabled only for clients that enable acks too
+    pub fn set_acks(&mut self, enabled: bool) {
+        self.acks = enabled;
+    }
+
+    /// Seal a sequence number into every encrypted frame and reject replayed or stale
+    /// frames with `NamedPipeError::ReplayDetected` (disabled by default). Has no effect on
+    /// unencrypted servers. Clients must use the same setting.
+    pub fn enable_replay_protection(&mut self, enabled: bool) {
+        self.replay_protection = enabled;
+    }
+
+    /// Select how messages are delimited; clients must match (`PipeMode::Byte` by default).
+    ///
+    /// `PipeMode::Byte` creates byte-stream pipes and uses the crate's own length-prefix
+    /// framing. `PipeMode::Message` creates `PIPE_TYPE_MESSAGE` pipes where every send is
+    /// one pipe message and no length prefix is written, for interop with peers that
+    /// expect message-mode pipes. Message mode limits payloads to
+    /// `MAX_MESSAGE_MODE_PAYLOAD` bytes.
+    pub fn set_pipe_mode(&mut self, mode: PipeMode) {
+        self.pipe_mode = mode;
+    }
+
+    /// Set the byte order of the frame length prefix for all connections; clients must
+    /// match (little-endian by default)
+    pub fn set_frame_endianness(&mut self, endian: Endian) {
+        self.frame_format.endian = endian;
+    }
+
+    /// Set the layout (byte order and width) of the frame length prefix for all
+    /// connections; clients must match. Use this to interoperate with non-Rust peers such
+    /// as .NET's `NamedPipeServerStream` clients that expect a specific prefix.
+    pub fn set_frame_format(&mut self, format: FrameFormat) {
+        self.frame_format = format;
+    }
+
+    /// Choose how messages are delimited on all connections; clients must match.
+    /// `Framing::None` turns each pipe into a plain byte stream (see `Framed::set_framing`)
+    /// and needs the handshake disabled; `incoming` and `start` fail with
+    /// `NamedPipeError::InvalidConfig` otherwise.
+    pub fn set_framing(&mut self, framing: Framing) {
+        self.framing = framing;
+    }
+
+    /// Reject frames whose payload is larger than `max_size` bytes on all connections
+    /// (unlimited by default).
+    ///
+    /// The limit covers the payload as sent on the wire, including encryption and checksum
+    /// overhead, and also bounds the metadata clients may send at connect. Oversized frames
+    /// fail with `NamedPipeError::MessageTooLarge` before any buffer is allocated.
+    pub fn set_max_message_size(&mut self, max_size: u64) {
+        self.max_message_size = max_size;
+    }
+
+    /// Stop accepting after `limit` clients have connected (unlimited by default).
+    ///
+    /// Every client that opens the pipe counts, including those later rejected by path
+    /// enforcement, the handshake or authentication. Once the limit is reached no new pipe
+    /// instance is created; `incoming` ends after yielding the remaining connections, and
+    /// `start` stops the server and returns while handlers already running finish on their
+    /// own. The count restarts whenever the server is started again.
+    pub fn set_max_total_connections(&mut self, limit: u64) {
+        self.max_total_connections = limit;
+    }
+
+    /// Expect every client to send metadata with `connect_with_metadata` (disabled by
+    /// default).
+    ///
+    /// When enabled, the first frame of each connection is read during setup and exposed
+    /// through `NamedPipeConnection::metadata` before the connection is handed out. Clients
+    /// must then connect with `connect_with_metadata`; a plain `connect` leaves the server
+    /// waiting for metadata.
+    pub fn set_expect_metadata(&mut self, enabled: bool) {
+        self.expect_metadata = enabled;
+    }
+
+    /// Decide from the client's process id and executable path whether to serve it.
+    ///
+    /// The filter runs right after a client opens the pipe, after path enforcement and
+    /// before the handshake, so rejecting a client is cheap. A rejected connection is
+    /// closed with `NamedPipeError::Rejected` and never reaches the handler. Unlike
+    /// authentication, the filter only sees what the client cannot choose freely: its
+    /// process, not anything it sends.
+    pub fn set_accept_filter<F>(&mut self, filter: F)
+    where
+        F: Fn(&ConnectionInfo) -> bool + Send + Sync + 'static,
+    {
+        self.accept_filter = Some(Arc::new(filter));
+    }
+
+    /// Record every refused connection in one place, e.g. for a security audit trail.
+    ///
+    /// `callback` gets an `AuditEvent` with the client's process id and path, the pipe
+    /// name and the step it failed at: path enforcement, the accept filter, the handshake,
+    /// authentication, an oversized frame or missing metadata. It runs on the connection's
+    /// setup task, so it should hand slow work off. Frames that exceed the limit after the
+    /// connection was handed out fail in the handler and are not audited.
+    pub fn set_audit_callback<F>(&mut self, callback: F)
+    where
+        F: Fn(AuditEvent) + Send + Sync + 'static,
+    {
+        self.audit = Some(Arc::new(callback));
+    }
+
+    /// Also report accepted connections to the audit callback (disabled by default)
+    pub fn set_audit_accepts(&mut self, enabled: bool) {
+        self.audit_accepts = enabled;
+    }
+
+    /// Get a stream of lifecycle events for connections accepted from now on: accepts,
+    /// rejections, byte milestones, closes and errors, each with its connection id.
+    ///
+    /// The stream holds up to `EVENT_STREAM_CAPACITY` events and drops the oldest when a
+    /// slow consumer lets it fill up, so the server never waits for it. Only one stream is
+    /// fed at a time; calling this again ends the previous one once its connections are
+    /// gone. Connections split with `into_split` stop reporting.
+    pub fn event_stream(&mut self) -> EventStream {
+        let (sender, stream) = EventStream::channel();
+        self.events = Some(sender);
+        stream
+    }
+
+    /// Report `PipeEvent::BytesMilestone` each time a connection has sent and received
+    /// another `bytes` in total (1 MiB by default); 0 disables milestones
+    pub fn set_byte_milestone(&mut self, bytes: u64) {
+        self.byte_milestone = bytes;
+    }
+
+    /// Require the server's first pipe instance to be the first instance of its name
+    /// (enabled by default).
+    ///
+    /// With this enabled, starting a server whose pipe name is already served, by this or
+    /// another process, fails with `NamedPipeError::PipeNameInUse` instead of silently
+    /// sharing the name. Instances still held by connections from an earlier run count as
+    /// well, so disable this to restart a server while its old connections stay open.
+    pub fn set_first_pipe_instance(&mut self, enabled: bool) {
+        self.first_pipe_instance = enabled;
+    }
+
+    /// Create the next pipe instance as soon as a client connects, before setting up and
+    /// handing out its connection (disabled by default).
+    ///
+    /// Otherwise the next instance is only created once the connection is on its way to
+    /// the handler, and a client opening the pipe in between finds no instance and fails
+    /// with `NotFound`. With this enabled the name is always served, so rapidly
+    /// reconnecting clients at worst see `ERROR_PIPE_BUSY` while the new instance is
+    /// created. Costs nothing extra: the instance is created either way, only earlier.
+    pub fn set_eager_instance(&mut self, enabled: bool) {
+        self.eager_instance = enabled;
+    }
+
+    /// Set the input and output buffer sizes of every pipe instance
+    /// (`DEFAULT_PIPE_BUFFER_SIZE`, 64 KiB, by default).
+    ///
+    /// Larger buffers let big messages move with fewer round trips between reader and
+    /// writer. Windows treats the sizes as advisory. Each size must be between 1 and
+    /// `MAX_PIPE_BUFFER_SIZE` bytes. Takes effect on the next `start`.
+    pub fn set_buffer_sizes(&mut self, in_size: u32, out_size: u32) -> Result<()> {
+        for size in [in_size, out_size] {
+            if size == 0 || size > MAX_PIPE_BUFFER_SIZE {
+                return Err(NamedPipeError::Io(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!(
+                        "Pipe buffer size {} is outside the supported range of 1 to {} bytes",
+                        size, MAX_PIPE_BUFFER_SIZE
+                    ),
+                )));
+            }
+        }
+
+        self.in_buffer_size = in_size;
+        self.out_buffer_size = out_size;
+        Ok(())
+    }
+
+    /// Build the options every pipe instance of this server is created with
+    fn server_options(&self) -> ServerOptions {
         let mut server_options = ServerOptions::new();
 
         // Enable write_dac to allow setting security information
         server_options.write_dac(true);
+        server_options.pipe_mode(self.pipe_mode);
+        server_options.in_buffer_size(self.in_buffer_size);
+        server_options.out_buffer_size(self.out_buffer_size);
+
+        server_options
+    }
 
+    /// Create server with proper security attributes to allow all users
+    fn create_server_with_security(
+        server_options: &ServerOptions,
+        pipe_name: &str,
+    ) -> Result<NamedPipeServer> {
         // Create the server
         let server = server_options
             .create(pipe_name)
@@ -245,30 +1557,297 @@ impl NamedPipeServerStruct {
         F: Fn(NamedPipeConnection) -> Fut + Send + Sync + 'static,
         Fut: std::future::Future<Output = Result<()>> + Send,
     {
-        let mut is_running = self.is_running.lock().await;
+        self.start_with_shutdown(handler, std::future::pending())
+            .await
+    }
+
+    /// Start the server like `start`, stopping it once `shutdown` completes.
+    ///
+    /// When `shutdown` fires the accept loop ends and `stop` is called; handlers that are
+    /// already running are left to finish on their own.
+    pub async fn start_with_shutdown<F, Fut, S>(&mut self, handler: F, shutdown: S) -> Result<()>
+    where
+        F: Fn(NamedPipeConnection) -> Fut + Send + Sync + 'static,
+        Fut: std::future::Future<Output = Result<()>> + Send,
+        S: std::future::Future<Output = ()>,
+    {
+        let handler = Arc::new(handler);
+        let mut incoming = Box::pin(self.incoming());
+        let panic_policy = self.panic_policy;
+        let handler_on_blocking = self.handler_on_blocking;
+        let events = self.events.clone();
+        let (panic_tx, mut panic_rx) = mpsc::unbounded_channel();
+        tokio::pin!(shutdown);
+
+        // Runs until shut down, stopped, or no longer able to accept connections
+        let panicked = loop {
+            let result = tokio::select! {
+                _ = &mut shutdown => break None,
+                Some(message) = panic_rx.recv() => break Some(message),
+                next = incoming.next() => match next {
+                    Some(result) => result,
+                    None => break None,
+                },
+            };
+            let connection = result?;
+
+            // Spawn handler for this connection, catching panics so the policy can apply
+            let handler_clone = Arc::clone(&handler);
+            let panic_tx = panic_tx.clone();
+            let events = events.clone();
+            let task = async move {
+                let id = connection.id();
+                match AssertUnwindSafe(handler_clone(connection))
+                    .catch_unwind()
+                    .await
+                {
+                    Ok(Ok(())) | Ok(Err(NamedPipeError::DisconnectRequested)) => {}
+                    Ok(Err(e)) => {
+                        eprintln!("Connection handler error: {}", e);
+                        report_error(&events, Some(id), &e);
+                    }
+                    Err(panic) => {
+                        let message = panic_message(&*panic);
+                        eprintln!("Connection handler for {} panicked: {}", id, message);
+                        report_error(&events, Some(id), &format!("handler panicked: {}", message));
+                        if panic_policy == PanicPolicy::Propagate {
+                            let _ = panic_tx.send(message);
+                        }
+                    }
+                }
+            };
+            if handler_on_blocking {
+                let runtime = tokio::runtime::Handle::current();
+                tokio::task::spawn_blocking(move || runtime.block_on(task));
+            } else {
+                tokio::spawn(task);
+            }
+        };
+
+        self.stop().await?;
+        match panicked {
+            Some(message) => Err(NamedPipeError::HandlerPanicked(message)),
+            None => Ok(()),
+        }
+    }
+
+    /// Accept a single connection, run `handler` on it to completion, and return its result.
+    ///
+    /// The server stops listening as soon as the first client has passed the connection
+    /// checks; clients rejected by them are not counted. Meant for single-shot tools.
+    pub async fn start_once<F, Fut>(&mut self, handler: F) -> Result<()>
+    where
+        F: FnOnce(NamedPipeConnection) -> Fut,
+        Fut: std::future::Future<Output = Result<()>>,
+    {
+        let mut incoming = Box::pin(self.incoming());
+        let connection = incoming.next().await;
+        self.stop().await?;
+        match connection {
+            Some(connection) => handler(connection?).await,
+            None => Ok(()),
+        }
+    }
+
+    /// Start the server like `start`, stopping it once `token` is cancelled.
+    ///
+    /// Each handler receives a clone of `token` so it can wind down cooperatively, e.g. by
+    /// selecting on `token.cancelled()` next to its receives. This lets the server join an
+    /// application-wide shutdown tree; `stop` keeps working as well.
+    pub async fn start_with_cancellation<F, Fut>(
+        &mut self,
+        token: CancellationToken,
+        handler: F,
+    ) -> Result<()>
+    where
+        F: Fn(NamedPipeConnection, CancellationToken) -> Fut + Send + Sync + 'static,
+        Fut: std::future::Future<Output = Result<()>> + Send,
+    {
+        let handler_token = token.clone();
+        self.start_with_shutdown(
+            move |connection| handler(connection, handler_token.clone()),
+            async move { token.cancelled().await },
+        )
+        .await
+    }
+
+    /// Start the server like `start`, handing each handler a token that is cancelled once
+    /// the server stops, for whatever reason, or the returned future is dropped.
+    ///
+    /// Handlers running receive loops can select on `token.cancelled()` next to each
+    /// receive and exit between messages instead of being cut off mid-read. Unlike
+    /// `start_with_cancellation`, the token belongs to the server; cancel from outside
+    /// through that method or `spawn` instead.
+    pub async fn start_with_handler_token<F, Fut>(&mut self, handler: F) -> Result<()>
+    where
+        F: Fn(NamedPipeConnection, CancellationToken) -> Fut + Send + Sync + 'static,
+        Fut: std::future::Future<Output = Result<()>> + Send,
+    {
+        let token = CancellationToken::new();
+        let handler_token = token.clone();
+        let _cancel_on_stop = token.drop_guard();
+        self.start(move |connection| handler(connection, handler_token.clone()))
+            .await
+    }
+
+    /// Get a signal that fires once the server is listening.
+    ///
+    /// The signal is set when the first pipe instance has been created, so clients can
+    /// connect without sleeping or retrying first. Take it before calling `start`, which
+    /// borrows the server until it stops.
+    pub fn ready_signal(&self) -> ReadySignal {
+        ReadySignal(self.ready.subscribe())
+    }
+
+    /// List the connections handed out and not yet dropped, with their clients and byte
+    /// counts. Clients still being checked or authenticated are not listed. Since `start`
+    /// borrows the server until it stops, use `ServerHandle::connections` or `incoming` to
+    /// call this while serving.
+    pub fn connections(&self) -> Vec<ConnectionSnapshot> {
+        snapshot(&self.registry)
+    }
+
+    /// Disconnect the active connection `id`, e.g. to kick a misbehaving client.
+    ///
+    /// The connection's pending or next receive of a message closes the pipe and fails with
+    /// `NamedPipeError::DisconnectRequested`, which `start` does not report as a handler
+    /// error; handlers doing other work can select on `disconnect_requested`. Streams being
+    /// received are not interrupted. Fails with `NamedPipeError::ConnectionNotFound` if
+    /// `connections` does not list `id`.
+    pub fn disconnect_connection(&self, id: ConnectionId) -> Result<()> {
+        request_disconnect(&self.registry, id, None)
+    }
+
+    /// Disconnect the active connection `id` like `disconnect_connection`, telling the
+    /// client why with a close frame first (which requires frame tags)
+    pub fn disconnect_connection_with_code(&self, id: ConnectionId, code: CloseCode) -> Result<()> {
+        request_disconnect(&self.registry, id, Some(code))
+    }
+
+    /// Start the server on a spawned task and return a handle to manage it.
+    ///
+    /// For embedding the server in a larger application or library without awaiting
+    /// `start` yourself: the handle's `shutdown` stops the server gracefully (as
+    /// `start_with_shutdown` does) and waits for it, while `join` only waits.
+    pub fn spawn<F, Fut>(self, handler: F) -> ServerHandle
+    where
+        F: Fn(NamedPipeConnection) -> Fut + Send + Sync + 'static,
+        Fut: std::future::Future<Output = Result<()>> + Send + 'static,
+    {
+        let token = CancellationToken::new();
+        let shutdown = token.clone().cancelled_owned();
+        let ready = self.ready_signal();
+        let registry = Arc::clone(&self.registry);
+        let mut server = self;
+        let task = tokio::spawn(async move { server.start_with_shutdown(handler, shutdown).await });
+        ServerHandle {
+            token,
+            task,
+            ready,
+            registry,
+        }
+    }
+
+    /// Start accepting connections and return them as a stream.
+    ///
+    /// Each item is a connection that has already passed path enforcement and
+    /// authentication (if configured); clients that fail those checks are dropped and never
+    /// yielded. The caller decides how to handle each connection, e.g. by spawning it onto
+    /// its own task pool. The stream ends once `stop` is called and every pending connection
+    /// has been yielded, or after yielding an error if a pipe instance cannot be created.
+    ///
+    /// If the server is already running, the stream yields a single
+    /// `NamedPipeError::ServerAlreadyRunning` error.
+    pub fn incoming(&mut self) -> impl Stream<Item = Result<NamedPipeConnection>> + Send + 'static {
+        let (connection_tx, connection_rx) = mpsc::channel(INCOMING_QUEUE_SIZE);
+
+        let checked = self
+            .check_framing()
+            .and_then(|()| self.check_encryption())
+            .and_then(|()| self.mark_running());
+        if let Err(e) = checked {
+            let _ = connection_tx.try_send(Err(e));
+        } else {
+            let (shutdown_tx, shutdown_rx) = broadcast::channel(1);
+            self.shutdown_tx = Some(shutdown_tx);
+            self.server_handle = Some(tokio::spawn(self.accept_loop(connection_tx, shutdown_rx)));
+        }
+
+        stream::unfold(connection_rx, |mut connection_rx| async move {
+            let item = connection_rx.recv().await?;
+            Some((item, connection_rx))
+        })
+    }
+
+    /// Flag the server as running, failing if it already is
+    fn mark_running(&self) -> Result<()> {
+        let already_running = NamedPipeError::ServerAlreadyRunning(self.pipe_name.clone());
+        let mut is_running = self.is_running.try_lock().map_err(|_| already_running)?;
         if *is_running {
             return Err(NamedPipeError::ServerAlreadyRunning(self.pipe_name.clone()));
         }
         *is_running = true;
-        drop(is_running);
-
-        let (shutdown_tx, mut shutdown_rx) = broadcast::channel(1);
-        self.shutdown_tx = Some(shutdown_tx.clone());
+        Ok(())
+    }
 
+    /// Build the accept loop task, which sends accepted connections into `connection_tx`
+    fn accept_loop(
+        &self,
+        connection_tx: mpsc::Sender<Result<NamedPipeConnection>>,
+        mut shutdown_rx: broadcast::Receiver<()>,
+    ) -> impl std::future::Future<Output = Result<()>> + Send + 'static {
         let pipe_name = self.pipe_name.clone();
-        let connection_counter = Arc::clone(&self.connection_counter);
-        let handler = Arc::new(handler);
-        let cipher_key = self.cipher_key;
+        let cipher = self.pipe_cipher();
         let enforce_same_path_client = self.enforce_same_path_client;
+        let setup = SetupOptions {
+            accept_filter: self.accept_filter.clone(),
+            keys: Arc::clone(&self.keys),
+            active_key: self.active_key,
+            handshake: self.handshake,
+            encryption_policy: self.encryption_policy,
+            derive_from: self.cipher_key.filter(|_| self.derive_keys),
+            shared_secret: self.shared_secret.clone(),
+            expect_metadata: self.expect_metadata,
+            rejection_reasons: self.rejection_reasons,
+            pipe_name: self.pipe_name.clone(),
+            audit: self.audit.clone(),
+            audit_accepts: self.audit_accepts,
+            handshake_timeout: self.handshake_timeout,
+        };
+        let ready = Arc::clone(&self.ready);
+        let max_total_connections = self.max_total_connections;
+        let integrity_check = self.integrity_check;
+        let compression = self.compression;
+        let compression_threshold = self.compression_threshold;
+        let acks = self.acks;
+        let replay_protection = self.replay_protection;
+        let pipe_mode = self.pipe_mode;
+        let frame_format = self.frame_format;
+        let framing = self.framing;
+        let max_message_size = self.max_message_size;
+        let events = self.events.clone();
+        let byte_milestone = self.byte_milestone;
+        let registry = Arc::clone(&self.registry);
+        let eager_instance = self.eager_instance;
+        let server_options = self.server_options();
+        let mut first_options = server_options.clone();
+        first_options.first_pipe_instance(self.first_pipe_instance);
 
-        let handle = tokio::spawn(async move {
+        async move {
             // Create the first server instance with security attributes
-            let mut current_server = match Self::create_server_with_security(&pipe_name) {
+            let first_server = Self::create_server_with_security(&first_options, &pipe_name)
+                .map_err(|e| name_collision_error(e, &pipe_name));
+            let mut current_server = match first_server {
                 Ok(server) => server,
-                Err(e) => return Err(e),
+                Err(e) => {
+                    let _ = connection_tx.send(Err(e)).await;
+                    return Ok(());
+                }
             };
+            ready.send_replace(true);
 
-            loop {
+            let mut accepted: u64 = 0;
+            while accepted < max_total_connections {
                 tokio::select! {
                     // Check for shutdown signal
                     _ = shutdown_rx.recv() => {
@@ -280,46 +1859,77 @@ impl NamedPipeServerStruct {
                     result = current_server.connect() => {
                         match result {
                             Ok(_) => {
-                                // Get connection ID
-                                let mut counter = connection_counter.lock().await;
-                                *counter += 1;
-                                let connection_id = *counter;
-                                drop(counter);
-
-                                // Create connection (encrypted if cipher_key is provided)
-                                let connection = if let Some(key) = cipher_key {
-                                    NamedPipeConnection::new_encrypted(current_server, connection_id, &key, enforce_same_path_client)
+                                // In eager mode, serve the name again before anything else
+                                let next_server = (eager_instance && accepted + 1 < max_total_connections)
+                                    .then(|| Self::create_server_with_security(&server_options, &pipe_name));
+                                let connection_id = next_connection_id();
+
+                                // Create connection (encrypted if a cipher is configured)
+                                let mut connection = if let Some(cipher) = cipher.as_ref() {
+                                    let mut connection = NamedPipeConnection::with_cipher(current_server, connection_id, cipher.cipher().clone(), enforce_same_path_client);
+                                    connection.framed.set_cipher_key(cipher.key());
+                                    connection
                                 } else {
                                     NamedPipeConnection::new(current_server, connection_id, enforce_same_path_client)
                                 };
+                                connection.set_integrity_check(integrity_check);
+                                connection.set_compression(compression);
+                                connection.framed.set_compression_threshold(compression_threshold);
+                                connection.framed.set_acks(acks);
+                                connection.enable_replay_protection(replay_protection);
+                                connection.framed.set_pipe_mode(pipe_mode);
+                                connection.framed.set_frame_format(frame_format);
+                                connection.framed.set_framing(framing);
+                                connection.set_max_message_size(max_message_size);
 
-                                // Spawn handler for this connection
-                                let handler_clone = Arc::clone(&handler);
+                                // Set the connection up on its own task so a slow client cannot stall accepts
+                                let setup_tx = connection_tx.clone();
+                                let setup = setup.clone();
+                                let setup_events = events.clone();
+                                let setup_registry = Arc::clone(&registry);
                                 tokio::spawn(async move {
-                                    // Verify client path if enforcement is enabled
-                                    if let Err(e) = connection.verify_client_path() {
-                                        eprintln!("Client path verification failed: {}", e);
-                                        return;
-                                    }
-
-                                    if let Err(e) = handler_clone(connection).await {
-                                        eprintln!("Connection handler error: {}", e);
+                                    match Self::setup_connection(connection, setup).await {
+                                        Ok(mut connection) => {
+                                            connection.register(setup_registry);
+                                            if let Some(events) = setup_events {
+                                                events.send(PipeEvent::Accepted { id: connection_id });
+                                                connection.attach_events(events, byte_milestone);
+                                            }
+                                            let _ = setup_tx.send(Ok(connection)).await;
+                                        }
+                                        Err(e) => {
+                                            eprintln!("Client rejected: {}", e);
+                                            if let Some(events) = setup_events {
+                                                events.send(PipeEvent::Rejected { id: connection_id, reason: e.to_string() });
+                                            }
+                                        }
                                     }
                                 });
 
+                                accepted += 1;
+                                if accepted >= max_total_connections {
+                                    break;
+                                }
+
                                 // Create a new server instance for the next connection
-                                match Self::create_server_with_security(&pipe_name) {
+                                let next_server = next_server.unwrap_or_else(|| {
+                                    Self::create_server_with_security(&server_options, &pipe_name)
+                                });
+                                match next_server {
                                     Ok(server) => {
                                         current_server = server;
                                     }
                                     Err(e) => {
                                         eprintln!("Failed to create new server instance: {}", e);
+                                        report_error(&events, None, &e);
+                                        let _ = connection_tx.send(Err(e)).await;
                                         break;
                                     }
                                 }
                             }
                             Err(e) => {
                                 eprintln!("Failed to accept connection: {}", e);
+                                report_error(&events, None, &e);
                                 tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
                             }
                         }
@@ -328,20 +1938,171 @@ impl NamedPipeServerStruct {
             }
 
             Ok(())
+        }
+    }
+
+    /// Run the per-connection checks that must pass before a connection is handed out,
+    /// reporting the decision to the audit callback if one is set
+    async fn setup_connection(
+        connection: NamedPipeConnection,
+        setup: SetupOptions,
+    ) -> Result<NamedPipeConnection> {
+        let Some(audit) = setup.audit.clone() else {
+            return Self::run_checks(connection, setup, &mut RejectionKind::PathMismatch).await;
+        };
+
+        // Query the client before the checks, which may close the pipe
+        let id = connection.id;
+        let pid = get_client_pid(connection.framed.get_ref()).ok();
+        let path = pid.and_then(|pid| get_process_path(pid).ok());
+        let pipe_name = setup.pipe_name.clone();
+        let audit_accepts = setup.audit_accepts;
+
+        let mut step = RejectionKind::PathMismatch;
+        let result = Self::run_checks(connection, setup, &mut step).await;
+        let outcome = match &result {
+            Ok(_) if !audit_accepts => return result,
+            Ok(_) => AuditOutcome::Accepted,
+            Err(e) => AuditOutcome::Rejected {
+                kind: match e {
+                    NamedPipeError::MessageTooLarge { .. } => RejectionKind::FrameTooLarge,
+                    _ => step,
+                },
+                reason: e.to_string(),
+            },
+        };
+        audit(AuditEvent {
+            id,
+            pid,
+            path,
+            pipe_name,
+            outcome,
         });
+        result
+    }
 
-        // Wait for the server task to complete (this will block until shutdown)
-        match handle.await {
-            Ok(result) => result,
-            Err(e) => Err(NamedPipeError::Io(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                e,
-            ))),
+    /// Run `check_connection` within the handshake timeout, if one is set
+    async fn run_checks(
+        connection: NamedPipeConnection,
+        setup: SetupOptions,
+        step: &mut RejectionKind,
+    ) -> Result<NamedPipeConnection> {
+        match setup.handshake_timeout {
+            Some(limit) => {
+                tokio::time::timeout(limit, Self::check_connection(connection, setup, step))
+                    .await
+                    .map_err(|_| NamedPipeError::HandshakeTimeout)?
+            }
+            None => Self::check_connection(connection, setup, step).await,
         }
     }
 
+    /// The checks behind `setup_connection`, keeping `step` at the one currently running
+    async fn check_connection(
+        mut connection: NamedPipeConnection,
+        setup: SetupOptions,
+        step: &mut RejectionKind,
+    ) -> Result<NamedPipeConnection> {
+        // Verify client path if enforcement is enabled
+        *step = RejectionKind::PathMismatch;
+        if let Err(e) = connection.verify_client_path() {
+            return Err(Self::reject(connection, &setup, "client path does not match", e).await);
+        }
+
+        *step = RejectionKind::Filtered;
+        if let Some(filter) = setup.accept_filter.as_ref() {
+            let pid = get_client_pid(connection.framed.get_ref())?;
+            let info = ConnectionInfo {
+                id: connection.id,
+                pid,
+                path: get_process_path(pid).ok(),
+            };
+            if !filter(&info) {
+                let e = NamedPipeError::Rejected;
+                return Err(
+                    Self::reject(connection, &setup, "refused by the accept filter", e).await,
+                );
+            }
+        }
+
+        connection
+            .framed
+            .install_keys(&setup.keys, setup.active_key)?;
+
+        // Negotiate encryption before anything else is exchanged
+        *step = RejectionKind::Handshake;
+        if setup.handshake {
+            connection
+                .accept_hello(setup.encryption_policy, setup.derive_from.is_some())
+                .await?;
+            if let Some(master_key) = setup.derive_from {
+                connection.derive_key(&master_key).await?;
+            }
+        } else if setup.derive_from.is_some() {
+            return Err(NamedPipeError::InvalidConfig(
+                "key derivation needs the handshake".to_string(),
+            ));
+        }
+
+        // Authenticate the client if a shared secret is configured
+        *step = RejectionKind::AuthFailed;
+        if let Some(secret) = setup.shared_secret.as_ref() {
+            if let Err(e) = connection.authenticate(secret).await {
+                if setup.rejection_reasons && matches!(e, NamedPipeError::AuthFailed) {
+                    let _ = connection
+                        .framed
+                        .send_rejection("authentication failed")
+                        .await;
+                }
+                return Err(e);
+            }
+        }
+
+        // The client's metadata is its first frame after authentication
+        *step = RejectionKind::Metadata;
+        if setup.expect_metadata {
+            connection.metadata = connection.framed.receive_bytes().await?;
+        }
+
+        Ok(connection)
+    }
+
+    /// Tell a client refused before the hello why, if enabled, and return the error
+    /// `setup_connection` fails with. Failing to deliver the reason does not change it.
+    async fn reject(
+        mut connection: NamedPipeConnection,
+        setup: &SetupOptions,
+        reason: &str,
+        error: NamedPipeError,
+    ) -> NamedPipeError {
+        if setup.rejection_reasons && setup.handshake {
+            let _ = reject_hello(&mut *connection.framed, reason).await;
+        }
+        error
+    }
+
+    fn check_encryption(&self) -> Result<()> {
+        let has_key = self.cipher_key.is_some() || self.shared_cipher.is_some();
+        if self.encryption_policy == EncryptionPolicy::Required && !has_key {
+            return Err(NamedPipeError::InvalidConfig(
+                "requiring encryption needs a key".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    fn check_framing(&self) -> Result<()> {
+        if self.framing == Framing::None && self.handshake {
+            return Err(NamedPipeError::InvalidConfig(
+                "raw framing needs the handshake disabled".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
     /// Stop the server
     pub async fn stop(&mut self) -> Result<()> {
+        self.ready.send_replace(false);
         if let Some(tx) = &self.shutdown_tx {
             let _ = tx.send(());
         }
@@ -367,6 +2128,177 @@ impl NamedPipeServerStruct {
     pub fn pipe_name(&self) -> &str {
         &self.pipe_name
     }
+
+    /// Check the configured key and cipher suite before serving any traffic: the key must
+    /// not be weak and must round-trip a test message (see `Cipher::self_test`). Always
+    /// succeeds on unencrypted servers.
+    pub fn verify_crypto_setup(&self) -> Result<()> {
+        if let Some(key) = self.cipher_key {
+            check_key(&key)?;
+        }
+        match self.pipe_cipher() {
+            Some(cipher) => cipher.cipher().self_test(),
+            None => Ok(()),
+        }
+    }
+
+    /// The cipher for new connections: the shared one, or one built once from the key
+    fn pipe_cipher(&self) -> Option<PipeCipher> {
+        match (&self.shared_cipher, self.cipher_key) {
+            (Some(cipher), _) => Some(cipher.clone()),
+            (None, Some(key)) => Some(PipeCipher::with_key(self.cipher_suite, &key)),
+            (None, None) => None,
+        }
+    }
+
+    /// Get the settings currently in effect, e.g. to log them at startup
+    pub fn effective_config(&self) -> ServerConfig {
+        ServerConfig {
+            pipe_name: self.pipe_name.clone(),
+            encrypted: self.cipher_key.is_some() || self.shared_cipher.is_some(),
+            cipher_suite: self
+                .shared_cipher
+                .as_ref()
+                .map_or(self.cipher_suite, PipeCipher::suite),
+            derive_keys: self.derive_keys,
+            encryption_policy: self.encryption_policy,
+            handshake: self.handshake,
+            handshake_timeout: self.handshake_timeout,
+            shared_secret: self.shared_secret.is_some(),
+            enforce_same_path_client: self.enforce_same_path_client,
+            accept_filter: self.accept_filter.is_some(),
+            audit_callback: self.audit.is_some(),
+            audit_accepts: self.audit_accepts,
+            event_stream: self.events.is_some(),
+            byte_milestone: self.byte_milestone,
+            integrity_check: self.integrity_check,
+            compression: self.compression,
+            compression_threshold: self.compression_threshold,
+            acks: self.acks,
+            replay_protection: self.replay_protection,
+            pipe_mode: self.pipe_mode,
+            frame_format: self.frame_format,
+            framing: self.framing,
+            max_message_size: self.max_message_size,
+            expect_metadata: self.expect_metadata,
+            first_pipe_instance: self.first_pipe_instance,
+            eager_instance: self.eager_instance,
+            in_buffer_size: self.in_buffer_size,
+            out_buffer_size: self.out_buffer_size,
+            panic_policy: self.panic_policy,
+            handler_on_blocking: self.handler_on_blocking,
+            max_total_connections: self.max_total_connections,
+        }
+    }
+}
+
+/// Report an error to the event stream, if there is one
+fn report_error(
+    events: &Option<EventSender>,
+    id: Option<ConnectionId>,
+    error: &dyn std::fmt::Display,
+) {
+    if let Some(events) = events {
+        events.send(PipeEvent::Error {
+            id,
+            message: error.to_string(),
+        });
+    }
+}
+
+/// Fires once a server is listening; see `NamedPipeServerStruct::ready_signal`
+#[derive(Clone)]
+pub struct ReadySignal(watch::Receiver<bool>);
+
+impl ReadySignal {
+    /// Wait until the server is listening. Returns immediately if it already is, and fails
+    /// if the server is dropped before it ever gets there.
+    pub async fn wait(&mut self) -> Result<()> {
+        self.0.wait_for(|ready| *ready).await.map_err(|_| {
+            NamedPipeError::Io(std::io::Error::other("Server stopped before it was ready"))
+        })?;
+        Ok(())
+    }
+}
+
+/// Handle to a server started with `NamedPipeServerStruct::spawn`.
+///
+/// Dropping the handle leaves the server running in the background.
+pub struct ServerHandle {
+    token: CancellationToken,
+    task: JoinHandle<Result<()>>,
+    ready: ReadySignal,
+    registry: Registry,
+}
+
+impl ServerHandle {
+    /// Wait until the server is listening, so clients can connect right away.
+    /// Fails if the server stops first, e.g. because the pipe name is taken.
+    pub async fn ready(&self) -> Result<()> {
+        self.ready.clone().wait().await
+    }
+
+    /// Stop accepting connections and wait for the server to finish stopping.
+    /// Handlers that are already running are left to finish on their own.
+    pub async fn shutdown(self) -> Result<()> {
+        self.token.cancel();
+        self.join().await
+    }
+
+    /// Wait for the server to stop, returning the error it stopped with, if any
+    pub async fn join(self) -> Result<()> {
+        self.task
+            .await
+            .map_err(|e| NamedPipeError::Io(std::io::Error::other(e)))?
+    }
+
+    /// List the server's active connections; see `NamedPipeServerStruct::connections`
+    pub fn connections(&self) -> Vec<ConnectionSnapshot> {
+        snapshot(&self.registry)
+    }
+
+    /// Disconnect one of the server's connections; see
+    /// `NamedPipeServerStruct::disconnect_connection`
+    pub fn disconnect_connection(&self, id: ConnectionId) -> Result<()> {
+        request_disconnect(&self.registry, id, None)
+    }
+
+    /// Disconnect one of the server's connections with a close frame; see
+    /// `NamedPipeServerStruct::disconnect_connection_with_code`
+    pub fn disconnect_connection_with_code(&self, id: ConnectionId, code: CloseCode) -> Result<()> {
+        request_disconnect(&self.registry, id, Some(code))
+    }
+
+    /// Whether the server has already stopped
+    pub fn is_finished(&self) -> bool {
+        self.task.is_finished()
+    }
+}
+
+/// Extract the message of a caught panic, if it carried one
+fn panic_message(panic: &(dyn std::any::Any + Send)) -> String {
+    if let Some(message) = panic.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = panic.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "unknown panic".to_string()
+    }
+}
+
+/// Report a failure to create the first pipe instance as `PipeNameInUse` when Windows
+/// refused it because another server already owns the name
+fn name_collision_error(e: NamedPipeError, pipe_name: &str) -> NamedPipeError {
+    match e {
+        NamedPipeError::Io(ref io)
+            if [ERROR_ACCESS_DENIED, ERROR_ALREADY_EXISTS]
+                .iter()
+                .any(|code| io.raw_os_error() == Some(code.0 as i32)) =>
+        {
+            NamedPipeError::PipeNameInUse(pipe_name.to_string())
+        }
+        e => e,
+    }
 }
 
 impl Drop for NamedPipeServerStruct {
@@ -0,0 +1,341 @@
+//! Splitting a framed stream into halves owned by different tasks.
+//!
+//! `into_split` hands the receiving side to a single `FramedReadHalf` and the sending side
+//! to a `FramedWriteHalf`, which any number of tasks can share by cloning it. Both halves
+//! keep the settings the stream had when it was split: cipher and key ids, frame tags,
+//! format and limits, and both share one rate limit. Streams with acks enabled cannot be
+//! split.
+//!
+//! Every send on the write half takes an internal lock, so frames from different tasks
+//! never interleave mid-frame. To send several frames with nothing from other tasks in
+//! between, take the lock for the whole sequence with `write_batch`.
+//!
+//! `send_bytes_nowait` hands a frame to a background writer task and returns at once,
+//! without waiting for the lock or the write. Its frames are written in the order they were
+//! queued; a write error is reported by the next send or `flush` on any clone, so it may
+//! belong to an earlier frame than the call reporting it.
+
+use crate::error::{NamedPipeError, Result};
+use crate::framing::{Framed, Priority};
+use std::ops::{Deref, DerefMut};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf, ReadHalf, WriteHalf};
+use tokio::sync::{mpsc, Mutex, Notify, OwnedMutexGuard};
+
+/// The receiving half of a split framed stream
+pub type FramedReadHalf<S> = Framed<ReadOnly<ReadHalf<S>>>;
+
+/// The framed writer shared by the clones of a `FramedWriteHalf`
+pub type FramedWriter<S> = Framed<WriteOnly<WriteHalf<S>>>;
+
+/// A stream half that can only be read; writes fail with `Unsupported`
+pub struct ReadOnly<T>(T);
+
+/// A stream half that can only be written; reads fail with `Unsupported`
+pub struct WriteOnly<T>(T);
+
+impl<T: AsyncRead + Unpin> AsyncRead for ReadOnly<T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.0).poll_read(cx, buf)
+    }
+}
+
+impl<T> AsyncWrite for ReadOnly<T> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        _buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Poll::Ready(Err(unsupported("The read half cannot send")))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<T> AsyncRead for WriteOnly<T> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        _buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Err(unsupported("The write half cannot receive")))
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for WriteOnly<T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.0).poll_shutdown(cx)
+    }
+}
+
+fn unsupported(msg: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::Unsupported, msg.to_string())
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Framed<S> {
+    /// Split into a read half and a write half that can be used from different tasks.
+    ///
+    /// Fails with `NamedPipeError::InvalidConfig` while acks are enabled: the read half
+    /// cannot write the acks the peer asks for, and the write half cannot read its own.
+    pub fn into_split(self) -> Result<(FramedReadHalf<S>, FramedWriteHalf<S>)> {
+        if self.acks_enabled() {
+            return Err(NamedPipeError::InvalidConfig(
+                "acks cannot be used on a split stream".to_string(),
+            ));
+        }
+        let (reader, writer) = self.into_halves(|stream| {
+            let (read_half, write_half) = tokio::io::split(stream);
+            (ReadOnly(read_half), WriteOnly(write_half))
+        });
+        let writer = FramedWriteHalf {
+            framed: Arc::new(Mutex::new(writer)),
+            queue: Arc::default(),
+            background: Arc::default(),
+        };
+        Ok((reader, writer))
+    }
+}
+
+/// The sending half of a split framed stream; clones share the same stream
+pub struct FramedWriteHalf<S> {
+    framed: Arc<Mutex<FramedWriter<S>>>,
+    /// Feeds the background writer, started by the first `send_bytes_nowait`. Only the
+    /// clones hold it, so the writer ends once they are all dropped and its queue is empty.
+    queue: Arc<std::sync::Mutex<Option<mpsc::UnboundedSender<Vec<u8>>>>>,
+    background: Arc<Background>,
+}
+
+/// State shared with the background writer
+#[derive(Default)]
+struct Background {
+    /// Frames queued but not yet written
+    pending: AtomicUsize,
+    drained: Notify,
+    /// The first write error not yet reported
+    error: std::sync::Mutex<Option<NamedPipeError>>,
+}
+
+impl<S> Clone for FramedWriteHalf<S> {
+    fn clone(&self) -> Self {
+        Self {
+            framed: Arc::clone(&self.framed),
+            queue: Arc::clone(&self.queue),
+            background: Arc::clone(&self.background),
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send + 'static> FramedWriteHalf<S> {
+    /// Send raw bytes as a single frame
+    pub async fn send_bytes(&self, data: &[u8]) -> Result<()> {
+        self.take_background_error()?;
+        self.framed.lock().await.send_bytes(data).await
+    }
+
+    /// Send raw bytes ahead of buffered frames of lower priority; see `Framed::send_bytes_priority`
+    pub async fn send_bytes_priority(&self, data: &[u8], priority: Priority) -> Result<()> {
+        self.take_background_error()?;
+        self.framed
+            .lock()
+            .await
+            .send_bytes_priority(data, priority)
+            .await
+    }
+
+    /// Send a string message
+    pub async fn send_string(&self, message: &str) -> Result<()> {
+        self.take_background_error()?;
+        self.framed.lock().await.send_string(message).await
+    }
+
+    /// Send a JSON message
+    pub async fn send_json<T: serde::Serialize>(&self, message: &T) -> Result<()> {
+        self.take_background_error()?;
+        self.framed.lock().await.send_json(message).await
+    }
+
+    /// Queue raw bytes as a single frame for the background writer and return without
+    /// waiting, trading delivery confirmation for latency, e.g. for telemetry. Must be
+    /// called within a Tokio runtime.
+    ///
+    /// Frames queued this way are written in order, but sends that wait may overtake them;
+    /// `flush` waits until all of them are written. A write error is returned by the next
+    /// send or `flush` instead, possibly for a frame queued well before that call, and only
+    /// once. The queue is unbounded, so a peer that stops reading makes it grow.
+    pub fn send_bytes_nowait(&self, data: &[u8]) -> Result<()> {
+        self.take_background_error()?;
+        let mut queue = self.queue.lock().unwrap();
+        let sender = queue.get_or_insert_with(|| {
+            let (sender, receiver) = mpsc::unbounded_channel();
+            tokio::spawn(write_in_background(
+                Arc::clone(&self.framed),
+                Arc::clone(&self.background),
+                receiver,
+            ));
+            sender
+        });
+        self.background.pending.fetch_add(1, Ordering::AcqRel);
+        sender.send(data.to_vec()).map_err(|_| {
+            self.background.frame_done();
+            NamedPipeError::ConnectionClosed
+        })
+    }
+
+    /// Wait until every frame queued with `send_bytes_nowait` is written, then write any
+    /// buffered frames to the stream and flush it. Reports a failed background write, and
+    /// fails with `ConnectionClosed` if the background writer stopped, e.g. because its
+    /// runtime shut down, before writing every queued frame.
+    pub async fn flush(&self) -> Result<()> {
+        let sender = self.queue.lock().unwrap().clone();
+        loop {
+            let drained = self.background.drained.notified();
+            tokio::pin!(drained);
+            drained.as_mut().enable();
+            if self.background.pending.load(Ordering::Acquire) == 0 {
+                break;
+            }
+            match &sender {
+                Some(sender) => tokio::select! {
+                    _ = drained => {}
+                    _ = sender.closed() => {
+                        self.take_background_error()?;
+                        return Err(NamedPipeError::ConnectionClosed);
+                    }
+                },
+                None => drained.await,
+            }
+        }
+        self.take_background_error()?;
+        self.framed.lock().await.flush().await
+    }
+
+    /// Report a background write that failed since the last report
+    fn take_background_error(&self) -> Result<()> {
+        match self.background.error.lock().unwrap().take() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    /// Lock the writer for a sequence of frames that must not be interleaved with frames
+    /// from other tasks, waiting for a batch or send in progress to finish first.
+    ///
+    /// The guard gives full access to the writer. Dropping it flushes any frames still
+    /// buffered on a spawned task, which keeps the lock until they are written; call
+    /// `finish` instead to wait for the flush and see its result.
+    ///
+    /// Every other sender on this write half waits while the guard is held. Do not hold it
+    /// across an await that depends on them, such as waiting on the read half for a reply
+    /// the peer only sends after receiving another task's frames: that deadlocks.
+    pub async fn write_batch(&self) -> WriteGuard<S> {
+        WriteGuard {
+            framed: Some(Arc::clone(&self.framed).lock_owned().await),
+        }
+    }
+}
+
+/// Write the frames queued by `send_bytes_nowait` until every write half is dropped,
+/// keeping the first error for the next send or flush to report
+async fn write_in_background<S: AsyncRead + AsyncWrite + Unpin + Send + 'static>(
+    framed: Arc<Mutex<FramedWriter<S>>>,
+    background: Arc<Background>,
+    mut queue: mpsc::UnboundedReceiver<Vec<u8>>,
+) {
+    while let Some(data) = queue.recv().await {
+        if let Err(e) = framed.lock().await.send_bytes(&data).await {
+            background.error.lock().unwrap().get_or_insert(e);
+        }
+        background.frame_done();
+    }
+}
+
+impl Background {
+    /// Count a queued frame as written or dropped, waking `flush` after the last one
+    fn frame_done(&self) {
+        if self.pending.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.drained.notify_waiters();
+        }
+    }
+}
+
+/// Exclusive access to a `FramedWriteHalf` for a batch of frames; see `write_batch`
+pub struct WriteGuard<S: AsyncRead + AsyncWrite + Unpin + Send + 'static> {
+    framed: Option<OwnedMutexGuard<FramedWriter<S>>>,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send + 'static> WriteGuard<S> {
+    /// Flush the batch and release the writer
+    pub async fn finish(mut self) -> Result<()> {
+        match self.framed.take() {
+            Some(mut framed) => framed.flush().await,
+            None => Ok(()),
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send + 'static> Deref for WriteGuard<S> {
+    type Target = FramedWriter<S>;
+
+    fn deref(&self) -> &Self::Target {
+        self.framed
+            .as_ref()
+            .expect("guard is only emptied when consumed")
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send + 'static> DerefMut for WriteGuard<S> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.framed
+            .as_mut()
+            .expect("guard is only emptied when consumed")
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send + 'static> Drop for WriteGuard<S> {
+    fn drop(&mut self) {
+        let Some(mut framed) = self.framed.take() else {
+            return;
+        };
+        if framed.buffered_len() == 0 {
+            return;
+        }
+        match tokio::runtime::Handle::try_current() {
+            Ok(runtime) => {
+                runtime.spawn(async move {
+                    if let Err(e) = framed.flush().await {
+                        eprintln!("Failed to flush write batch: {}", e);
+                    }
+                });
+            }
+            Err(_) => eprintln!(
+                "Warning: write batch dropped outside a runtime with {} unflushed bytes",
+                framed.buffered_len()
+            ),
+        }
+    }
+}
@@ -0,0 +1,211 @@
+//! Resumable transfer of large payloads as a sequence of frames.
+//!
+//! `send_stream` copies a reader to the peer in chunks of at most `STREAM_CHUNK_SIZE`
+//! bytes, or as set with `Framed::set_stream_chunk_size`. Each chunk frame is the chunk's
+//! offset in the whole stream as a big-endian `u64`, followed by its data; a chunk without
+//! data marks the end. Every `STREAM_ACK_INTERVAL` chunks, and after the end marker, the
+//! receiver answers with an acknowledgement frame holding the offset up to which it has
+//! received and written everything. The sender waits for each acknowledgement before going
+//! on, so it never gets more than one interval ahead.
+//!
+//! If the transfer breaks, both sides fail with `NamedPipeError::StreamInterrupted`, which
+//! carries the offset the sender saw acknowledged or the receiver has written. After
+//! reconnecting, the sender calls `resume_stream` with its offset and the receiver calls
+//! `resume_receive_stream` with its own. The receiver may be ahead of the last
+//! acknowledgement; chunks it already has are dropped.
+//!
+//! The receiver collects data until it holds its own chunk size, or an acknowledgement is
+//! due, before writing it out, so it accepts chunks of any size. Its offset only counts
+//! data written, so collected data lost to an interruption is sent again on resume.
+
+use crate::error::{NamedPipeError, Result};
+use crate::framing::Framed;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Default largest amount of data carried by one stream chunk
+pub const STREAM_CHUNK_SIZE: usize = 32 * 1024;
+
+/// Number of chunks the receiver acknowledges at once
+pub const STREAM_ACK_INTERVAL: u64 = 16;
+
+/// Length of the offset opening every chunk and acknowledgement
+const OFFSET_LEN: usize = 8;
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Framed<S> {
+    /// Send everything `reader` yields as a stream, returning its total length
+    pub async fn send_stream<R: AsyncRead + Unpin>(&mut self, reader: R) -> Result<u64> {
+        self.resume_stream(0, reader).await
+    }
+
+    /// Send a stream starting at `from_offset`, skipping the bytes of `reader` before it.
+    ///
+    /// `reader` must yield the stream from its beginning, like the original transfer did;
+    /// pass the offset from the `StreamInterrupted` error of that transfer.
+    pub async fn resume_stream<R: AsyncRead + Unpin>(
+        &mut self,
+        from_offset: u64,
+        mut reader: R,
+    ) -> Result<u64> {
+        let mut acked = from_offset;
+        self.send_stream_chunks(from_offset, &mut reader, &mut acked)
+            .await
+            .map_err(|e| interrupted(acked, e))
+    }
+
+    /// Receive a stream into `writer`, returning its total length
+    pub async fn receive_stream<W: AsyncWrite + Unpin>(&mut self, writer: W) -> Result<u64> {
+        self.resume_receive_stream(0, writer).await
+    }
+
+    /// Receive the rest of a stream of which the first `from_offset` bytes were already
+    /// written, e.g. the offset from the `StreamInterrupted` error of the original transfer
+    pub async fn resume_receive_stream<W: AsyncWrite + Unpin>(
+        &mut self,
+        from_offset: u64,
+        mut writer: W,
+    ) -> Result<u64> {
+        let mut received = from_offset;
+        self.receive_stream_chunks(&mut writer, &mut received)
+            .await
+            .map_err(|e| interrupted(received, e))
+    }
+
+    async fn send_stream_chunks<R: AsyncRead + Unpin>(
+        &mut self,
+        from_offset: u64,
+        reader: &mut R,
+        acked: &mut u64,
+    ) -> Result<u64> {
+        let skipped =
+            tokio::io::copy(&mut reader.take(from_offset), &mut tokio::io::sink()).await?;
+        if skipped < from_offset {
+            return Err(NamedPipeError::Io(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                format!("Stream ends at {} bytes, before the resume offset", skipped),
+            )));
+        }
+
+        let mut offset = from_offset;
+        let mut chunk = vec![0u8; OFFSET_LEN + self.stream_chunk_size()];
+        let mut unacked_chunks = 0;
+        loop {
+            chunk[..OFFSET_LEN].copy_from_slice(&offset.to_be_bytes());
+            let read = reader.read(&mut chunk[OFFSET_LEN..]).await?;
+            self.send_bytes(&chunk[..OFFSET_LEN + read]).await?;
+            offset += read as u64;
+            if read == 0 {
+                self.flush().await?;
+                *acked = self.receive_stream_ack(offset).await?;
+                return Ok(offset);
+            }
+
+            unacked_chunks += 1;
+            if unacked_chunks == STREAM_ACK_INTERVAL {
+                self.flush().await?;
+                *acked = self.receive_stream_ack(offset).await?;
+                unacked_chunks = 0;
+            }
+        }
+    }
+
+    /// Wait for an acknowledgement and check that it covers everything sent so far.
+    /// A resumed receiver may acknowledge more, if it was ahead of the resume offset.
+    async fn receive_stream_ack(&mut self, offset: u64) -> Result<u64> {
+        let ack = self.receive_bytes().await?;
+        match parse_offset(&ack) {
+            Some((acked, &[])) if acked >= offset => Ok(offset),
+            _ => Err(invalid_stream(&format!(
+                "expected an acknowledgement of offset {}",
+                offset
+            ))),
+        }
+    }
+
+    async fn receive_stream_chunks<W: AsyncWrite + Unpin>(
+        &mut self,
+        writer: &mut W,
+        received: &mut u64,
+    ) -> Result<u64> {
+        let chunk_size = self.stream_chunk_size();
+        let mut collected = Vec::with_capacity(chunk_size);
+        let mut unacked_chunks = 0;
+        loop {
+            let frame = self.receive_bytes().await?;
+            let Some((offset, data)) = parse_offset(&frame) else {
+                return Err(invalid_stream("chunk is missing its offset"));
+            };
+            let end = *received + collected.len() as u64;
+            if offset > end {
+                return Err(invalid_stream(&format!(
+                    "chunk at offset {} leaves a gap after offset {}",
+                    offset, end
+                )));
+            }
+
+            if data.is_empty() {
+                if offset != end {
+                    return Err(invalid_stream(&format!(
+                        "stream ends at offset {} but {} bytes were received",
+                        offset, end
+                    )));
+                }
+                write_collected(writer, &mut collected, received).await?;
+                writer.flush().await?;
+                self.send_stream_ack(*received).await?;
+                return Ok(*received);
+            }
+
+            // Drop the part of a resent chunk that was already received
+            let already_received = (end - offset).min(data.len() as u64) as usize;
+            collected.extend_from_slice(&data[already_received..]);
+            if collected.len() >= chunk_size {
+                write_collected(writer, &mut collected, received).await?;
+            }
+
+            unacked_chunks += 1;
+            if unacked_chunks == STREAM_ACK_INTERVAL {
+                write_collected(writer, &mut collected, received).await?;
+                writer.flush().await?;
+                self.send_stream_ack(*received).await?;
+                unacked_chunks = 0;
+            }
+        }
+    }
+
+    async fn send_stream_ack(&mut self, offset: u64) -> Result<()> {
+        self.send_bytes(&offset.to_be_bytes()).await?;
+        self.flush().await
+    }
+}
+
+/// Write out the data collected by the receiver and count it as received
+async fn write_collected<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    collected: &mut Vec<u8>,
+    received: &mut u64,
+) -> Result<()> {
+    writer.write_all(collected).await?;
+    *received += collected.len() as u64;
+    collected.clear();
+    Ok(())
+}
+
+/// Split a chunk or acknowledgement into its offset and the rest
+fn parse_offset(frame: &[u8]) -> Option<(u64, &[u8])> {
+    let (offset, rest) = frame.split_first_chunk::<OFFSET_LEN>()?;
+    Some((u64::from_be_bytes(*offset), rest))
+}
+
+fn invalid_stream(reason: &str) -> NamedPipeError {
+    NamedPipeError::Io(std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        format!("Invalid stream: {}", reason),
+    ))
+}
+
+fn interrupted(offset: u64, source: NamedPipeError) -> NamedPipeError {
+    NamedPipeError::StreamInterrupted {
+        offset,
+        source: Box::new(source),
+    }
+}
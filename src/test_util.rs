@@ -0,0 +1,162 @@
+//! A scriptable fake server for testing client code over real pipes.
+//!
+//! Enabled with the `test-util` feature. Unlike `InMemoryConnection`, `TestServer` runs an
+//! actual `NamedPipeServerStruct`, so the client under test exercises the real pipe path,
+//! handshake included.
+
+pub use crate::cipher::{set_nonce_source, NonceSource};
+use crate::error::Result;
+use crate::server::{NamedPipeConnection, NamedPipeServerStruct};
+use futures::StreamExt;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use tokio::task::JoinHandle;
+
+/// One scripted request and the reply to send back
+struct Exchange {
+    request: Vec<u8>,
+    response: Vec<u8>,
+}
+
+/// A fake server that answers requests from a script of expected exchanges.
+///
+/// Requests must arrive in the scripted order, across all connections. A request that does
+/// not match the next expectation is recorded as a failure and the connection is closed;
+/// `finish` panics if any failure was recorded or any expectation was left unmet.
+///
+/// ```ignore
+/// let mut server = TestServer::new("my_pipe");
+/// server.expect(b"ping").respond(b"pong");
+/// server.start();
+/// // ... run the client under test ...
+/// server.finish().await;
+/// ```
+pub struct TestServer {
+    server: NamedPipeServerStruct,
+    script: Arc<Mutex<VecDeque<Exchange>>>,
+    failures: Arc<Mutex<Vec<String>>>,
+    task: Option<JoinHandle<()>>,
+}
+
+/// An expected request waiting for its scripted response
+#[must_use = "an expectation does nothing until `respond` is called"]
+pub struct Expectation<'a> {
+    server: &'a mut TestServer,
+    request: Vec<u8>,
+}
+
+impl Expectation<'_> {
+    /// Answer the expected request with `response`
+    pub fn respond(self, response: impl AsRef<[u8]>) {
+        self.server.script.lock().unwrap().push_back(Exchange {
+            request: self.request,
+            response: response.as_ref().to_vec(),
+        });
+    }
+}
+
+impl TestServer {
+    /// Create a fake server for `pipe_name` with an empty script
+    pub fn new(pipe_name: &str) -> Self {
+        Self {
+            server: NamedPipeServerStruct::new(pipe_name),
+            script: Arc::new(Mutex::new(VecDeque::new())),
+            failures: Arc::new(Mutex::new(Vec::new())),
+            task: None,
+        }
+    }
+
+    /// Get the underlying server, e.g. to configure encryption or framing before `start`
+    pub fn server_mut(&mut self) -> &mut NamedPipeServerStruct {
+        &mut self.server
+    }
+
+    /// Expect `request` as the next request; complete with `respond`
+    pub fn expect(&mut self, request: impl AsRef<[u8]>) -> Expectation<'_> {
+        Expectation {
+            request: request.as_ref().to_vec(),
+            server: self,
+        }
+    }
+
+    /// Start accepting connections in the background and playing the script
+    pub fn start(&mut self) {
+        let mut incoming = Box::pin(self.server.incoming());
+        let script = Arc::clone(&self.script);
+        let failures = Arc::clone(&self.failures);
+
+        self.task = Some(tokio::spawn(async move {
+            while let Some(result) = incoming.next().await {
+                match result {
+                    Ok(connection) => {
+                        let script = Arc::clone(&script);
+                        let failures = Arc::clone(&failures);
+                        tokio::spawn(async move {
+                            if let Err(e) = play(connection, &script, &failures).await {
+                                if !e.is_disconnect() {
+                                    failures.lock().unwrap().push(e.to_string());
+                                }
+                            }
+                        });
+                    }
+                    Err(e) => failures.lock().unwrap().push(e.to_string()),
+                }
+            }
+        }));
+    }
+
+    /// Stop the server and assert that every expectation was met.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a request did not match its expectation or expectations remain unmet.
+    pub async fn finish(mut self) {
+        let _ = self.server.stop().await;
+        if let Some(task) = self.task.take() {
+            task.abort();
+        }
+
+        let failures = self.failures.lock().unwrap();
+        assert!(failures.is_empty(), "TestServer failures: {:?}", *failures);
+        let remaining = self.script.lock().unwrap().len();
+        assert_eq!(
+            remaining, 0,
+            "TestServer: {} expected requests never arrived",
+            remaining
+        );
+    }
+}
+
+/// Answer requests on `connection` from the script until it runs out or a request mismatches
+async fn play(
+    mut connection: NamedPipeConnection,
+    script: &Mutex<VecDeque<Exchange>>,
+    failures: &Mutex<Vec<String>>,
+) -> Result<()> {
+    loop {
+        let request = connection.receive_bytes().await?;
+        let next = script.lock().unwrap().pop_front();
+
+        match next {
+            Some(exchange) if exchange.request == request => {
+                connection.send_bytes(&exchange.response).await?;
+                connection.flush().await?;
+            }
+            Some(exchange) => {
+                failures.lock().unwrap().push(format!(
+                    "expected request {:?}, got {:?}",
+                    String::from_utf8_lossy(&exchange.request),
+                    String::from_utf8_lossy(&request)
+                ));
+                return connection.close().await;
+            }
+            None => {
+                failures.lock().unwrap().push(format!(
+                    "unexpected request {:?}",
+                    String::from_utf8_lossy(&request)
+                ));
+                return connection.close().await;
+            }
+        }
+    }
+}
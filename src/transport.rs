@@ -0,0 +1,194 @@
+//! Running the crate's protocol over transports other than named pipes.
+//!
+//! Framing, encryption and the handshake only need an `AsyncRead + AsyncWrite` stream. A
+//! `Transport` supplies such streams by connecting to and accepting on an address, and
+//! `connect_framed` and `accept_framed` run the handshake over them, so the same protocol
+//! can be tested over TCP loopback and served over named pipes. `NamedPipeTransport` is
+//! the default; `TcpTransport` carries it over TCP.
+//!
+//! `NamedPipeClientStruct` and `NamedPipeServerStruct` stay tied to named pipes, since
+//! their peer checks (same path, signatures, process ids) and pipe options have no
+//! equivalent on other transports. Use them for production pipes and the transport
+//! functions where the protocol alone matters.
+
+use crate::cipher::PipeCipher;
+use crate::error::{NamedPipeError, Result};
+use crate::framing::Framed;
+use crate::handshake::{accept_hello, send_hello};
+use crate::server::EncryptionPolicy;
+use crate::utils::format_pipe_name;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::windows::named_pipe::{
+    ClientOptions, NamedPipeClient, NamedPipeServer, ServerOptions,
+};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Opens and accepts the byte streams the protocol runs over
+pub trait Transport: Send + Sync {
+    /// A connection in either direction
+    type Stream: AsyncRead + AsyncWrite + Unpin + Send + 'static;
+    /// What `accept` waits on, created by `bind`
+    type Listener: Send;
+
+    /// Connect to the peer listening on `address`
+    fn connect(&self, address: &str) -> impl Future<Output = Result<Self::Stream>> + Send;
+
+    /// Start listening on `address`
+    fn bind(&self, address: &str) -> impl Future<Output = Result<Self::Listener>> + Send;
+
+    /// Wait for the next peer to connect to `listener`
+    fn accept(
+        &self,
+        listener: &mut Self::Listener,
+    ) -> impl Future<Output = Result<Self::Stream>> + Send;
+}
+
+/// Named pipes, addressed by pipe name as in `NamedPipeClientStruct::new`
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NamedPipeTransport;
+
+/// A named pipe accepting connections, created by `NamedPipeTransport::bind`
+pub struct NamedPipeListener {
+    pipe_name: String,
+    next: NamedPipeServer,
+}
+
+/// Either end of a named pipe connection
+pub enum NamedPipeStream {
+    /// The end opened by `NamedPipeTransport::connect`
+    Client(NamedPipeClient),
+    /// The end returned by `NamedPipeTransport::accept`
+    Server(NamedPipeServer),
+}
+
+impl Transport for NamedPipeTransport {
+    type Stream = NamedPipeStream;
+    type Listener = NamedPipeListener;
+
+    async fn connect(&self, address: &str) -> Result<NamedPipeStream> {
+        let client = ClientOptions::new().open(format_pipe_name(address))?;
+        Ok(NamedPipeStream::Client(client))
+    }
+
+    async fn bind(&self, address: &str) -> Result<NamedPipeListener> {
+        let pipe_name = format_pipe_name(address);
+        let next = ServerOptions::new()
+            .first_pipe_instance(true)
+            .create(&pipe_name)?;
+        Ok(NamedPipeListener { pipe_name, next })
+    }
+
+    async fn accept(&self, listener: &mut NamedPipeListener) -> Result<NamedPipeStream> {
+        listener.next.connect().await?;
+        // Create the next instance before handing this one out, so the name stays served
+        let next = ServerOptions::new().create(&listener.pipe_name)?;
+        let connected = std::mem::replace(&mut listener.next, next);
+        Ok(NamedPipeStream::Server(connected))
+    }
+}
+
+impl AsyncRead for NamedPipeStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            NamedPipeStream::Client(pipe) => Pin::new(pipe).poll_read(cx, buf),
+            NamedPipeStream::Server(pipe) => Pin::new(pipe).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for NamedPipeStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        match self.get_mut() {
+            NamedPipeStream::Client(pipe) => Pin::new(pipe).poll_write(cx, buf),
+            NamedPipeStream::Server(pipe) => Pin::new(pipe).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            NamedPipeStream::Client(pipe) => Pin::new(pipe).poll_flush(cx),
+            NamedPipeStream::Server(pipe) => Pin::new(pipe).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            NamedPipeStream::Client(pipe) => Pin::new(pipe).poll_shutdown(cx),
+            NamedPipeStream::Server(pipe) => Pin::new(pipe).poll_shutdown(cx),
+        }
+    }
+}
+
+/// TCP, addressed as `host:port`. Meant for loopback, e.g. test harnesses: TCP offers no
+/// way to identify the peer process, so encrypt and authenticate the protocol instead.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TcpTransport;
+
+impl Transport for TcpTransport {
+    type Stream = TcpStream;
+    type Listener = TcpListener;
+
+    async fn connect(&self, address: &str) -> Result<TcpStream> {
+        let stream = TcpStream::connect(address).await?;
+        // Frames are flushed whole, so waiting to coalesce them only adds latency
+        stream.set_nodelay(true)?;
+        Ok(stream)
+    }
+
+    async fn bind(&self, address: &str) -> Result<TcpListener> {
+        Ok(TcpListener::bind(address).await?)
+    }
+
+    async fn accept(&self, listener: &mut TcpListener) -> Result<TcpStream> {
+        let (stream, _) = listener.accept().await?;
+        stream.set_nodelay(true)?;
+        Ok(stream)
+    }
+}
+
+/// Connect to `address` over `transport` and run the client side of the handshake,
+/// encrypting with `cipher` if given. The result speaks the same protocol as a connected
+/// `NamedPipeClientStruct`.
+pub async fn connect_framed<T: Transport>(
+    transport: &T,
+    address: &str,
+    cipher: Option<&PipeCipher>,
+) -> Result<Framed<T::Stream>> {
+    let stream = transport.connect(address).await?;
+    let mut framed = Framed::with_cipher(stream, cipher.map(|c| c.cipher().clone()));
+    framed.set_cipher_key(cipher.and_then(PipeCipher::key));
+    send_hello(&mut framed, false).await?;
+    Ok(framed)
+}
+
+/// Accept the next peer on `listener` and run the server side of the handshake, applying
+/// `policy` like `NamedPipeServerStruct::set_encryption_policy`. A peer that fails the
+/// handshake is dropped and its error returned; keep accepting to serve the next one.
+pub async fn accept_framed<T: Transport>(
+    transport: &T,
+    listener: &mut T::Listener,
+    cipher: Option<&PipeCipher>,
+    policy: EncryptionPolicy,
+) -> Result<Framed<T::Stream>> {
+    if cipher.is_none() && policy == EncryptionPolicy::Required {
+        return Err(NamedPipeError::InvalidConfig(
+            "requiring encryption needs a cipher".to_string(),
+        ));
+    }
+    let stream = transport.accept(listener).await?;
+    let mut framed = Framed::with_cipher(stream, cipher.map(|c| c.cipher().clone()));
+    framed.set_cipher_key(cipher.and_then(PipeCipher::key));
+    accept_hello(&mut framed, policy, false).await?;
+    Ok(framed)
+}
@@ -1,49 +1,60 @@
+use crate::cipher::{seal, split_nonce, CipherSuite};
 use crate::error::{NamedPipeError, Result};
-use chacha20poly1305::{
-    aead::{Aead, AeadCore, OsRng},
-    ChaCha20Poly1305, Nonce,
+use chacha20poly1305::{aead::Aead, ChaCha20Poly1305, Nonce};
+use std::os::windows::io::{AsRawHandle, RawHandle};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::OnceLock;
+use tokio::net::windows::named_pipe::PipeMode;
+use windows::core::{PCWSTR, PWSTR};
+use windows::Wdk::System::SystemServices::RtlGetVersion;
+use windows::Win32::Foundation::{
+    CloseHandle, DuplicateHandle, DUPLICATE_CLOSE_SOURCE, DUPLICATE_SAME_ACCESS, HANDLE, HWND,
 };
-use std::os::windows::io::AsRawHandle;
-use windows::core::PWSTR;
-use windows::Win32::Foundation::HANDLE;
-use windows::Win32::System::Pipes::{GetNamedPipeClientProcessId, GetNamedPipeServerProcessId};
+use windows::Win32::Security::Cryptography::{CertGetNameStringW, CERT_NAME_SIMPLE_DISPLAY_TYPE};
+use windows::Win32::Security::WinTrust::{
+    WTHelperGetProvSignerFromChain, WTHelperProvDataFromStateData, WinVerifyTrust,
+    WINTRUST_ACTION_GENERIC_VERIFY_V2, WINTRUST_DATA, WINTRUST_FILE_INFO, WTD_CHOICE_FILE,
+    WTD_REVOKE_WHOLECHAIN, WTD_STATEACTION_CLOSE, WTD_STATEACTION_VERIFY, WTD_UI_NONE,
+};
+use windows::Win32::Storage::FileSystem::{
+    FindClose, FindFirstFileW, FindNextFileW, WIN32_FIND_DATAW,
+};
+use windows::Win32::System::Pipes::{
+    GetNamedPipeClientProcessId, GetNamedPipeHandleStateW, GetNamedPipeServerProcessId,
+    NAMED_PIPE_MODE, PIPE_READMODE_MESSAGE,
+};
+use windows::Win32::System::SystemInformation::OSVERSIONINFOW;
 use windows::Win32::System::Threading::{
-    OpenProcess, QueryFullProcessImageNameW, PROCESS_NAME_WIN32, PROCESS_QUERY_INFORMATION,
-    PROCESS_VM_READ,
+    GetCurrentProcess, OpenProcess, QueryFullProcessImageNameW, PROCESS_DUP_HANDLE,
+    PROCESS_NAME_WIN32, PROCESS_QUERY_INFORMATION, PROCESS_VM_READ,
 };
 
-/// Encrypt data using ChaCha20Poly1305 and prepend nonce
-pub fn encrypt_message(cipher: &ChaCha20Poly1305, data: &[u8]) -> Result<Vec<u8>> {
-    // Generate a random nonce
-    let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+/// Namespace every named pipe lives in
+const PIPE_NAMESPACE: &str = "\\\\.\\pipe\\";
 
-    // Encrypt the data
-    let ciphertext = cipher.encrypt(&nonce, data).map_err(|e| {
-        NamedPipeError::Io(std::io::Error::new(
-            std::io::ErrorKind::InvalidData,
-            format!("Encryption failed: {}", e),
-        ))
-    })?;
+/// Identifier of a client or server-side connection.
+///
+/// Ids are unique within a process and increase monotonically, across all servers and
+/// clients and across server restarts. They are not unique across processes; to correlate
+/// both ends of a session, exchange the ids over the connection.
+pub type ConnectionId = u64;
 
-    // Prepare encrypted message: nonce (12 bytes) + ciphertext
-    let mut encrypted_message = Vec::with_capacity(12 + ciphertext.len());
-    encrypted_message.extend_from_slice(&nonce);
-    encrypted_message.extend_from_slice(&ciphertext);
+static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(1);
 
-    Ok(encrypted_message)
+/// Allocate the next process-wide connection id
+pub fn next_connection_id() -> ConnectionId {
+    NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Encrypt data using ChaCha20Poly1305 and prepend nonce
+pub fn encrypt_message(cipher: &ChaCha20Poly1305, data: &[u8]) -> Result<Vec<u8>> {
+    seal(cipher, data)
 }
 
 /// Decrypt data using ChaCha20Poly1305, expecting nonce prepended
 pub fn decrypt_message(cipher: &ChaCha20Poly1305, data: &[u8]) -> Result<Vec<u8>> {
-    // For encrypted data: first 12 bytes are nonce, rest is ciphertext
-    if data.len() < 12 {
-        return Err(NamedPipeError::Io(std::io::Error::new(
-            std::io::ErrorKind::InvalidData,
-            "Encrypted message too short",
-        )));
-    }
-
-    let (nonce_bytes, ciphertext) = data.split_at(12);
+    let nonce_len = CipherSuite::ChaCha20Poly1305.nonce_len();
+    let (nonce_bytes, ciphertext) = split_nonce(data, nonce_len)?;
     let nonce = Nonce::from_slice(nonce_bytes);
 
     // Decrypt the data
@@ -127,6 +138,102 @@ pub fn get_client_pid<H: AsRawHandle>(handle: &H) -> Result<u32> {
     Ok(client_pid)
 }
 
+/// Get the read mode of a pipe handle as reported by `GetNamedPipeHandleState`
+pub fn get_pipe_mode<H: AsRawHandle>(handle: &H) -> Result<PipeMode> {
+    let mut state = NAMED_PIPE_MODE::default();
+    let ok = unsafe {
+        GetNamedPipeHandleStateW(
+            HANDLE(handle.as_raw_handle()),
+            Some(&mut state),
+            None,
+            None,
+            None,
+            None,
+        )
+    };
+    if !ok.as_bool() {
+        return Err(NamedPipeError::Io(std::io::Error::last_os_error()));
+    }
+
+    if state.0 & PIPE_READMODE_MESSAGE.0 != 0 {
+        Ok(PipeMode::Message)
+    } else {
+        Ok(PipeMode::Byte)
+    }
+}
+
+/// Duplicate `handle` into the process with the given PID.
+/// Returns the handle value that is valid inside the target process.
+pub fn duplicate_handle_to_process(handle: RawHandle, target_pid: u32) -> Result<RawHandle> {
+    let target = unsafe {
+        OpenProcess(PROCESS_DUP_HANDLE, false, target_pid).map_err(|_| {
+            NamedPipeError::Io(std::io::Error::new(
+                std::io::ErrorKind::PermissionDenied,
+                "Cannot open process",
+            ))
+        })?
+    };
+
+    let mut duplicated = HANDLE::default();
+    let result = unsafe {
+        DuplicateHandle(
+            GetCurrentProcess(),
+            HANDLE(handle),
+            target,
+            &mut duplicated,
+            0,
+            false,
+            DUPLICATE_SAME_ACCESS,
+        )
+    };
+    unsafe {
+        let _ = CloseHandle(target);
+    }
+
+    result.map_err(|e| {
+        NamedPipeError::Io(std::io::Error::other(format!(
+            "Failed to duplicate handle: {}",
+            e
+        )))
+    })?;
+    Ok(duplicated.0)
+}
+
+/// Close `handle`, a handle value valid inside the process with the given PID, e.g. one
+/// returned by `duplicate_handle_to_process` that never reached that process
+pub(crate) fn close_handle_in_process(handle: RawHandle, target_pid: u32) -> Result<()> {
+    let target = unsafe {
+        OpenProcess(PROCESS_DUP_HANDLE, false, target_pid).map_err(|_| {
+            NamedPipeError::Io(std::io::Error::new(
+                std::io::ErrorKind::PermissionDenied,
+                "Cannot open process",
+            ))
+        })?
+    };
+
+    let result = unsafe {
+        DuplicateHandle(
+            target,
+            HANDLE(handle),
+            HANDLE::default(),
+            std::ptr::null_mut(),
+            0,
+            false,
+            DUPLICATE_CLOSE_SOURCE,
+        )
+    };
+    unsafe {
+        let _ = CloseHandle(target);
+    }
+
+    result.map_err(|e| {
+        NamedPipeError::Io(std::io::Error::other(format!(
+            "Failed to close handle: {}",
+            e
+        )))
+    })
+}
+
 /// Verify that the other process has the same executable path as this process
 pub fn verify_same_path(other_pid: u32) -> Result<()> {
     let other_path = get_process_path(other_pid)?;
@@ -146,11 +253,210 @@ pub fn verify_same_path(other_pid: u32) -> Result<()> {
     Ok(())
 }
 
-/// Format pipe name to Windows named pipe format
+/// Verify that the executable of process `pid` carries a valid Authenticode signature
+/// chaining to a trusted root, with `WinVerifyTrust`, and if `expected_subject` is given,
+/// that the signing certificate's subject name matches it (case-insensitive).
+///
+/// Revocation is checked for the whole chain, which may go online. Fails with
+/// `NamedPipeError::SignatureVerificationFailed` for unsigned or untrusted executables and
+/// a different signer.
+pub fn verify_signed_process(pid: u32, expected_subject: Option<&str>) -> Result<()> {
+    let path = get_process_path(pid)?;
+    let subject = verify_signature(&path)?;
+    match expected_subject {
+        Some(expected) if !subject.eq_ignore_ascii_case(expected) => {
+            Err(NamedPipeError::SignatureVerificationFailed(format!(
+                "{} is signed by \"{}\", not \"{}\"",
+                path, subject, expected
+            )))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Verify the Authenticode signature of the file at `path`, returning the signer's subject
+fn verify_signature(path: &str) -> Result<String> {
+    let wide_path: Vec<u16> = path.encode_utf16().chain(std::iter::once(0)).collect();
+    let mut file_info = WINTRUST_FILE_INFO {
+        cbStruct: std::mem::size_of::<WINTRUST_FILE_INFO>() as u32,
+        pcwszFilePath: PCWSTR(wide_path.as_ptr()),
+        ..Default::default()
+    };
+    let mut data = WINTRUST_DATA {
+        cbStruct: std::mem::size_of::<WINTRUST_DATA>() as u32,
+        dwUIChoice: WTD_UI_NONE,
+        fdwRevocationChecks: WTD_REVOKE_WHOLECHAIN,
+        dwUnionChoice: WTD_CHOICE_FILE,
+        dwStateAction: WTD_STATEACTION_VERIFY,
+        ..Default::default()
+    };
+    data.Anonymous.pFile = &mut file_info;
+    let mut action = WINTRUST_ACTION_GENERIC_VERIFY_V2;
+
+    // SAFETY: `data` points to `file_info` and `wide_path`, which outlive both calls, and
+    // the state data opened by the first call is released by the second
+    unsafe {
+        let status = WinVerifyTrust(HWND::default(), &mut action, &mut data as *mut _ as _);
+        let result = if status == 0 {
+            signer_subject(data.hWVTStateData)
+        } else {
+            Err(NamedPipeError::SignatureVerificationFailed(format!(
+                "{} has no trusted signature (0x{:08X})",
+                path, status as u32
+            )))
+        };
+        data.dwStateAction = WTD_STATEACTION_CLOSE;
+        WinVerifyTrust(HWND::default(), &mut action, &mut data as *mut _ as _);
+        result
+    }
+}
+
+/// Read the subject name of the first signer's certificate from verified trust state
+///
+/// # Safety
+///
+/// `state` must be the state data of a successful `WinVerifyTrust` call, not yet closed.
+unsafe fn signer_subject(state: HANDLE) -> Result<String> {
+    let missing = || NamedPipeError::SignatureVerificationFailed("no signer certificate".into());
+    let provider = unsafe { WTHelperProvDataFromStateData(state) };
+    if provider.is_null() {
+        return Err(missing());
+    }
+    let signer = unsafe { WTHelperGetProvSignerFromChain(provider, 0, false, 0) };
+    if signer.is_null() || unsafe { (*signer).csCertChain } == 0 {
+        return Err(missing());
+    }
+    let certificate = unsafe { (*(*signer).pasCertChain).pCert };
+    if certificate.is_null() {
+        return Err(missing());
+    }
+
+    let mut name = [0u16; 256];
+    let length = unsafe {
+        CertGetNameStringW(
+            certificate,
+            CERT_NAME_SIMPLE_DISPLAY_TYPE,
+            0,
+            None,
+            Some(&mut name),
+        )
+    };
+    // The length includes the terminating null
+    Ok(String::from_utf16_lossy(
+        &name[..(length as usize).saturating_sub(1)],
+    ))
+}
+
+/// Format pipe name to Windows named pipe format. Full names, including remote ones like
+/// `\\server\pipe\name`, are kept as they are.
 pub fn format_pipe_name(name: &str) -> String {
-    if name.starts_with("\\\\.\\pipe\\") {
+    if is_full_pipe_name(name) {
         name.to_string()
     } else {
         format!("\\\\.\\pipe\\{}", name)
     }
 }
+
+/// Whether `name` is a full `\\host\pipe\name` path rather than a bare pipe name
+fn is_full_pipe_name(name: &str) -> bool {
+    name.strip_prefix("\\\\")
+        .and_then(|rest| rest.split_once('\\'))
+        .is_some_and(|(host, rest)| !host.is_empty() && rest.starts_with("pipe\\"))
+}
+
+/// Whether a formatted pipe name refers to a pipe on another machine
+pub(crate) fn is_remote_pipe_name(name: &str) -> bool {
+    is_full_pipe_name(name) && !name.starts_with("\\\\.\\")
+}
+
+/// List the logical names of all existing named pipes that start with `prefix`.
+///
+/// Names are returned without the `\\.\pipe\` namespace, ready to pass to
+/// `NamedPipeClientStruct::new`; the prefix may be given with or without it. Matching is
+/// case-insensitive, like pipe names themselves. The result is a point-in-time snapshot:
+/// pipes can appear or disappear at any moment, so connecting to a listed pipe may still
+/// fail. Windows truncates names longer than 259 characters in the listing.
+pub fn list_pipes(prefix: &str) -> Result<Vec<String>> {
+    let prefix = prefix
+        .strip_prefix(PIPE_NAMESPACE)
+        .unwrap_or(prefix)
+        .to_lowercase();
+    let pattern: Vec<u16> = format!("{}*", PIPE_NAMESPACE)
+        .encode_utf16()
+        .chain(std::iter::once(0))
+        .collect();
+
+    let mut names = Vec::new();
+    let mut data = WIN32_FIND_DATAW::default();
+    let find = unsafe { FindFirstFileW(PCWSTR(pattern.as_ptr()), &mut data) }
+        .map_err(|e| NamedPipeError::Io(std::io::Error::from_raw_os_error(e.code().0)))?;
+
+    loop {
+        let len = data
+            .cFileName
+            .iter()
+            .position(|&c| c == 0)
+            .unwrap_or(data.cFileName.len());
+        let name = String::from_utf16_lossy(&data.cFileName[..len]);
+        if name.to_lowercase().starts_with(&prefix) {
+            names.push(name);
+        }
+
+        if unsafe { FindNextFileW(find, &mut data) }.is_err() {
+            break;
+        }
+    }
+
+    unsafe {
+        let _ = FindClose(find);
+    }
+    Ok(names)
+}
+
+/// Optional named pipe features supported by the running OS, as reported by `capabilities`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capabilities {
+    /// Windows major version, e.g. 10 for Windows 10 and 11
+    pub major_version: u32,
+    /// Windows minor version
+    pub minor_version: u32,
+    /// Windows build number
+    pub build_number: u32,
+    /// Message-mode pipes (`PipeMode::Message`)
+    pub message_mode: bool,
+    /// `FILE_FLAG_FIRST_PIPE_INSTANCE`, used by `set_first_pipe_instance` to detect name squatting
+    pub first_pipe_instance: bool,
+    /// `PIPE_REJECT_REMOTE_CLIENTS`, which keeps remote clients off local-only servers
+    pub reject_remote_clients: bool,
+    /// Connecting to pipes on other machines through `\\server\pipe\name`
+    pub remote_pipes: bool,
+}
+
+/// Report which optional pipe features the running Windows version supports.
+///
+/// The version comes from `RtlGetVersion`, which unlike `GetVersionEx` is not subject to
+/// compatibility shims. It is queried once and cached for the life of the process.
+pub fn capabilities() -> Capabilities {
+    static CAPABILITIES: OnceLock<Capabilities> = OnceLock::new();
+    *CAPABILITIES.get_or_init(|| {
+        let mut info = OSVERSIONINFOW {
+            dwOSVersionInfoSize: std::mem::size_of::<OSVERSIONINFOW>() as u32,
+            ..Default::default()
+        };
+        // RtlGetVersion always succeeds for OSVERSIONINFOW
+        let _ = unsafe { RtlGetVersion(&mut info) };
+        let version = (info.dwMajorVersion, info.dwMinorVersion);
+
+        Capabilities {
+            major_version: info.dwMajorVersion,
+            minor_version: info.dwMinorVersion,
+            build_number: info.dwBuildNumber,
+            message_mode: true,
+            // Windows XP (5.1) and later
+            first_pipe_instance: version >= (5, 1),
+            // Windows Vista (6.0) and later
+            reject_remote_clients: version >= (6, 0),
+            remote_pipes: true,
+        }
+    })
+}